@@ -0,0 +1,313 @@
+use std::str::FromStr;
+
+use colored::Colorize;
+
+use crate::report::Report;
+
+/// Check on a finished run. Written as `<metric><op><value>[unit]`,
+/// e.g. `p99_latency<50ms`, `loss==0` or `throughput>20000`. Loss is
+/// counted against what subscribers expected, so loss assertions fail on
+/// runs without subscribers
+#[derive(Clone, Debug)]
+pub struct Assertion {
+    expr: String,
+    metric: Metric,
+    op: Op,
+    value: f64,
+    /// Compare loss as a percentage of expected publishes
+    percent: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Metric {
+    Throughput,
+    SubThroughput,
+    P50Latency,
+    P90Latency,
+    P99Latency,
+    MaxLatency,
+    Loss,
+    Sent,
+    Received,
+    Reconnects,
+    /// Problems found by `--validate`
+    Invalid,
+}
+
+impl Metric {
+    const ALL: [(&'static str, Metric); 11] = [
+        ("throughput", Metric::Throughput),
+        ("sub_throughput", Metric::SubThroughput),
+        ("p50_latency", Metric::P50Latency),
+        ("p90_latency", Metric::P90Latency),
+        ("p99_latency", Metric::P99Latency),
+        ("max_latency", Metric::MaxLatency),
+        ("loss", Metric::Loss),
+        ("sent", Metric::Sent),
+        ("received", Metric::Received),
+        ("reconnects", Metric::Reconnects),
+        ("invalid", Metric::Invalid),
+    ];
+
+    fn is_latency(&self) -> bool {
+        matches!(
+            self,
+            Metric::P50Latency | Metric::P90Latency | Metric::P99Latency | Metric::MaxLatency
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Op {
+    // Longer operators first so that `<=` isn't parsed as `<`
+    const ALL: [(&'static str, Op); 6] = [
+        ("<=", Op::Le),
+        (">=", Op::Ge),
+        ("==", Op::Eq),
+        ("!=", Op::Ne),
+        ("<", Op::Lt),
+        (">", Op::Gt),
+    ];
+
+    fn check(&self, actual: f64, expected: f64) -> bool {
+        match self {
+            Op::Lt => actual < expected,
+            Op::Le => actual <= expected,
+            Op::Gt => actual > expected,
+            Op::Ge => actual >= expected,
+            Op::Eq => actual == expected,
+            Op::Ne => actual != expected,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Missing comparison operator in `{0}`")]
+    MissingOp(String),
+    #[error("Unknown metric `{0}`")]
+    UnknownMetric(String),
+    #[error("Invalid value `{0}`")]
+    InvalidValue(String),
+    #[error("Unit `{1}` can't be used with `{0}`")]
+    InvalidUnit(String, String),
+}
+
+impl FromStr for Assertion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr: String = s.split_whitespace().collect();
+        let (at, op_str, op) = Op::ALL
+            .iter()
+            .find_map(|(op_str, op)| expr.find(op_str).map(|at| (at, *op_str, *op)))
+            .ok_or_else(|| Error::MissingOp(s.to_owned()))?;
+
+        let name = &expr[..at];
+        let metric = Metric::ALL
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, metric)| *metric)
+            .ok_or_else(|| Error::UnknownMetric(name.to_owned()))?;
+
+        let value = &expr[at + op_str.len()..];
+        let unit_at = value
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(unit_at);
+        let number: f64 = number
+            .parse()
+            .map_err(|_| Error::InvalidValue(value.to_owned()))?;
+
+        let invalid_unit = || Error::InvalidUnit(name.to_owned(), unit.to_owned());
+        let (value, percent) = match unit {
+            "" => (number, false),
+            "ms" if metric.is_latency() => (number, false),
+            "s" if metric.is_latency() => (number * 1000.0, false),
+            "%" if matches!(metric, Metric::Loss) => (number, true),
+            _ => return Err(invalid_unit()),
+        };
+
+        Ok(Assertion {
+            expr,
+            metric,
+            op,
+            value,
+            percent,
+        })
+    }
+}
+
+impl Assertion {
+    fn actual(&self, report: &Report) -> f64 {
+        match self.metric {
+            Metric::Throughput => report.throughput,
+            Metric::SubThroughput => report.sub_throughput,
+            Metric::P50Latency => report.p50_latency as f64,
+            Metric::P90Latency => report.p90_latency as f64,
+            Metric::P99Latency => report.p99_latency as f64,
+            Metric::MaxLatency => report.max_latency as f64,
+            Metric::Loss if self.percent => report.loss_percent(),
+            Metric::Loss => report.loss() as f64,
+            Metric::Sent => report.sent as f64,
+            Metric::Received => report.received as f64,
+            Metric::Reconnects => report.reconnects as f64,
+            Metric::Invalid => report.validation.as_ref().map_or(0, |v| v.errors()) as f64,
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        match self.metric {
+            _ if self.metric.is_latency() => "ms",
+            Metric::Loss if self.percent => "%",
+            Metric::Throughput | Metric::SubThroughput => " msgs/s",
+            _ => "",
+        }
+    }
+}
+
+/// Checks all assertions against the report and prints a pass/fail table.
+/// Returns false if any assertion failed
+pub fn check(assertions: &[Assertion], report: &Report) -> bool {
+    if assertions.is_empty() {
+        return true;
+    }
+
+    let width = assertions
+        .iter()
+        .map(|a| a.expr.len())
+        .max()
+        .unwrap_or(0)
+        .max("Assertion".len());
+
+    println!("\n{:<width$}   {:<20} Result", "Assertion", "Actual");
+    println!("{}", "-".repeat(width + 30));
+
+    let mut passed = true;
+    for assertion in assertions {
+        let unmeasured = matches!(assertion.metric, Metric::Loss) && report.expected == 0;
+        let actual = assertion.actual(report);
+        let result = if !unmeasured && assertion.op.check(actual, assertion.value) {
+            "PASS".green()
+        } else {
+            passed = false;
+            "FAIL".red()
+        };
+
+        let actual = if unmeasured {
+            "-".to_owned()
+        } else {
+            format!("{:.2}{}", actual, assertion.unit())
+        };
+        println!(
+            "{:<width$}   {:<20} {}",
+            assertion.expr,
+            actual,
+            result.bold()
+        );
+
+        if unmeasured {
+            let note = "subscribers expected no publishes, so loss can't be measured";
+            println!("{:<width$}   {}", "", note.yellow());
+        }
+    }
+
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Assertion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn assertions_parse() {
+        let assertion = parse("p99_latency<50ms");
+        assert!(matches!(assertion.metric, Metric::P99Latency));
+        assert!(matches!(assertion.op, Op::Lt));
+        assert_eq!(assertion.value, 50.0);
+
+        let assertion = parse("max_latency <= 1.5s");
+        assert!(matches!(assertion.op, Op::Le));
+        assert_eq!(assertion.value, 1500.0);
+        assert_eq!(assertion.expr, "max_latency<=1.5s");
+
+        let assertion = parse("loss<=1%");
+        assert!(matches!(assertion.metric, Metric::Loss));
+        assert!(matches!(assertion.op, Op::Le));
+        assert_eq!(assertion.value, 1.0);
+        assert!(assertion.percent);
+
+        let assertion = parse("throughput>20000");
+        assert!(matches!(assertion.metric, Metric::Throughput));
+        assert!(matches!(assertion.op, Op::Gt));
+        assert_eq!(assertion.value, 20000.0);
+        assert!(!assertion.percent);
+
+        assert!(matches!(parse("loss==0").op, Op::Eq));
+        assert!(matches!(parse("reconnects!=3").op, Op::Ne));
+        assert!(matches!(parse("sent>=10").op, Op::Ge));
+    }
+
+    #[test]
+    fn invalid_assertions_are_rejected() {
+        let error = |s: &str| s.parse::<Assertion>().unwrap_err();
+
+        assert!(matches!(error("throughput>20ms"), Error::InvalidUnit(..)));
+        assert!(matches!(error("loss<5ms"), Error::InvalidUnit(..)));
+        assert!(matches!(error("p99_latency<1%"), Error::InvalidUnit(..)));
+        assert!(matches!(error("p99_latency<50us"), Error::InvalidUnit(..)));
+        assert!(matches!(error("latency<50ms"), Error::UnknownMetric(v) if v == "latency"));
+        assert!(matches!(error("loss=0"), Error::MissingOp(_)));
+        assert!(matches!(error("loss<"), Error::InvalidValue(_)));
+        assert!(matches!(error("loss<1.2.3"), Error::InvalidValue(_)));
+    }
+
+    #[test]
+    fn assertions_check_reports() {
+        let report = Report {
+            sent: 1000,
+            received: 990,
+            expected: 1000,
+            throughput: 25000.0,
+            p99_latency: 40,
+            ..Default::default()
+        };
+
+        assert!(check(
+            &[parse("p99_latency<50ms"), parse("throughput>20000")],
+            &report
+        ));
+        assert!(check(&[parse("loss<=1%")], &report));
+        assert!(!check(&[parse("loss==0")], &report));
+        assert!(!check(
+            &[parse("loss<1%"), parse("throughput>20000")],
+            &report
+        ));
+    }
+
+    #[test]
+    fn loss_needs_subscribers() {
+        let report = Report {
+            sent: 1000,
+            ..Default::default()
+        };
+
+        assert!(!check(&[parse("loss==0")], &report));
+        assert!(!check(&[parse("loss<1%")], &report));
+        assert!(!check(&[parse("loss>=0")], &report));
+        assert!(check(&[parse("sent==1000")], &report));
+    }
+}
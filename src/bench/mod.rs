@@ -3,14 +3,17 @@ use std::{fs, io, sync::Arc, time::Duration};
 use futures::StreamExt;
 use indicatif::ProgressBar;
 use rumqttc::{MqttOptions, QoS, Transport};
-use tokio::{sync::Barrier, task};
+use tokio::sync::Barrier;
 
 use crate::{
     common::{PubStats, Stats, SubStats, PROGRESS_STYLE},
+    metrics,
+    report::Report,
+    runtime::Shards,
     BenchConfig,
 };
 
-mod publisher;
+pub(crate) mod publisher;
 mod subscriber;
 
 #[derive(thiserror::Error, Debug)]
@@ -25,8 +28,8 @@ pub enum ConnectionError {
     Client(#[from] rumqttc::ClientError),
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-pub(crate) async fn start(config: BenchConfig) {
+pub(crate) async fn start(config: BenchConfig, shards: Shards) -> Report {
+    metrics::start(config.metrics_addr).await;
     let config = Arc::new(config);
     let mut handles = futures::stream::FuturesUnordered::new();
     let barrier_sub = Arc::new(Barrier::new(config.subscribers));
@@ -42,8 +45,13 @@ pub(crate) async fn start(config: BenchConfig) {
         let id = format!("sub-{i:05}");
         let barrier_handle = barrier_sub.clone();
         sub_bar.set_message(format!("spawning {id}"));
-        let mut subscriber = subscriber::Subscriber::new(id, config).await.unwrap();
-        handles.push(task::spawn(async move {
+        // connect on the shard which will drive this client
+        let mut subscriber = shards
+            .spawn(i, subscriber::Subscriber::new(id, config))
+            .await
+            .unwrap()
+            .unwrap();
+        handles.push(shards.spawn(i, async move {
             Stats::SubStats(subscriber.start(barrier_handle).await)
         }));
         sub_bar.inc(1);
@@ -56,12 +64,19 @@ pub(crate) async fn start(config: BenchConfig) {
         .with_style((*PROGRESS_STYLE).clone());
 
     for i in 0..config.publishers {
+        // publishers take the shards after subscribers
+        let shard = config.subscribers + i;
         let config = Arc::clone(&config);
         let id = format!("pub-{i:05}");
         let barrier_handle = barrier_pub.clone();
         pub_bar.set_message(format!("spawning {id}"));
-        let mut publisher = publisher::Publisher::new(id, config).await.unwrap();
-        handles.push(task::spawn(async move {
+        // connect on the shard which will drive this client
+        let mut publisher = shards
+            .spawn(shard, publisher::Publisher::new(id, config))
+            .await
+            .unwrap()
+            .unwrap();
+        handles.push(shards.spawn(shard, async move {
             Stats::PubStats(publisher.start(barrier_handle).await)
         }));
         pub_bar.inc(1);
@@ -73,23 +88,18 @@ pub(crate) async fn start(config: BenchConfig) {
     // await and consume all futures
     while let Some(some_stat) = handles.next().await {
         match some_stat.unwrap() {
-            Stats::SubStats(substats) => {
-                aggregate_substats.publish_count += substats.publish_count;
-                aggregate_substats.puback_count += substats.puback_count;
-                aggregate_substats.reconnects += substats.reconnects;
-                aggregate_substats.throughput += substats.throughput;
-            }
-            Stats::PubStats(pubstats) => {
-                aggregate_pubstats.outgoing_publish += pubstats.outgoing_publish;
-                aggregate_pubstats.throughput += pubstats.throughput;
-                aggregate_pubstats.reconnects += pubstats.reconnects;
-            }
+            Stats::SubStats(substats) => aggregate_substats.merge(&substats),
+            Stats::PubStats(pubstats) => aggregate_pubstats.merge(&pubstats),
         }
     }
     println!(
         "Aggregate PubStats: {:#?}\nAggregate SubStats: {:#?}",
         &aggregate_pubstats, &aggregate_substats
     );
+
+    // every subscriber receives publishes of all the publishers
+    let expected = config.subscribers * config.publishers * config.count;
+    Report::new(&aggregate_pubstats, &aggregate_substats, expected as u64)
 }
 
 pub(crate) fn options(config: Arc<BenchConfig>, id: &str) -> io::Result<MqttOptions> {
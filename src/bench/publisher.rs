@@ -1,6 +1,5 @@
 use std::{fs, io, sync::Arc, time::Instant};
 
-use hdrhistogram::Histogram;
 use rumqttc::{AsyncClient, Event, EventLoop, Incoming, MqttOptions, Outgoing, QoS, Transport};
 use tokio::{
     sync::Barrier,
@@ -10,6 +9,8 @@ use tokio::{
 
 use crate::{
     bench::{ConnectionError, PubStats},
+    common::{self, Latencies},
+    metrics::METRICS,
     BenchConfig,
 };
 
@@ -46,6 +47,7 @@ impl Publisher {
                 match v {
                     Incoming::ConnAck(_) => {
                         // println!("{id} connected");
+                        METRICS.connected();
                         break;
                     }
                     incoming => return Err(ConnectionError::WrongPacket(incoming)),
@@ -62,20 +64,14 @@ impl Publisher {
     }
 
     pub async fn start(&mut self, barrier_handle: Arc<Barrier>) -> PubStats {
-        let qos = get_qos(self.config.publish_qos);
-        let inflight = self.config.max_inflight;
-        let payload_size = self.config.payload_size;
-        let count = self.config.count;
-        let rate = self.config.rate;
-        let id = self.id.clone();
-
+        let load = Load {
+            topic: format!("hello/{}/world", self.id),
+            qos: get_qos(self.config.publish_qos),
+            payload_size: self.config.payload_size,
+            count: self.config.count,
+            rate: self.config.rate,
+        };
         let start = Instant::now();
-        let mut acks_expected = count;
-        let mut outgoing_elapsed = Duration::from_secs(0);
-        let mut acks_count = 0;
-
-        let topic = format!("hello/{}/world", self.id);
-        let client = self.client.clone();
 
         let wait = barrier_handle.wait();
         tokio::pin!(wait);
@@ -91,82 +87,18 @@ impl Publisher {
             };
         }
 
-        // If publish count is 0, don't publish. This is an idle connection
-        // which can be used to test pings
-        if count != 0 {
-            // delay between messages in milliseconds
-            let delay = if rate == 0 { 0 } else { 1000 / rate };
-            task::spawn(async move {
-                requests(topic, payload_size, count, client, qos, delay).await;
-            });
-        } else {
-            // Just keep this connection alive
-            acks_expected = 1;
-        }
-
-        if self.config.publish_qos == 0 {
-            // only last extra publish is qos 1 for synchronization
-            acks_expected = 1;
-        }
-
-        let mut reconnects: u64 = 0;
-        let mut latencies: Vec<Option<Instant>> = vec![None; inflight as usize + 1];
-        let mut histogram = Histogram::<u64>::new(4).unwrap();
-
-        loop {
-            let event = match self.eventloop.poll().await {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Id = {}, Connection error = {:?}", self.id, e);
-                    reconnects += 1;
-                    if reconnects >= 1 {
-                        break;
-                    }
-
-                    continue;
-                }
-            };
-
-            debug!("Id = {}, {:?}, count {}", self.id, event, acks_count);
-            match event {
-                Event::Incoming(v) => match v {
-                    Incoming::PubAck(ack) => {
-                        acks_count += 1;
-                        let elapsed = match latencies[ack.pkid as usize] {
-                            Some(instant) => instant.elapsed(),
-                            None => {
-                                warn!("Id = {}, Unsolicited PubAck", ack.pkid);
-                                continue;
-                            }
-                        };
-                        histogram.record(elapsed.as_millis() as u64).unwrap();
-                    }
-                    Incoming::PingResp => {
-                        debug!("ping response")
-                    }
-                    incoming => {
-                        error!("Id = {}, Unexpected incoming packet = {:?}", id, incoming);
-                        break;
-                    }
-                },
-                Event::Outgoing(Outgoing::Publish(pkid)) => {
-                    latencies[pkid as usize] = Some(Instant::now());
-                }
-                Event::Outgoing(Outgoing::PingReq) => {
-                    debug!("ping request")
-                }
-                _ => (),
-            }
-
-            if acks_count >= acks_expected {
-                outgoing_elapsed = start.elapsed();
-                break;
-            }
-        }
+        let inflight = self.config.max_inflight;
+        let client = self.client.clone();
+        let acks = publish(&self.id, client, &mut self.eventloop, inflight, load, start).await;
+        let mut acks_count = acks.count;
+        let outgoing_elapsed = acks.elapsed.unwrap_or_default();
 
-        let outgoing_throughput = (count * 1000) as f32 / outgoing_elapsed.as_millis() as f32;
+        METRICS.disconnected();
+        let count = self.config.count;
+        let outgoing_throughput = common::throughput(count as u64, outgoing_elapsed);
 
         if self.config.show_pub_stat {
+            let histogram = &acks.latencies.0;
             println!(
                 "Id = {}
             Throughputs
@@ -185,7 +117,7 @@ impl Publisher {
                 self.id,
                 acks_count,
                 outgoing_throughput,
-                reconnects,
+                acks.reconnects,
                 histogram.len(),
                 histogram.value_at_percentile(100.0),
                 histogram.value_at_percentile(99.9999),
@@ -203,11 +135,131 @@ impl Publisher {
         PubStats {
             outgoing_publish: acks_count as u64,
             throughput: outgoing_throughput,
-            reconnects,
+            reconnects: acks.reconnects,
+            latencies: acks.latencies,
         }
     }
 }
 
+/// Publishes of a client
+pub(crate) struct Load {
+    pub topic: String,
+    pub qos: QoS,
+    pub payload_size: usize,
+    /// Publishes to send, 0 is an idle connection which only pings
+    pub count: usize,
+    /// Publishes per second, 0 is no throttle
+    pub rate: u64,
+}
+
+/// Acks of the publishes of a client
+pub(crate) struct Acks {
+    pub count: usize,
+    pub reconnects: u64,
+    /// Publish to ack latencies in milliseconds
+    pub latencies: Latencies,
+    /// Time from the start to the last ack waited for, `None` when the
+    /// connection failed before
+    pub elapsed: Option<Duration>,
+}
+
+/// Sends the publishes of `load` from another task and polls the eventloop
+/// until they are acked. QoS 0 loads end with a QoS 1 publish, which is the
+/// only one waited for
+pub(crate) async fn publish(
+    id: &str,
+    client: AsyncClient,
+    eventloop: &mut EventLoop,
+    inflight: u16,
+    load: Load,
+    start: Instant,
+) -> Acks {
+    let Load {
+        topic,
+        qos,
+        payload_size,
+        count,
+        rate,
+    } = load;
+    let mut acks_expected = count;
+
+    // If publish count is 0, don't publish. This is an idle connection
+    // which can be used to test pings
+    if count != 0 {
+        // delay between messages in milliseconds
+        let delay = 1000u64.checked_div(rate).unwrap_or(0);
+        task::spawn(async move {
+            requests(topic, payload_size, count, client, qos, delay).await;
+        });
+    } else {
+        // Just keep this connection alive
+        acks_expected = 1;
+    }
+
+    if qos == QoS::AtMostOnce {
+        // only last extra publish is qos 1 for synchronization
+        acks_expected = 1;
+    }
+
+    let mut acks = Acks {
+        count: 0,
+        reconnects: 0,
+        latencies: Latencies::default(),
+        elapsed: None,
+    };
+    let mut sent_at: Vec<Option<Instant>> = vec![None; inflight as usize + 1];
+    loop {
+        let event = match eventloop.poll().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Id = {}, Connection error = {:?}", id, e);
+                METRICS.reconnected();
+                acks.reconnects += 1;
+                break;
+            }
+        };
+
+        debug!("Id = {}, {:?}, count {}", id, event, acks.count);
+        match event {
+            Event::Incoming(Incoming::PubAck(rumqttc::PubAck { pkid }))
+            | Event::Incoming(Incoming::PubComp(rumqttc::PubComp { pkid })) => {
+                METRICS.acked();
+                acks.count += 1;
+                match sent_at[pkid as usize].take() {
+                    Some(instant) => {
+                        let elapsed = instant.elapsed().as_millis() as u64;
+                        acks.latencies.record(elapsed);
+                        METRICS.latency(elapsed);
+                    }
+                    None => warn!("Id = {}, Unsolicited PubAck", pkid),
+                }
+            }
+            Event::Outgoing(Outgoing::Publish(pkid)) => {
+                METRICS.published();
+                sent_at[pkid as usize] = Some(Instant::now());
+            }
+            Event::Incoming(Incoming::PingResp) => {
+                debug!("ping response")
+            }
+            Event::Outgoing(Outgoing::PingReq) => {
+                debug!("ping request")
+            }
+            Event::Incoming(Incoming::PubRec(_)) | Event::Outgoing(_) => {}
+            Event::Incoming(incoming) => {
+                error!("Id = {}, Unexpected incoming packet = {:?}", id, incoming);
+                break;
+            }
+        }
+
+        if acks.count >= acks_expected {
+            acks.elapsed = Some(start.elapsed());
+            break;
+        }
+    }
+
+    acks
+}
+
 /// make count number of requests at specified QoS.
 async fn requests(
     topic: String,
@@ -6,6 +6,8 @@ use tokio::sync::Barrier;
 
 use crate::{
     bench::{get_qos, options, ConnectionError, SubStats},
+    common,
+    metrics::METRICS,
     BenchConfig,
 };
 
@@ -32,7 +34,10 @@ impl Subscriber {
             let event = eventloop.poll().await?;
             if let Event::Incoming(v) = event {
                 match v {
-                    Incoming::ConnAck(_) => break,
+                    Incoming::ConnAck(_) => {
+                        METRICS.connected();
+                        break;
+                    }
                     incoming => return Err(ConnectionError::WrongPacket(incoming)),
                 }
             }
@@ -84,6 +89,7 @@ impl Subscriber {
                 Ok(v) => v,
                 Err(e) => {
                     error!("Id = {}, Connection error = {:?}", self.id, e);
+                    METRICS.reconnected();
 
                     reconnects += 1;
                     if reconnects >= 1 {
@@ -95,6 +101,7 @@ impl Subscriber {
 
             match event {
                 Event::Incoming(Incoming::Publish(_)) => {
+                    METRICS.received();
                     publish_count += 1;
                     start = Instant::now();
                     last_publish = start;
@@ -122,6 +129,7 @@ impl Subscriber {
                 Ok(v) => v,
                 Err(e) => {
                     error!("Id = {}, Connection error = {:?}", self.id, e);
+                    METRICS.reconnected();
                     reconnects += 1;
                     if reconnects >= 2 {
                         break;
@@ -134,6 +142,7 @@ impl Subscriber {
 
             match event {
                 Event::Incoming(Incoming::Publish(_)) => {
+                    METRICS.received();
                     publish_count += 1;
                     histogram
                         .record(last_publish.elapsed().as_millis() as u64)
@@ -151,8 +160,8 @@ impl Subscriber {
             }
         }
 
-        let outgoing_throughput =
-            (publish_count * 1000) as f32 / (last_publish - start).as_millis() as f32;
+        METRICS.disconnected();
+        let outgoing_throughput = common::throughput(publish_count as u64, last_publish - start);
 
         if self.config.show_sub_stat {
             println!(
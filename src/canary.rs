@@ -0,0 +1,366 @@
+//! Long running canary of a broker. Every cycle connects a subscriber and a
+//! publisher, sends a few messages through the broker and checks that all of
+//! them come back in time. The latest result is served on a health endpoint
+//! and a webhook is called whenever the canary starts failing or recovers
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rumqttc::{AsyncClient, Incoming, MqttOptions, QoS};
+use serde::Serialize;
+use serde_json::json;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::{
+    common::{self, Latencies, WrappedEventLoop},
+    http::{self, Response},
+    CanaryConfig,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    // boxed to keep probe results small
+    #[error("Connection error = {0:?}")]
+    Connection(Box<rumqttc::ConnectionError>),
+    #[error("Wrong packet = {0:?}")]
+    WrongPacket(Box<Incoming>),
+    #[error("Client error = {0:?}")]
+    Client(#[from] rumqttc::ClientError),
+    #[error("Timed out with {0} of {1} messages received")]
+    Timeout(usize, usize),
+    #[error("Latency of {0}ms is over the limit")]
+    Slow(u64),
+}
+
+impl From<rumqttc::ConnectionError> for Error {
+    fn from(e: rumqttc::ConnectionError) -> Self {
+        Error::Connection(Box::new(e))
+    }
+}
+
+/// Result of a cycle
+#[derive(Debug, Clone, Serialize)]
+struct Probe {
+    cycle: u64,
+    /// Unix time of the end of the cycle in milliseconds
+    time: u64,
+    sent: usize,
+    received: usize,
+    /// Publish to receive latencies in milliseconds
+    p50_latency: u64,
+    max_latency: u64,
+    error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Status {
+    healthy: bool,
+    cycles: u64,
+    failures: u64,
+    /// Cycles which failed since the last one which passed
+    failing_for: u64,
+    last: Option<Probe>,
+}
+
+/// Probes the broker every `--interval` until killed
+pub async fn start(config: CanaryConfig) {
+    let status = Arc::new(Mutex::new(Status::default()));
+    if let Some(addr) = config.health_addr {
+        serve(addr, status.clone()).await;
+    }
+
+    let mut interval = time::interval(Duration::from_secs(config.interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for cycle in 0.. {
+        interval.tick().await;
+
+        let mut latencies = Latencies::default();
+        let result = time::timeout(
+            Duration::from_secs(config.timeout),
+            probe(&config, cycle, &mut latencies),
+        )
+        .await;
+        let received = latencies.0.len() as usize;
+        let result = match result {
+            Err(_) => Err(Error::Timeout(received, config.count)),
+            Ok(Ok(())) if config.max_latency.is_some_and(|v| latencies.0.max() > v) => {
+                Err(Error::Slow(latencies.0.max()))
+            }
+            Ok(v) => v,
+        };
+
+        let probe = Probe {
+            cycle,
+            time: now(),
+            sent: config.count,
+            received,
+            p50_latency: latencies.percentile(50.0),
+            max_latency: latencies.0.max(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+
+        match &probe.error {
+            None => println!(
+                "Cycle = {}, received = {}/{}, p50 = {}ms, max = {}ms",
+                cycle, probe.received, probe.sent, probe.p50_latency, probe.max_latency
+            ),
+            Some(e) => println!("Cycle = {}, failed = {}", cycle, e),
+        }
+
+        let changed = status.lock().unwrap().update(&probe);
+        if let (true, Some(url)) = (changed, &config.webhook) {
+            alert(url, &config, &probe).await;
+        }
+    }
+}
+
+impl Status {
+    /// Takes the probe of a cycle. Returns whether the health changed, which
+    /// a canary failing from the start counts as
+    fn update(&mut self, probe: &Probe) -> bool {
+        let passed = probe.error.is_none();
+        let changed = match self.cycles {
+            0 => !passed,
+            _ => self.healthy != passed,
+        };
+
+        self.healthy = passed;
+        self.cycles += 1;
+        if !passed {
+            self.failures += 1;
+            self.failing_for += 1;
+        } else {
+            self.failing_for = 0;
+        }
+        self.last = Some(probe.clone());
+        changed
+    }
+}
+
+/// Serves the status on `/health`, with a 503 while the canary is failing
+async fn serve(addr: SocketAddr, status: Arc<Mutex<Status>>) {
+    let handler = move |path: &str| match path {
+        "/health" => {
+            let status = status.lock().unwrap();
+            let body = serde_json::to_string_pretty(&*status).unwrap();
+            match status.healthy {
+                true => Response::ok("application/json", body),
+                false => Response {
+                    status: 503,
+                    content_type: "application/json",
+                    body,
+                },
+            }
+        }
+        _ => Response::not_found(),
+    };
+
+    match http::serve(addr, handler).await {
+        Ok(()) => info!("Serving health on http://{}/health", addr),
+        Err(e) => error!("Couldn't serve health on {}: {}", addr, e),
+    }
+}
+
+/// Posts a failure or recovery to the webhook. The text makes it readable
+/// in chat tools like slack which only show `text`
+async fn alert(url: &str, config: &CanaryConfig, probe: &Probe) {
+    let broker = format!("{}:{}", config.server, config.port);
+    let (event, text) = match &probe.error {
+        Some(e) => (
+            "failure",
+            format!("mqttwrk canary of {broker} failing: {e}"),
+        ),
+        None => ("recovery", format!("mqttwrk canary of {broker} recovered")),
+    };
+
+    let body = json!({
+        "text": text,
+        "event": event,
+        "broker": broker,
+        "probe": probe,
+    });
+
+    match http::post(url, "application/json", &[], &body.to_string()).await {
+        Ok((status, _)) if (200..300).contains(&status) => info!("Sent {} alert", event),
+        Ok((status, body)) => error!("Webhook status = {}, {}", status, body.trim()),
+        Err(e) => error!("Couldn't call webhook: {}", e),
+    }
+}
+
+/// Sends `--count` messages from a publisher to a subscriber and records
+/// their latencies
+async fn probe(config: &CanaryConfig, cycle: u64, latencies: &mut Latencies) -> Result<(), Error> {
+    let qos = match config.qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    };
+
+    let (subscriber, mut sub_loop) = connect(config, &format!("{}-sub", config.id)).await?;
+    subscriber.subscribe(&config.topic, qos).await?;
+    loop {
+        match sub_loop.poll().await? {
+            Incoming::SubAck(_) => break,
+            Incoming::PingResp => continue,
+            packet => return Err(Error::WrongPacket(Box::new(packet))),
+        }
+    }
+
+    let (publisher, mut pub_loop) = connect(config, &format!("{}-pub", config.id)).await?;
+    // ends once disconnected
+    let pub_task = tokio::spawn(async move { while pub_loop.poll().await.is_ok() {} });
+
+    let mut sent = Vec::with_capacity(config.count);
+    for i in 0..config.count {
+        sent.push(Instant::now());
+        let payload = format!("{cycle} {i}");
+        publisher
+            .publish(&config.topic, qos, false, payload)
+            .await?;
+    }
+    publisher.disconnect().await?;
+
+    let mut received = vec![false; config.count];
+    while latencies.0.len() < config.count as u64 {
+        let publish = match sub_loop.poll().await? {
+            Incoming::Publish(v) => v,
+            _ => continue,
+        };
+
+        // late messages of earlier cycles don't count
+        let payload = String::from_utf8_lossy(&publish.payload);
+        let mut fields = payload.split_whitespace().map(|v| v.parse::<u64>().ok());
+        if let (Some(Some(c)), Some(Some(i))) = (fields.next(), fields.next()) {
+            let i = i as usize;
+            if c == cycle && i < config.count && !received[i] {
+                received[i] = true;
+                latencies.record(sent[i].elapsed().as_millis() as u64);
+            }
+        }
+    }
+
+    subscriber.disconnect().await?;
+    while sub_loop.poll().await.is_ok() {}
+    let _ = pub_task.await;
+    Ok(())
+}
+
+async fn connect(
+    config: &CanaryConfig,
+    id: &str,
+) -> Result<(AsyncClient, WrappedEventLoop), Error> {
+    let mut options = MqttOptions::new(id, &config.server, config.port);
+    options.set_keep_alive(Duration::from_secs(10));
+
+    let (client, mut eventloop) = common::get_client(options);
+    match eventloop.poll().await? {
+        Incoming::ConnAck(_) => Ok((client, eventloop)),
+        packet => Err(Error::WrongPacket(Box::new(packet))),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::Value;
+
+    use super::*;
+
+    fn probe(cycle: u64, error: Option<&str>) -> Probe {
+        Probe {
+            cycle,
+            time: now(),
+            sent: 10,
+            received: if error.is_some() { 4 } else { 10 },
+            p50_latency: 2,
+            max_latency: 5,
+            error: error.map(str::to_owned),
+        }
+    }
+
+    /// Json body of a request the webhook got
+    fn body(request: &str) -> Value {
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn failures_and_recoveries_alert() {
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_owned();
+        let (url, mut requests) = http::stub(vec![ok.clone(), ok]).await;
+        let config = CanaryConfig::parse_from(["canary", "-S", "broker", "--webhook", &url]);
+
+        let mut status = Status::default();
+        assert!(!status.update(&probe(0, None)));
+        let failure = probe(1, Some("Timed out with 4 of 10 messages received"));
+        assert!(status.update(&failure));
+        alert(&url, &config, &failure).await;
+        assert!(!status.update(&probe(2, Some("Latency of 900ms is over the limit"))));
+        let recovery = probe(3, None);
+        assert!(status.update(&recovery));
+        alert(&url, &config, &recovery).await;
+
+        let failure = body(&requests.recv().await.unwrap());
+        assert_eq!(failure["event"], "failure");
+        assert_eq!(failure["broker"], "broker:1883");
+        assert_eq!(
+            failure["text"],
+            "mqttwrk canary of broker:1883 failing: Timed out with 4 of 10 messages received"
+        );
+        assert_eq!(failure["probe"]["cycle"], 1);
+        assert_eq!(failure["probe"]["received"], 4);
+
+        let recovery = body(&requests.recv().await.unwrap());
+        assert_eq!(recovery["event"], "recovery");
+        assert_eq!(recovery["text"], "mqttwrk canary of broker:1883 recovered");
+        assert_eq!(recovery["probe"]["cycle"], 3);
+        assert_eq!(recovery["probe"]["error"], Value::Null);
+    }
+
+    #[test]
+    fn failing_from_the_start_alerts() {
+        let mut status = Status::default();
+        assert!(status.update(&probe(0, Some("Connection error"))));
+    }
+
+    #[tokio::test]
+    async fn health_follows_the_last_probe() {
+        // a free port for the server
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let status = Arc::new(Mutex::new(Status::default()));
+        serve(addr, status.clone()).await;
+
+        status.lock().unwrap().update(&probe(0, None));
+        status.lock().unwrap().update(&probe(1, Some("Slow")));
+        status.lock().unwrap().update(&probe(2, Some("Slow")));
+        let (code, body) = http::get(addr, "/health").await.unwrap();
+        let health: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(code, 503);
+        assert_eq!(health["healthy"], false);
+        assert_eq!(health["failing_for"], 2);
+        assert_eq!(health["last"]["cycle"], 2);
+
+        status.lock().unwrap().update(&probe(3, None));
+        let (code, body) = http::get(addr, "/health").await.unwrap();
+        let health: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(code, 200);
+        assert_eq!(health["healthy"], true);
+        assert_eq!(health["cycles"], 4);
+        assert_eq!(health["failures"], 2);
+        assert_eq!(health["failing_for"], 0);
+
+        assert_eq!(http::get(addr, "/").await.unwrap().0, 404);
+    }
+}
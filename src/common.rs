@@ -1,3 +1,6 @@
+use std::{fmt, time::Duration};
+
+use hdrhistogram::Histogram;
 use indicatif::ProgressStyle;
 use once_cell::sync::Lazy;
 use rumqttc::{AsyncClient, ConnectionError, Event, EventLoop, Incoming, MqttOptions};
@@ -23,11 +26,79 @@ pub struct SubStats {
     pub throughput: f32,
 }
 
+impl SubStats {
+    pub fn merge(&mut self, other: &SubStats) {
+        self.publish_count += other.publish_count;
+        self.puback_count += other.puback_count;
+        self.reconnects += other.reconnects;
+        self.throughput += other.throughput;
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct PubStats {
     pub outgoing_publish: u64,
     pub throughput: f32,
     pub reconnects: u64,
+    /// Publish to ack latencies in milliseconds
+    pub latencies: Latencies,
+}
+
+impl PubStats {
+    pub fn merge(&mut self, other: &PubStats) {
+        self.outgoing_publish += other.outgoing_publish;
+        self.throughput += other.throughput;
+        self.reconnects += other.reconnects;
+        self.latencies.merge(&other.latencies);
+    }
+}
+
+/// Latency histogram which only prints its percentiles when debugged
+#[derive(Clone)]
+pub struct Latencies(pub Histogram<u64>);
+
+impl Latencies {
+    pub fn record(&mut self, value: u64) {
+        // auto resizing histograms grow to fit any value, so recording can't fail
+        self.0.record(value).unwrap();
+    }
+
+    pub fn merge(&mut self, other: &Latencies) {
+        // both histograms auto resize, so adding can't fail
+        self.0.add(&other.0).unwrap();
+    }
+
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        self.0.value_at_percentile(percentile)
+    }
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies(Histogram::new(4).unwrap())
+    }
+}
+
+impl fmt::Debug for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Latencies")
+            .field("samples", &self.0.len())
+            .field("p50", &self.percentile(50.0))
+            .field("p90", &self.percentile(90.0))
+            .field("p99", &self.percentile(99.0))
+            .field("max", &self.0.max())
+            .finish()
+    }
+}
+
+/// Messages per second of `count` messages sent or received over `elapsed`.
+/// Runs too short to measure have no throughput rather than an infinite one,
+/// which reports couldn't store
+pub fn throughput(count: u64, elapsed: Duration) -> f32 {
+    match elapsed.is_zero() {
+        true => 0.0,
+        false => (count as f64 / elapsed.as_secs_f64()) as f32,
+    }
 }
 
 pub fn get_client(config: MqttOptions) -> (AsyncClient, WrappedEventLoop) {
@@ -0,0 +1,228 @@
+use colored::Colorize;
+
+use crate::{report::Report, CompareConfig};
+
+/// Whether a bigger value of a metric is an improvement or a regression
+#[derive(Clone, Copy)]
+enum Better {
+    Higher,
+    Lower,
+}
+
+struct Metric {
+    name: &'static str,
+    better: Better,
+    /// Counts of failures, which regress on any increase from 0
+    count: bool,
+    value: fn(&Report) -> f64,
+}
+
+const METRICS: [Metric; 8] = [
+    Metric {
+        name: "throughput",
+        better: Better::Higher,
+        count: false,
+        value: |r| r.throughput,
+    },
+    Metric {
+        name: "sub_throughput",
+        better: Better::Higher,
+        count: false,
+        value: |r| r.sub_throughput,
+    },
+    Metric {
+        name: "p50_latency",
+        better: Better::Lower,
+        count: false,
+        value: |r| r.p50_latency as f64,
+    },
+    Metric {
+        name: "p90_latency",
+        better: Better::Lower,
+        count: false,
+        value: |r| r.p90_latency as f64,
+    },
+    Metric {
+        name: "p99_latency",
+        better: Better::Lower,
+        count: false,
+        value: |r| r.p99_latency as f64,
+    },
+    Metric {
+        name: "max_latency",
+        better: Better::Lower,
+        count: false,
+        value: |r| r.max_latency as f64,
+    },
+    Metric {
+        name: "loss",
+        better: Better::Lower,
+        count: true,
+        value: |r| r.loss() as f64,
+    },
+    Metric {
+        name: "reconnects",
+        better: Better::Lower,
+        count: true,
+        value: |r| r.reconnects as f64,
+    },
+];
+
+/// Prints per metric deltas between two saved reports. Returns false if
+/// any metric regressed beyond the tolerance
+pub fn start(config: CompareConfig) -> bool {
+    let baseline = match Report::load(&config.baseline) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Couldn't load {}: {}", config.baseline, e);
+            return false;
+        }
+    };
+
+    let current = match Report::load(&config.current) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Couldn't load {}: {}", config.current, e);
+            return false;
+        }
+    };
+
+    println!(
+        "{:<16} {:>14} {:>14} {:>10}   Result",
+        "Metric", "Baseline", "Current", "Delta"
+    );
+    println!("{}", "-".repeat(68));
+
+    let mut passed = true;
+    for metric in METRICS.iter() {
+        let old = (metric.value)(&baseline);
+        let new = (metric.value)(&current);
+
+        let (delta, change) = change(metric, old, new, config.tolerance);
+        let result = match change {
+            Change::Regressed => {
+                passed = false;
+                "REGRESSED".red()
+            }
+            Change::Improved => "improved".green(),
+            Change::Same => "ok".normal(),
+            Change::Skipped => "skipped".yellow(),
+        };
+
+        let delta = if delta.is_infinite() {
+            "new".to_owned()
+        } else {
+            format!("{delta:+.2}%")
+        };
+
+        println!(
+            "{:<16} {:>14.2} {:>14.2} {:>10}   {}",
+            metric.name,
+            old,
+            new,
+            delta,
+            result.bold()
+        );
+    }
+
+    passed
+}
+
+/// How a metric moved from the baseline
+#[derive(Debug, PartialEq)]
+enum Change {
+    /// Worse by more than the tolerance
+    Regressed,
+    Improved,
+    /// Worse within the tolerance, or unchanged
+    Same,
+    /// Measurement which was 0 in the baseline, a run without publishes
+    /// or acks, so there's nothing to compare to
+    Skipped,
+}
+
+/// Delta in percent, infinite from a baseline of 0, and how the metric
+/// moved given the tolerance in percent
+fn change(metric: &Metric, old: f64, new: f64, tolerance: f64) -> (f64, Change) {
+    // Signed change in the direction that is worse for this metric
+    let worse = match metric.better {
+        Better::Higher => old - new,
+        Better::Lower => new - old,
+    };
+
+    if old == 0.0 {
+        return match (new == 0.0, metric.count) {
+            (true, _) => (0.0, Change::Same),
+            // No loss or no reconnects regresses on any increase
+            (false, true) => (f64::INFINITY, Change::Regressed),
+            (false, false) => (f64::INFINITY, Change::Skipped),
+        };
+    }
+
+    let delta = (new - old) * 100.0 / old;
+    let change = if worse * 100.0 / old > tolerance {
+        Change::Regressed
+    } else if worse < 0.0 {
+        Change::Improved
+    } else {
+        Change::Same
+    };
+    (delta, change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: &str) -> &'static Metric {
+        METRICS.iter().find(|v| v.name == name).unwrap()
+    }
+
+    #[test]
+    fn changes_respect_the_tolerance() {
+        let throughput = metric("throughput");
+        assert_eq!(
+            change(throughput, 100.0, 90.0, 5.0),
+            (-10.0, Change::Regressed)
+        );
+        assert_eq!(change(throughput, 100.0, 96.0, 5.0), (-4.0, Change::Same));
+        assert_eq!(
+            change(throughput, 100.0, 110.0, 5.0),
+            (10.0, Change::Improved)
+        );
+        assert_eq!(change(throughput, 100.0, 100.0, 5.0), (0.0, Change::Same));
+
+        let latency = metric("p99_latency");
+        assert_eq!(change(latency, 10.0, 12.0, 5.0), (20.0, Change::Regressed));
+        assert_eq!(change(latency, 10.0, 12.0, 25.0), (20.0, Change::Same));
+        assert_eq!(change(latency, 10.0, 8.0, 5.0), (-20.0, Change::Improved));
+
+        let reconnects = metric("reconnects");
+        assert_eq!(change(reconnects, 4.0, 5.0, 5.0), (25.0, Change::Regressed));
+        assert_eq!(change(reconnects, 4.0, 2.0, 5.0), (-50.0, Change::Improved));
+    }
+
+    #[test]
+    fn zero_baselines_only_flag_counts() {
+        let loss = metric("loss");
+        assert_eq!(
+            change(loss, 0.0, 1.0, 50.0),
+            (f64::INFINITY, Change::Regressed)
+        );
+        assert_eq!(change(loss, 0.0, 0.0, 5.0), (0.0, Change::Same));
+        assert_eq!(change(loss, 3.0, 0.0, 5.0), (-100.0, Change::Improved));
+
+        // runs which measured nothing aren't a baseline
+        let latency = metric("p50_latency");
+        assert_eq!(
+            change(latency, 0.0, 7.0, 5.0),
+            (f64::INFINITY, Change::Skipped)
+        );
+        assert_eq!(change(latency, 0.0, 0.0, 5.0), (0.0, Change::Same));
+        let throughput = metric("throughput");
+        assert_eq!(
+            change(throughput, 0.0, 1000.0, 5.0),
+            (f64::INFINITY, Change::Skipped)
+        );
+    }
+}
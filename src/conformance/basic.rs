@@ -1,21 +1,60 @@
 #![allow(unused_imports)]
 
 use crate::common::{self, WrappedEventLoop};
-use crate::conformance::PROGRESS_BAR;
+use crate::conformance::{Error, PROGRESS_BAR};
 use crate::ConformanceConfig;
 use colored::Colorize;
 use indicatif::ProgressBar;
 use rumqttc::{
-    matches, AsyncClient, ConnAck, ConnectReturnCode, Event, Incoming, LastWill, MqttOptions,
-    Outgoing, Packet, PubAck, Publish, QoS, SubAck, Subscribe, SubscribeFilter,
+    matches, AsyncClient, ConnAck, ConnectReturnCode, ConnectionError, Event, Incoming, LastWill,
+    MqttOptions, Outgoing, Packet, PubAck, Publish, QoS, SubAck, Subscribe, SubscribeFilter,
     SubscribeReasonCode, UnsubAck,
 };
-use std::thread;
 use std::time::Duration;
+use tokio::time;
+
+/// Fails the test with the packet unless it matches `pattern`
+macro_rules! expect_matches {
+    ($step:expr, $actual:expr, $pattern:pat) => {
+        if !matches!($actual, $pattern) {
+            return Err(Error::Packet {
+                step: $step,
+                expected: stringify!($pattern).to_owned(),
+                actual: format!("{:?}", $actual),
+            });
+        }
+    };
+}
+
+/// Fails the test with both packets unless they are equal
+fn expect_eq(step: &'static str, expected: Incoming, actual: Incoming) -> Result<(), Error> {
+    match expected == actual {
+        true => Ok(()),
+        false => Err(Error::Packet {
+            step,
+            expected: format!("{expected:?}"),
+            actual: format!("{actual:?}"),
+        }),
+    }
+}
+
+/// Fails the test unless the broker closed the connection
+fn expect_closed(
+    step: &'static str,
+    actual: Result<Incoming, ConnectionError>,
+) -> Result<(), Error> {
+    match actual {
+        Err(_) => Ok(()),
+        Ok(actual) => Err(Error::Packet {
+            step,
+            expected: "connection closed".to_owned(),
+            actual: format!("{actual:?}"),
+        }),
+    }
+}
 
 // TODO?: Connecting to same socket twice should fail
-pub async fn test_basic(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Basic test".yellow().to_string());
+pub async fn test_basic(conformance_config: &ConformanceConfig) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-basic",
         &conformance_config.server,
@@ -29,66 +68,65 @@ pub async fn test_basic(conformance_config: &ConformanceConfig) {
 
     let (client, mut eventloop) = common::get_client(config.clone());
 
-    let incoming = eventloop.poll().await.unwrap(); // connack
-    assert_eq!(
-        incoming,
+    let incoming = eventloop.poll().await?; // connack
+    expect_eq(
+        "connack",
         Incoming::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
-
-    client.subscribe("topic/q0", QoS::AtMostOnce).await.unwrap();
-    let incoming = eventloop.poll().await.unwrap(); // suback
-    assert_eq!(
+            code: ConnectReturnCode::Success,
+        }),
         incoming,
+    )?;
+
+    client.subscribe("topic/q0", QoS::AtMostOnce).await?;
+    let incoming = eventloop.poll().await?; // suback
+    expect_eq(
+        "suback of topic/q0",
         Incoming::SubAck(SubAck {
             pkid: 1,
             return_codes: [SubscribeReasonCode::Success(QoS::AtMostOnce)].to_vec(),
-        })
-    );
-
-    client
-        .subscribe("topic/q1", QoS::AtLeastOnce)
-        .await
-        .unwrap();
-    let incoming = eventloop.poll().await.unwrap(); // suback
-    assert_eq!(
+        }),
         incoming,
+    )?;
+
+    client.subscribe("topic/q1", QoS::AtLeastOnce).await?;
+    let incoming = eventloop.poll().await?; // suback
+    expect_eq(
+        "suback of topic/q1",
         Incoming::SubAck(SubAck {
             pkid: 2,
             return_codes: [SubscribeReasonCode::Success(QoS::AtLeastOnce)].to_vec(),
-        })
-    );
+        }),
+        incoming,
+    )?;
 
     // Qos 0 Publish
     client
         .publish("topic/q0", QoS::AtMostOnce, false, "QoS::AtMostOnce")
-        .await
-        .unwrap();
+        .await?;
 
-    let incoming = eventloop.poll().await.unwrap(); // incoming:publish
-    assert!(matches!(incoming, Incoming::Publish(Publish { .. })));
+    let incoming = eventloop.poll().await?; // incoming:publish
+    expect_matches!("qos 0 publish", incoming, Incoming::Publish(Publish { .. }));
 
     // Qos 1 Publish
     client
         .publish("topic/q1", QoS::AtLeastOnce, false, "QoS::AtLeastOnce")
-        .await
-        .unwrap();
+        .await?;
 
-    let incoming = eventloop.poll().await.unwrap(); // incoming:publish
-    assert!(matches!(incoming, Incoming::PubAck(PubAck { .. })));
+    let incoming = eventloop.poll().await?; // incoming:publish
+    expect_matches!(
+        "puback of qos 1 publish",
+        incoming,
+        Incoming::PubAck(PubAck { .. })
+    );
 
-    let incoming = eventloop.poll().await.unwrap(); // incoming:publish
-    assert!(matches!(incoming, Incoming::Publish(Publish { .. })));
+    let incoming = eventloop.poll().await?; // incoming:publish
+    expect_matches!("qos 1 publish", incoming, Incoming::Publish(Publish { .. }));
 
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Basic test succedeed".green().to_string());
+    Ok(())
 }
 
-pub async fn session_test(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Session test".yellow().to_string());
-
+pub async fn session_test(conformance_config: &ConformanceConfig) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-session",
         &conformance_config.server,
@@ -98,11 +136,11 @@ pub async fn session_test(conformance_config: &ConformanceConfig) {
     config.set_clean_session(true);
 
     let (client, mut eventloop) = common::get_client(config.clone());
-    let incoming = eventloop.poll().await.unwrap(); // connack
-    assert!(matches!(incoming, Incoming::ConnAck(ConnAck { .. })));
+    let incoming = eventloop.poll().await?; // connack
+    expect_matches!("connack", incoming, Incoming::ConnAck(ConnAck { .. }));
 
-    client.disconnect().await.unwrap();
-    assert!(eventloop.poll().await.is_err());
+    client.disconnect().await?;
+    expect_closed("disconnect", eventloop.poll().await)?;
 
     let mut config2 = MqttOptions::new(
         "conformance-session",
@@ -113,37 +151,40 @@ pub async fn session_test(conformance_config: &ConformanceConfig) {
     config2.set_clean_session(false);
 
     let (client, mut eventloop) = common::get_client(config2.clone());
-    let incoming = eventloop.poll().await.unwrap(); // connack
+    let incoming = eventloop.poll().await?; // connack
 
-    assert_eq!(
-        incoming,
+    expect_eq(
+        "connack of persistent session",
         Packet::ConnAck(ConnAck {
             session_present: true,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        incoming,
+    )?;
 
-    client.subscribe("topic/a", QoS::AtMostOnce).await.unwrap();
-    let _ = eventloop.poll().await.unwrap(); // suback
+    client.subscribe("topic/a", QoS::AtMostOnce).await?;
+    let _ = eventloop.poll().await?; // suback
     drop(client);
     drop(eventloop);
 
     let (_client, mut eventloop) = common::get_client(config.clone());
-    let notification1 = eventloop.poll().await.unwrap(); // connack
+    let notification1 = eventloop.poll().await?; // connack
 
-    assert_eq!(
-        notification1,
+    expect_eq(
+        "connack of clean session",
         Packet::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Session test successful".green().to_string());
+            code: ConnectReturnCode::Success,
+        }),
+        notification1,
+    )?;
+
+    Ok(())
 }
 
-pub async fn test_overlapping_subscriptions(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Overlapping subscriptions test".yellow().to_string());
+pub async fn test_overlapping_subscriptions(
+    conformance_config: &ConformanceConfig,
+) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-overlapping-subscriptions",
         &conformance_config.server,
@@ -153,17 +194,16 @@ pub async fn test_overlapping_subscriptions(conformance_config: &ConformanceConf
     config.set_clean_session(true);
 
     let (client, mut eventloop) = common::get_client(config.clone());
-    let _ = eventloop.poll().await.unwrap(); // connack
+    let _ = eventloop.poll().await?; // connack
 
     client
         .subscribe_many(vec![
             SubscribeFilter::new("topic/+".to_string(), QoS::AtMostOnce),
             SubscribeFilter::new("topic/#".to_string(), QoS::AtLeastOnce),
         ])
-        .await
-        .unwrap();
+        .await?;
 
-    let _ = eventloop.poll().await.unwrap(); // suback
+    let _ = eventloop.poll().await?; // suback
     client
         .publish(
             "topic/a",
@@ -171,13 +211,12 @@ pub async fn test_overlapping_subscriptions(conformance_config: &ConformanceConf
             false,
             "overlapping topic filter",
         )
-        .await
-        .unwrap();
-    let _ = eventloop.poll().await.unwrap(); // puback
+        .await?;
+    let _ = eventloop.poll().await?; // puback
 
-    let notif1 = eventloop.poll().await.unwrap(); // publish from topic/+
-    let notif2 = eventloop.poll().await.unwrap(); // publish from topic/#
-                                                  // dbg!(&notif1, &notif2);
+    let notif1 = eventloop.poll().await?; // publish from topic/+
+    let notif2 = eventloop.poll().await?; // publish from topic/#
+                                          // dbg!(&notif1, &notif2);
 
     let notif1_is_publish = matches!(notif1, Incoming::Publish(Publish { .. }));
     let notif2_is_publish = matches!(notif2, Incoming::Publish(Publish { .. }));
@@ -196,20 +235,19 @@ pub async fn test_overlapping_subscriptions(conformance_config: &ConformanceConf
                 .to_string(),
         ),
         (false, false) => {
-            panic!("Should receive atleast 1 publish message");
+            return Err(Error::Packet {
+                step: "publishes of overlapping subscriptions",
+                expected: "at least 1 publish".to_owned(),
+                actual: format!("{notif1:?}, {notif2:?}"),
+            });
         }
     }
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println(
-        "Overlapping subscriptions test Successful"
-            .green()
-            .to_string(),
-    );
+
+    Ok(())
 }
 
 // TODO: Not disconnecting the client if keep_alive time has passed with no messages from client
-pub async fn test_keepalive(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Ping test".yellow().to_string());
+pub async fn test_keepalive(conformance_config: &ConformanceConfig) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-overlapping-subscriptions",
         &conformance_config.server,
@@ -224,26 +262,22 @@ pub async fn test_keepalive(conformance_config: &ConformanceConfig) {
     ));
 
     let (_client, mut eventloop) = common::get_client(config);
-    let _ = eventloop.poll().await.unwrap(); // connack
+    let _ = eventloop.poll().await?; // connack
 
-    thread::sleep(Duration::from_secs(10));
+    time::sleep(Duration::from_secs(10)).await;
 
     for i in 0..5 {
-        let incoming = eventloop.poll().await.unwrap();
-        assert!(matches!(incoming, Incoming::PingResp));
+        let incoming = eventloop.poll().await?;
+        expect_matches!("ping response", incoming, Incoming::PingResp);
         PROGRESS_BAR.println(format!("Ping response {} received", i).green().to_string());
     }
 
-    PROGRESS_BAR.println("Ping test successful".green().to_string());
+    Ok(())
 }
 
-pub async fn test_retain_on_different_connect(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message(
-        "Retained message test on different connect"
-            .yellow()
-            .to_string(),
-    );
-
+pub async fn test_retain_on_different_connect(
+    conformance_config: &ConformanceConfig,
+) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-retained-message",
         &conformance_config.server,
@@ -257,38 +291,42 @@ pub async fn test_retain_on_different_connect(conformance_config: &ConformanceCo
     let qos1topic = "fromb/qos 1";
     let wildcardtopic = "fromb/+";
 
-    let notification1 = eventloop.poll().await.unwrap(); // connack
-    assert_eq!(
-        notification1,
+    let notification1 = eventloop.poll().await?; // connack
+    expect_eq(
+        "connack",
         Packet::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notification1,
+    )?;
 
     client
         .publish(qos0topic, QoS::AtMostOnce, true, "QoS::AtMostOnce")
-        .await
-        .unwrap();
+        .await?;
 
     client
         .publish(qos1topic, QoS::AtLeastOnce, true, "QoS::AtLeastOnce")
-        .await
-        .unwrap();
+        .await?;
 
-    let _ = eventloop.poll().await.unwrap(); // incoming: puback
+    let _ = eventloop.poll().await?; // incoming: puback
 
-    client
-        .subscribe(wildcardtopic, QoS::AtMostOnce)
-        .await
-        .unwrap();
-    let _ = eventloop.poll().await.unwrap(); //suback
+    client.subscribe(wildcardtopic, QoS::AtMostOnce).await?;
+    let _ = eventloop.poll().await?; //suback
 
-    let notif1 = eventloop.poll().await.unwrap();
-    assert!(matches!(notif1, Incoming::Publish(Publish { .. })));
+    let notif1 = eventloop.poll().await?;
+    expect_matches!(
+        "retained qos 0 publish",
+        notif1,
+        Incoming::Publish(Publish { .. })
+    );
 
-    let notif2 = eventloop.poll().await.unwrap();
-    assert!(matches!(notif2, Incoming::Publish(Publish { .. })));
+    let notif2 = eventloop.poll().await?;
+    expect_matches!(
+        "retained qos 1 publish",
+        notif2,
+        Incoming::Publish(Publish { .. })
+    );
 
     drop(client);
     drop(eventloop);
@@ -302,56 +340,60 @@ pub async fn test_retain_on_different_connect(conformance_config: &ConformanceCo
 
     let (client2, mut eventloop2) = common::get_client(config2.clone());
 
-    let _ = eventloop2.poll().await.unwrap(); // connack
+    let _ = eventloop2.poll().await?; // connack
 
-    client2
-        .subscribe(wildcardtopic, QoS::AtMostOnce)
-        .await
-        .unwrap();
+    client2.subscribe(wildcardtopic, QoS::AtMostOnce).await?;
 
-    let _ = eventloop2.poll().await.unwrap(); //suback
+    let _ = eventloop2.poll().await?; //suback
 
-    let notif1 = eventloop2.poll().await.unwrap();
-    assert!(matches!(notif1, Incoming::Publish(Publish { .. })));
+    let notif1 = eventloop2.poll().await?;
+    expect_matches!(
+        "retained qos 0 publish on new client",
+        notif1,
+        Incoming::Publish(Publish { .. })
+    );
 
-    let notif2 = eventloop2.poll().await.unwrap();
-    assert!(matches!(notif2, Incoming::Publish(Publish { .. })));
+    let notif2 = eventloop2.poll().await?;
+    expect_matches!(
+        "retained qos 1 publish on new client",
+        notif2,
+        Incoming::Publish(Publish { .. })
+    );
 
     let (client, mut eventloop) = common::get_client(config.clone());
 
-    let notif1 = eventloop.poll().await.unwrap(); // connack
-    assert_eq!(
-        notif1,
+    let notif1 = eventloop.poll().await?; // connack
+    expect_eq(
+        "connack on reconnect",
         Incoming::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notif1,
+    )?;
 
-    client
-        .publish(qos0topic, QoS::AtMostOnce, true, "")
-        .await
-        .unwrap();
+    client.publish(qos0topic, QoS::AtMostOnce, true, "").await?;
 
     client
         .publish(qos1topic, QoS::AtLeastOnce, true, "")
-        .await
-        .unwrap();
+        .await?;
 
-    let _ = eventloop.poll().await.unwrap(); // incoming: puback
+    let _ = eventloop.poll().await?; // incoming: puback
 
-    let notif2 = eventloop.poll().await.unwrap();
+    let notif2 = eventloop.poll().await?;
 
     // We cleared all the retained messages so should only receive pings
-    assert_eq!(notif2, Incoming::PingResp);
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Retained message test Successful".green().to_string());
+    expect_eq(
+        "ping after clearing retained messages",
+        Incoming::PingResp,
+        notif2,
+    )?;
+
+    Ok(())
 }
 
 // TODO: messages not being retained
-pub async fn test_retained_messages(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Retained message test".yellow().to_string());
-
+pub async fn test_retained_messages(conformance_config: &ConformanceConfig) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-retained-message",
         &conformance_config.server,
@@ -365,91 +407,99 @@ pub async fn test_retained_messages(conformance_config: &ConformanceConfig) {
     let qos1topic = "fromb/qos 1";
     let wildcardtopic = "fromb/+";
 
-    let notification1 = eventloop.poll().await.unwrap(); // connack
-    assert_eq!(
-        notification1,
+    let notification1 = eventloop.poll().await?; // connack
+    expect_eq(
+        "connack",
         Packet::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notification1,
+    )?;
 
     client
         .publish(qos0topic, QoS::AtMostOnce, true, "QoS::AtMostOnce")
-        .await
-        .unwrap();
+        .await?;
 
     client
         .publish(qos1topic, QoS::AtLeastOnce, true, "QoS::AtLeastOnce")
-        .await
-        .unwrap();
+        .await?;
 
-    let _ = eventloop.poll().await.unwrap(); // incoming: puback
+    let _ = eventloop.poll().await?; // incoming: puback
 
-    client
-        .subscribe(wildcardtopic, QoS::AtMostOnce)
-        .await
-        .unwrap();
-    let _ = eventloop.poll().await.unwrap(); //suback
+    client.subscribe(wildcardtopic, QoS::AtMostOnce).await?;
+    let _ = eventloop.poll().await?; //suback
 
-    let notif1 = eventloop.poll().await.unwrap();
-    assert!(matches!(notif1, Incoming::Publish(Publish { .. })));
+    let notif1 = eventloop.poll().await?;
+    expect_matches!(
+        "retained qos 0 publish",
+        notif1,
+        Incoming::Publish(Publish { .. })
+    );
 
-    let notif2 = eventloop.poll().await.unwrap();
-    assert!(matches!(notif2, Incoming::Publish(Publish { .. })));
+    let notif2 = eventloop.poll().await?;
+    expect_matches!(
+        "retained qos 1 publish",
+        notif2,
+        Incoming::Publish(Publish { .. })
+    );
 
     drop(client);
     drop(eventloop);
 
     let (client, mut eventloop) = common::get_client(config.clone());
 
-    let notif1 = eventloop.poll().await.unwrap(); // connack
-    assert_eq!(
-        notif1,
+    let notif1 = eventloop.poll().await?; // connack
+    expect_eq(
+        "connack on reconnect",
         Incoming::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notif1,
+    )?;
 
-    client
-        .publish(qos0topic, QoS::AtMostOnce, true, "")
-        .await
-        .unwrap();
+    client.publish(qos0topic, QoS::AtMostOnce, true, "").await?;
 
     client
         .publish(qos1topic, QoS::AtLeastOnce, true, "")
-        .await
-        .unwrap();
+        .await?;
 
-    let _ = eventloop.poll().await.unwrap(); // incoming: puback
+    let _ = eventloop.poll().await?; // incoming: puback
 
-    let notif2 = eventloop.poll().await.unwrap();
+    let notif2 = eventloop.poll().await?;
 
     // We cleared all the retained messages so should only receive pings
-    assert_eq!(notif2, Incoming::PingResp);
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Retained message test Successful".green().to_string());
+    expect_eq(
+        "ping after clearing retained messages",
+        Incoming::PingResp,
+        notif2,
+    )?;
+
+    Ok(())
 }
 
 // TODO: Currently rumqttc panics for this test. According to spec broker should be the one handling this not client
-pub async fn test_zero_length_clientid(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Zero length clientid".yellow().to_string());
+#[allow(dead_code)]
+pub async fn test_zero_length_clientid(
+    conformance_config: &ConformanceConfig,
+) -> Result<(), Error> {
     let mut config = MqttOptions::new("", &conformance_config.server, conformance_config.port);
     config.set_keep_alive(Duration::from_secs(5));
     config.set_clean_session(true);
 
     let (_client, mut eventloop) = common::get_client(config);
 
-    let notification1 = eventloop.poll().await.unwrap(); // connack
+    let notification1 = eventloop.poll().await?; // connack
 
-    assert_eq!(
-        notification1,
+    expect_eq(
+        "connack",
         Packet::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notification1,
+    )?;
 
     let mut config2 = MqttOptions::new("", &conformance_config.server, conformance_config.port);
     config2.set_keep_alive(Duration::from_secs(5));
@@ -457,18 +507,22 @@ pub async fn test_zero_length_clientid(conformance_config: &ConformanceConfig) {
 
     let (_client, mut eventloop) = common::get_client(config2);
 
-    let notification1 = eventloop.poll().await.unwrap(); // connack
-    assert_eq!(
-        notification1,
+    let notification1 = eventloop.poll().await?; // connack
+    expect_eq(
+        "connack of persistent session",
         Packet::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::BadClientId
-        })
-    );
+            code: ConnectReturnCode::BadClientId,
+        }),
+        notification1,
+    )?;
+
+    Ok(())
 }
 
-pub async fn test_offline_message_queueing(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Offline message Queue test".yellow().to_string());
+pub async fn test_offline_message_queueing(
+    conformance_config: &ConformanceConfig,
+) -> Result<(), Error> {
     let mut config1 = MqttOptions::new(
         "conformance-offline-message-queue",
         &conformance_config.server,
@@ -478,12 +532,12 @@ pub async fn test_offline_message_queueing(conformance_config: &ConformanceConfi
     config1.set_clean_session(false);
 
     let (client1, mut eventloop1) = common::get_client(config1.clone());
-    let _ = eventloop1.poll().await.unwrap(); // connack
+    let _ = eventloop1.poll().await?; // connack
 
-    client1.subscribe("+/+", QoS::AtLeastOnce).await.unwrap();
-    let _ = eventloop1.poll().await.unwrap(); // suback
+    client1.subscribe("+/+", QoS::AtLeastOnce).await?;
+    let _ = eventloop1.poll().await?; // suback
 
-    client1.disconnect().await.unwrap();
+    client1.disconnect().await?;
     let _ = eventloop1.poll().await; // disconnect
 
     let mut config2 = MqttOptions::new(
@@ -495,37 +549,36 @@ pub async fn test_offline_message_queueing(conformance_config: &ConformanceConfi
     config2.set_clean_session(true);
 
     let (client2, mut eventloop2) = common::get_client(config2);
-    let _ = eventloop2.poll().await.unwrap(); // connack
+    let _ = eventloop2.poll().await?; // connack
 
     client2
         .publish("topic/a", QoS::AtMostOnce, true, "QoS::AtMostOnce")
-        .await
-        .unwrap();
+        .await?;
 
     client2
         .publish("topic/b", QoS::AtLeastOnce, true, "QoS::AtLeastOnce")
-        .await
-        .unwrap();
+        .await?;
 
-    let _ = eventloop2.poll().await.unwrap(); // incoming: puback
+    let _ = eventloop2.poll().await?; // incoming: puback
 
-    client2.disconnect().await.unwrap();
+    client2.disconnect().await?;
     let _ = eventloop2.poll().await;
 
     let (_client1, mut eventloop1) = common::get_client(config1.clone());
-    let notif1 = eventloop1.poll().await.unwrap(); // connack
+    let notif1 = eventloop1.poll().await?; // connack
 
-    assert_eq!(
-        notif1,
+    expect_eq(
+        "connack of persistent session",
         Incoming::ConnAck(ConnAck {
             session_present: true,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notif1,
+    )?;
 
     // NOTE: We store QoS0 publish's when client is not connected.
-    let notif1 = eventloop1.poll().await.unwrap(); // QoS0 publish
-    let notif2 = eventloop1.poll().await.unwrap(); // QoS1 publish
+    let notif1 = eventloop1.poll().await?; // QoS0 publish
+    let notif2 = eventloop1.poll().await?; // QoS1 publish
     let notif1_is_pubilsh = matches!(notif1, Incoming::Publish(Publish { .. }));
     let notif2_is_pubilsh = matches!(notif2, Incoming::Publish(Publish { .. }));
 
@@ -545,28 +598,28 @@ pub async fn test_offline_message_queueing(conformance_config: &ConformanceConfi
             );
         }
         _ => {
-            panic!("First notif should be a publish");
+            return Err(Error::Packet {
+                step: "queued publishes",
+                expected: "Publish first".to_owned(),
+                actual: format!("{notif1:?}, {notif2:?}"),
+            });
         }
     }
 
     client2
         .publish("topic/a", QoS::AtLeastOnce, true, "")
-        .await
-        .unwrap();
-    let _ = eventloop2.poll().await.unwrap(); // incoming: puback
+        .await?;
+    let _ = eventloop2.poll().await?; // incoming: puback
 
     client2
         .publish("topic/a", QoS::AtLeastOnce, true, "")
-        .await
-        .unwrap();
-    let _ = eventloop2.poll().await.unwrap(); // incoming: puback
+        .await?;
+    let _ = eventloop2.poll().await?; // incoming: puback
 
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Offline message Queue test successful".green().to_string());
+    Ok(())
 }
 
-pub async fn test_will_message(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Will message test".yellow().to_string());
+pub async fn test_will_message(conformance_config: &ConformanceConfig) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-will-message",
         &conformance_config.server,
@@ -584,14 +637,15 @@ pub async fn test_will_message(conformance_config: &ConformanceConfig) {
 
     let (_client, mut eventloop) = common::get_client(config);
 
-    let notif1 = eventloop.poll().await.unwrap(); // connack
-    assert_eq!(
-        notif1,
+    let notif1 = eventloop.poll().await?; // connack
+    expect_eq(
+        "connack",
         Incoming::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notif1,
+    )?;
 
     let mut config2 = MqttOptions::new(
         "conformance-will-message2",
@@ -602,28 +656,25 @@ pub async fn test_will_message(conformance_config: &ConformanceConfig) {
 
     let (client2, mut eventloop2) = common::get_client(config2);
 
-    let _ = eventloop2.poll().await.unwrap(); // connack
+    let _ = eventloop2.poll().await?; // connack
 
-    client2
-        .subscribe("topic/will", QoS::AtMostOnce)
-        .await
-        .unwrap();
+    client2.subscribe("topic/will", QoS::AtMostOnce).await?;
 
-    let _ = eventloop2.poll().await.unwrap(); // suback
+    let _ = eventloop2.poll().await?; // suback
 
     drop(eventloop);
 
-    thread::sleep(Duration::from_secs(10));
+    time::sleep(Duration::from_secs(10)).await;
+
+    let notif3 = eventloop2.poll().await?; // publish
 
-    let notif3 = eventloop2.poll().await.unwrap(); // publish
+    expect_matches!("will publish", notif3, Incoming::Publish(Publish { .. }));
 
-    assert!(matches!(notif3, Incoming::Publish(Publish { .. })));
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Will message test Successful".green().to_string());
+    Ok(())
 }
 
-pub async fn test_dollar_topic_filter(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Dollar topic test".yellow().to_string());
+#[allow(dead_code)]
+pub async fn test_dollar_topic_filter(conformance_config: &ConformanceConfig) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-dollar-topic-filter",
         &conformance_config.server,
@@ -632,25 +683,27 @@ pub async fn test_dollar_topic_filter(conformance_config: &ConformanceConfig) {
     config.set_keep_alive(Duration::from_secs(5));
 
     let (client1, mut eventloop1) = common::get_client(config.clone());
-    let _ = eventloop1.poll().await.unwrap(); // connack
+    let _ = eventloop1.poll().await?; // connack
 
-    client1.subscribe("+/+", QoS::AtMostOnce).await.unwrap();
-    let _ = eventloop1.poll().await.unwrap(); // suback
+    client1.subscribe("+/+", QoS::AtMostOnce).await?;
+    let _ = eventloop1.poll().await?; // suback
 
     client1
         .publish("$dollar_test", QoS::AtMostOnce, false, "")
-        .await
-        .unwrap();
-    let _ = eventloop1.poll().await.unwrap(); // puback
-
-    let notif1 = eventloop1.poll().await.unwrap();
-    assert!(matches!(notif1, Incoming::PingResp));
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Dollar topic test Successful".green().to_string());
+        .await?;
+    let _ = eventloop1.poll().await?; // puback
+
+    let notif1 = eventloop1.poll().await?;
+    expect_matches!(
+        "ping instead of $ topic publish",
+        notif1,
+        Incoming::PingResp
+    );
+
+    Ok(())
 }
 
-pub async fn test_unsubscribe(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Unsubscribe test".yellow().to_string());
+pub async fn test_unsubscribe(conformance_config: &ConformanceConfig) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-unsubscribe",
         &conformance_config.server,
@@ -659,24 +712,25 @@ pub async fn test_unsubscribe(conformance_config: &ConformanceConfig) {
     config.set_keep_alive(Duration::from_secs(5));
 
     let (client1, mut eventloop1) = common::get_client(config.clone());
-    let _ = eventloop1.poll().await.unwrap(); // connack
+    let _ = eventloop1.poll().await?; // connack
 
-    client1.subscribe("topicA", QoS::AtMostOnce).await.unwrap();
-    let _ = eventloop1.poll().await.unwrap(); // suback
+    client1.subscribe("topicA", QoS::AtMostOnce).await?;
+    let _ = eventloop1.poll().await?; // suback
 
-    client1
-        .subscribe("topicA/B", QoS::AtMostOnce)
-        .await
-        .unwrap();
-    let _ = eventloop1.poll().await.unwrap(); // suback
+    client1.subscribe("topicA/B", QoS::AtMostOnce).await?;
+    let _ = eventloop1.poll().await?; // suback
 
-    client1.subscribe("topicC", QoS::AtMostOnce).await.unwrap();
-    let _ = eventloop1.poll().await.unwrap(); // suback
+    client1.subscribe("topicC", QoS::AtMostOnce).await?;
+    let _ = eventloop1.poll().await?; // suback
 
-    client1.unsubscribe("topicA").await.unwrap();
-    let notif1 = eventloop1.poll().await.unwrap(); // unsuback
+    client1.unsubscribe("topicA").await?;
+    let notif1 = eventloop1.poll().await?; // unsuback
 
-    assert!(matches!(notif1, Incoming::UnsubAck(UnsubAck { .. })));
+    expect_matches!(
+        "unsuback of topicA",
+        notif1,
+        Incoming::UnsubAck(UnsubAck { .. })
+    );
 
     let mut config = MqttOptions::new(
         "conformance-unsubscribe2",
@@ -686,41 +740,45 @@ pub async fn test_unsubscribe(conformance_config: &ConformanceConfig) {
     config.set_keep_alive(Duration::from_secs(5));
 
     let (client2, mut eventloop2) = common::get_client(config.clone());
-    let _ = eventloop2.poll().await.unwrap(); // connack
+    let _ = eventloop2.poll().await?; // connack
 
     client2
         .publish("topicA", QoS::AtLeastOnce, false, "")
-        .await
-        .unwrap();
-    let _ = eventloop2.poll().await.unwrap(); // puback
+        .await?;
+    let _ = eventloop2.poll().await?; // puback
     client2
         .publish("topicA/B", QoS::AtLeastOnce, false, "")
-        .await
-        .unwrap();
-    let _ = eventloop2.poll().await.unwrap(); // puback
+        .await?;
+    let _ = eventloop2.poll().await?; // puback
     client2
         .publish("topicC", QoS::AtLeastOnce, false, "")
-        .await
-        .unwrap();
-    let _ = eventloop2.poll().await.unwrap(); // puback
+        .await?;
+    let _ = eventloop2.poll().await?; // puback
 
-    let notif1 = eventloop1.poll().await.unwrap();
-    assert!(matches!(notif1, Incoming::Publish(Publish { .. })));
+    let notif1 = eventloop1.poll().await?;
+    expect_matches!(
+        "publish of topicA/B",
+        notif1,
+        Incoming::Publish(Publish { .. })
+    );
 
-    let notif2 = eventloop1.poll().await.unwrap();
-    assert!(matches!(notif2, Incoming::Publish(Publish { .. })));
+    let notif2 = eventloop1.poll().await?;
+    expect_matches!(
+        "publish of topicC",
+        notif2,
+        Incoming::Publish(Publish { .. })
+    );
 
     for _ in 0..5 {
-        let notif3 = eventloop1.poll().await.unwrap();
+        let notif3 = eventloop1.poll().await?;
         // dbg!(&notif3);
-        assert!(matches!(notif3, Incoming::PingResp));
+        expect_matches!("ping after unsubscribing", notif3, Incoming::PingResp);
     }
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Unsubscribe test Successful".green().to_string());
+
+    Ok(())
 }
 
-pub async fn test_subscribe_failure(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Subscribe failure test".yellow().to_string());
+pub async fn test_subscribe_failure(conformance_config: &ConformanceConfig) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-sub-failure",
         &conformance_config.server,
@@ -730,22 +788,22 @@ pub async fn test_subscribe_failure(conformance_config: &ConformanceConfig) {
 
     let (client, mut eventloop) = common::get_client(config);
 
-    let _ = eventloop.poll().await.unwrap(); // connack
+    let _ = eventloop.poll().await?; // connack
 
     client
         .subscribe("$SYS/rumqttd/donotsubscribe", QoS::AtMostOnce)
-        .await
-        .unwrap();
+        .await?;
 
     // TODO: Err should contain more descriptive message
-    assert!(eventloop.poll().await.is_err());
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Subscribe failure test Successful".green().to_string());
+    expect_closed("suback of $SYS topic", eventloop.poll().await)?;
+
+    Ok(())
 }
 
 // TODO: re-eval this after retransmission is implemented in broker
-pub async fn test_redelivery_on_reconnect(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Redelivery test".yellow().to_string());
+pub async fn test_redelivery_on_reconnect(
+    conformance_config: &ConformanceConfig,
+) -> Result<(), Error> {
     let mut config = MqttOptions::new(
         "conformance-test-redelivery",
         &conformance_config.server,
@@ -756,10 +814,10 @@ pub async fn test_redelivery_on_reconnect(conformance_config: &ConformanceConfig
         .set_clean_session(false);
 
     let (client, mut eventloop) = common::get_client(config.clone());
-    let _ = eventloop.poll().await.unwrap(); // connack
+    let _ = eventloop.poll().await?; // connack
 
-    client.subscribe("+/+", QoS::AtLeastOnce).await.unwrap();
-    let _ = eventloop.poll().await.unwrap(); // suback
+    client.subscribe("+/+", QoS::AtLeastOnce).await?;
+    let _ = eventloop.poll().await?; // suback
 
     drop(eventloop);
 
@@ -771,31 +829,34 @@ pub async fn test_redelivery_on_reconnect(conformance_config: &ConformanceConfig
     config2.set_keep_alive(Duration::from_secs(5));
 
     let (client2, mut eventloop2) = common::get_client(config2);
-    let _ = eventloop2.poll().await.unwrap(); // connack
+    let _ = eventloop2.poll().await?; // connack
 
     // Qos 1 Publish
     client2
             .publish("topic/a", QoS::AtLeastOnce, false, "1111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111")
-            .await
-            .unwrap();
+            .await?;
 
-    let _ = eventloop2.poll().await.unwrap(); // puback
+    let _ = eventloop2.poll().await?; // puback
 
     // drop(eventloop);
 
     let (_, mut eventloop) = common::get_client(config);
-    let _ = eventloop.poll().await.unwrap(); // connack
+    let _ = eventloop.poll().await?; // connack
 
-    let incoming1 = eventloop.poll().await.unwrap(); // incoming:publish
-                                                     // dbg!(&incoming1);
-    assert!(matches!(incoming1, Incoming::Publish(Publish { .. })));
+    let incoming1 = eventloop.poll().await?; // incoming:publish
+                                             // dbg!(&incoming1);
+    expect_matches!(
+        "redelivered publish",
+        incoming1,
+        Incoming::Publish(Publish { .. })
+    );
 
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println("Redelivery test Successful".green().to_string());
+    Ok(())
 }
 
-pub async fn test_connack_with_clean_session(conformance_config: &ConformanceConfig) {
-    PROGRESS_BAR.set_message("Connack with clean session test".yellow().to_string());
+pub async fn test_connack_with_clean_session(
+    conformance_config: &ConformanceConfig,
+) -> Result<(), Error> {
     // To make sure any of the previous tests doesn't affect this create a connection and drop it
     // immediately to clean any previous state
     let mut config = MqttOptions::new(
@@ -805,8 +866,8 @@ pub async fn test_connack_with_clean_session(conformance_config: &ConformanceCon
     );
     config.set_keep_alive(Duration::from_secs(5));
     let (client, mut eventloop) = common::get_client(config);
-    client.subscribe("topic/a", QoS::AtMostOnce).await.unwrap();
-    let _ = eventloop.poll().await.unwrap(); // connack
+    client.subscribe("topic/a", QoS::AtMostOnce).await?;
+    let _ = eventloop.poll().await?; // connack
     drop(client);
     drop(eventloop);
 
@@ -819,9 +880,9 @@ pub async fn test_connack_with_clean_session(conformance_config: &ConformanceCon
     config.set_keep_alive(Duration::from_secs(5));
     config.set_clean_session(false);
     let (client, mut eventloop) = common::get_client(config);
-    let _ = eventloop.poll().await.unwrap(); // connack
-    client.subscribe("topic/a", QoS::AtMostOnce).await.unwrap();
-    let _ = eventloop.poll().await.unwrap(); // suback
+    let _ = eventloop.poll().await?; // connack
+    client.subscribe("topic/a", QoS::AtMostOnce).await?;
+    let _ = eventloop.poll().await?; // suback
     drop(client);
     drop(eventloop);
 
@@ -835,15 +896,16 @@ pub async fn test_connack_with_clean_session(conformance_config: &ConformanceCon
     config.set_clean_session(false);
     let (client, mut eventloop) = common::get_client(config);
 
-    let notification1 = eventloop.poll().await.unwrap(); // connack
+    let notification1 = eventloop.poll().await?; // connack
 
-    assert_eq!(
-        notification1,
+    expect_eq(
+        "connack of persistent session",
         Packet::ConnAck(ConnAck {
             session_present: true,
-            code: ConnectReturnCode::Success
-        })
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notification1,
+    )?;
     drop(client);
     drop(eventloop);
 
@@ -857,19 +919,16 @@ pub async fn test_connack_with_clean_session(conformance_config: &ConformanceCon
 
     let (_client, mut eventloop) = common::get_client(config);
 
-    let notification1 = eventloop.poll().await.unwrap(); // connack
+    let notification1 = eventloop.poll().await?; // connack
 
-    assert_eq!(
-        notification1,
+    expect_eq(
+        "connack of clean session",
         Packet::ConnAck(ConnAck {
             session_present: false,
-            code: ConnectReturnCode::Success
-        })
-    );
-    PROGRESS_BAR.inc(1);
-    PROGRESS_BAR.println(
-        "Connack with clean session test Successful"
-            .green()
-            .to_string(),
-    );
+            code: ConnectReturnCode::Success,
+        }),
+        notification1,
+    )?;
+
+    Ok(())
 }
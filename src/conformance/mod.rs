@@ -1,16 +1,19 @@
 mod basic;
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use basic::*;
+use colored::Colorize;
+use futures::future::BoxFuture;
 use indicatif::ProgressBar;
 use once_cell::sync::Lazy;
+use tokio::time;
 
 use crate::common::PROGRESS_STYLE;
 use crate::ConformanceConfig;
 
 pub static PROGRESS_BAR: Lazy<indicatif::ProgressBar> = Lazy::new(|| {
-    let progress_bar = ProgressBar::new(12)
+    let progress_bar = ProgressBar::new(0)
         .with_prefix("Conformance test:")
         .with_style((*PROGRESS_STYLE).clone());
 
@@ -18,25 +21,307 @@ pub static PROGRESS_BAR: Lazy<indicatif::ProgressBar> = Lazy::new(|| {
     progress_bar
 });
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-pub async fn start(config: ConformanceConfig) {
-    test_basic(&config).await;
-    test_keepalive(&config).await;
-    session_test(&config).await;
-    test_will_message(&config).await;
-    test_connack_with_clean_session(&config).await;
-    test_offline_message_queueing(&config).await;
-    test_subscribe_failure(&config).await;
-    test_redelivery_on_reconnect(&config).await;
-    test_overlapping_subscriptions(&config).await;
-    test_retained_messages(&config).await;
-    test_retain_on_different_connect(&config).await;
-    test_unsubscribe(&config).await;
-
-    // NOTE: Client cannot publish to $ topics
-    // test_dollar_topic_filter().await;
-
-    // NOTE: rumqttc don't allow empty clientID with clean_session false
-    // test_zero_length_clientid().await;
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    // boxed to keep test results small
+    #[error("Connection error = {0:?}")]
+    Connection(Box<rumqttc::ConnectionError>),
+    #[error("Client error = {0:?}")]
+    Client(#[from] rumqttc::ClientError),
+    #[error("{step}: expected {expected}, got {actual}")]
+    Packet {
+        step: &'static str,
+        expected: String,
+        actual: String,
+    },
+    #[error("Timed out after {0}s")]
+    Timeout(u64),
+}
+
+impl From<rumqttc::ConnectionError> for Error {
+    fn from(e: rumqttc::ConnectionError) -> Self {
+        Error::Connection(Box::new(e))
+    }
+}
+
+type Run = for<'a> fn(&'a ConformanceConfig) -> BoxFuture<'a, Result<(), Error>>;
+
+enum Outcome {
+    Passed(Duration),
+    Failed(Duration, Error),
+    /// Not selected by `--filter` and `--skip`
+    Skipped,
+}
+
+/// Conformance test of a broker
+pub struct Test {
+    pub name: &'static str,
+    /// Section of the MQTT 3.1.1 spec the test checks
+    pub section: &'static str,
+    pub tags: &'static [&'static str],
+    run: Run,
+}
+
+impl Test {
+    /// Whether `pattern` is part of the name, one of the tags or a spec
+    /// section the test's one is in
+    fn matches(&self, pattern: &str) -> bool {
+        self.name.contains(pattern)
+            || self.tags.contains(&pattern)
+            || self.section == pattern
+            || self.section.starts_with(&format!("{pattern}."))
+    }
+
+    /// Whether any `--filter` matches, without one all the tests do, and no
+    /// `--skip` does
+    fn selected(&self, config: &ConformanceConfig) -> bool {
+        (config.filters.is_empty() || config.filters.iter().any(|f| self.matches(f)))
+            && !config.skips.iter().any(|f| self.matches(f))
+    }
+}
+
+/// Tests to run, in order
+fn selection(config: &ConformanceConfig) -> Vec<&'static Test> {
+    TESTS.iter().filter(|t| t.selected(config)).collect()
+}
+
+// NOTE: Client cannot publish to $ topics, so `test_dollar_topic_filter` isn't
+// registered
+// NOTE: rumqttc don't allow empty clientID with clean_session false, so
+// `test_zero_length_clientid` isn't registered
+/// Every test, in the order they run
+pub const TESTS: [Test; 12] = [
+    Test {
+        name: "basic",
+        section: "4.3",
+        tags: &["publish", "subscribe", "qos"],
+        run: |c| Box::pin(test_basic(c)),
+    },
+    Test {
+        name: "keepalive",
+        section: "3.1.2.10",
+        tags: &["keepalive", "connect"],
+        run: |c| Box::pin(test_keepalive(c)),
+    },
+    Test {
+        name: "session",
+        section: "3.1.2.4",
+        tags: &["session", "connect"],
+        run: |c| Box::pin(session_test(c)),
+    },
+    Test {
+        name: "will-message",
+        section: "3.1.2.5",
+        tags: &["will", "connect"],
+        run: |c| Box::pin(test_will_message(c)),
+    },
+    Test {
+        name: "connack-clean-session",
+        section: "3.2.2.2",
+        tags: &["session", "connect"],
+        run: |c| Box::pin(test_connack_with_clean_session(c)),
+    },
+    Test {
+        name: "offline-message-queueing",
+        section: "3.1.2.4",
+        tags: &["session", "qos"],
+        run: |c| Box::pin(test_offline_message_queueing(c)),
+    },
+    Test {
+        name: "subscribe-failure",
+        section: "3.9.3",
+        tags: &["subscribe"],
+        run: |c| Box::pin(test_subscribe_failure(c)),
+    },
+    Test {
+        name: "redelivery-on-reconnect",
+        section: "4.4",
+        tags: &["session", "qos"],
+        run: |c| Box::pin(test_redelivery_on_reconnect(c)),
+    },
+    Test {
+        name: "overlapping-subscriptions",
+        section: "3.3.5",
+        tags: &["subscribe", "wildcard"],
+        run: |c| Box::pin(test_overlapping_subscriptions(c)),
+    },
+    Test {
+        name: "retained-messages",
+        section: "3.3.1.3",
+        tags: &["retain"],
+        run: |c| Box::pin(test_retained_messages(c)),
+    },
+    Test {
+        name: "retain-on-different-connect",
+        section: "3.3.1.3",
+        tags: &["retain"],
+        run: |c| Box::pin(test_retain_on_different_connect(c)),
+    },
+    Test {
+        name: "unsubscribe",
+        section: "3.10.4",
+        tags: &["unsubscribe", "subscribe"],
+        run: |c| Box::pin(test_unsubscribe(c)),
+    },
+];
+
+/// Runs the selected tests, carrying on past failures, and prints a summary.
+/// Returns false if any test failed
+pub async fn start(config: ConformanceConfig) -> bool {
+    let selection = selection(&config);
+    if config.list {
+        println!("{:<28} {:<10} Tags", "Name", "Section");
+        println!("{}", "-".repeat(60));
+        for test in selection {
+            println!(
+                "{:<28} {:<10} {}",
+                test.name,
+                test.section,
+                test.tags.join(", ")
+            );
+        }
+        return true;
+    }
+
+    PROGRESS_BAR.set_length(selection.len() as u64);
+    let mut outcomes = Vec::with_capacity(TESTS.len());
+    for test in TESTS.iter() {
+        if !test.selected(&config) {
+            outcomes.push(Outcome::Skipped);
+            continue;
+        }
+
+        PROGRESS_BAR.set_message(test.name.yellow().to_string());
+        let start = Instant::now();
+        let result = time::timeout(Duration::from_secs(config.timeout), (test.run)(&config))
+            .await
+            .unwrap_or(Err(Error::Timeout(config.timeout)));
+        PROGRESS_BAR.inc(1);
+
+        let outcome = match result {
+            Ok(()) => {
+                let message = format!("{} test successful", test.name);
+                PROGRESS_BAR.println(message.green().to_string());
+                Outcome::Passed(start.elapsed())
+            }
+            Err(e) => {
+                let message = format!("{} test failed: {}", test.name, e);
+                PROGRESS_BAR.println(message.red().to_string());
+                Outcome::Failed(start.elapsed(), e)
+            }
+        };
+        outcomes.push(outcome);
+    }
+
     PROGRESS_BAR.finish_and_clear();
+    summary(&outcomes)
+}
+
+fn summary(outcomes: &[Outcome]) -> bool {
+    println!(
+        "{:<28} {:<10} {:<8} {:>8}",
+        "Test", "Section", "Result", "Time"
+    );
+    println!("{}", "-".repeat(57));
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (test, outcome) in TESTS.iter().zip(outcomes) {
+        let (result, elapsed) = match outcome {
+            Outcome::Passed(elapsed) => {
+                passed += 1;
+                (format!("{:<8}", "passed").green(), Some(elapsed))
+            }
+            Outcome::Failed(elapsed, _) => {
+                failed += 1;
+                (format!("{:<8}", "FAILED").red(), Some(elapsed))
+            }
+            Outcome::Skipped => {
+                skipped += 1;
+                (format!("{:<8}", "skipped").normal(), None)
+            }
+        };
+
+        let elapsed = elapsed.map_or(String::new(), |v| format!("{:.1}s", v.as_secs_f64()));
+        println!(
+            "{:<28} {:<10} {} {:>8}",
+            test.name, test.section, result, elapsed
+        );
+    }
+
+    for (test, outcome) in TESTS.iter().zip(outcomes) {
+        if let Outcome::Failed(_, e) = outcome {
+            println!("\n{} {}", test.name.red(), e);
+        }
+    }
+
+    println!("\nPassed = {passed}, failed = {failed}, skipped = {skipped}");
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn test(name: &str) -> &'static Test {
+        TESTS.iter().find(|t| t.name == name).unwrap()
+    }
+
+    /// Names of the tests these arguments select
+    fn names(args: &str) -> Vec<&'static str> {
+        let args = format!("conformance {args}");
+        let config = ConformanceConfig::parse_from(args.split_whitespace());
+        selection(&config).iter().map(|t| t.name).collect()
+    }
+
+    #[test]
+    fn tests_match_names_tags_and_sections() {
+        let retained = test("retained-messages");
+        assert!(retained.matches("retain"));
+        assert!(retained.matches("messages"));
+        assert!(retained.matches("3.3.1.3"));
+        assert!(retained.matches("3.3"));
+        assert!(retained.matches("3"));
+        // sections match whole numbers
+        assert!(!retained.matches("3.3.1.30"));
+        assert!(!retained.matches("3.3.1.3.1"));
+        assert!(!retained.matches("3.31"));
+
+        let session = test("session");
+        assert!(session.matches("connect"));
+        // tags match exactly
+        assert!(!session.matches("conn"));
+        assert!(!test("basic").matches("pub"));
+    }
+
+    #[test]
+    fn filters_and_skips_select_tests() {
+        assert_eq!(names("").len(), TESTS.len());
+        assert_eq!(
+            names("--filter 3.3"),
+            [
+                "overlapping-subscriptions",
+                "retained-messages",
+                "retain-on-different-connect"
+            ]
+        );
+        assert_eq!(
+            names("--filter retain --filter unsubscribe"),
+            [
+                "retained-messages",
+                "retain-on-different-connect",
+                "unsubscribe"
+            ]
+        );
+        // skips win over filters
+        assert_eq!(
+            names("--filter session --skip qos"),
+            ["session", "connack-clean-session"]
+        );
+        // 4 tagged connect and 2 with it in their name
+        assert_eq!(names("--skip connect").len(), TESTS.len() - 6);
+        assert!(names("--filter retain --skip 3.3").is_empty());
+        assert!(names("--filter nothing").is_empty());
+    }
 }
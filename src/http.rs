@@ -0,0 +1,315 @@
+use std::{convert::TryFrom, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use rumqttc::tokio_rustls::{
+    rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+    TlsConnector,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+
+/// Longest a request to another server, or from a client, may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: String) -> Response {
+        Response {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response {
+            status: 404,
+            content_type: "text/plain",
+            body: "not found\n".to_owned(),
+        }
+    }
+}
+
+/// Binds to `addr` and answers every GET request with the response of
+/// `handler` for its path. This is only meant for scraping live state of a
+/// run, so requests are served one response per connection
+pub async fn serve<F>(addr: SocketAddr, handler: F) -> io::Result<()>
+where
+    F: Fn(&str) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let handler = Arc::new(handler);
+
+    task::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Http accept error = {:?}", e);
+                    continue;
+                }
+            };
+
+            let handler = handler.clone();
+            task::spawn(async move {
+                if let Err(e) = respond(stream, handler.as_ref()).await {
+                    debug!("Http peer = {}, error = {:?}", peer, e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn respond<F>(mut stream: TcpStream, handler: &F) -> io::Result<()>
+where
+    F: Fn(&str) -> Response,
+{
+    // Only the request line matters, headers and body are ignored. Clients
+    // which stall don't keep the connection open
+    let mut request = Vec::new();
+    let read = async {
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8 * 1024 {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        io::Result::Ok(())
+    };
+    time::timeout(TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => handler(path),
+        _ => Response {
+            status: 405,
+            content_type: "text/plain",
+            body: "method not allowed\n".to_owned(),
+        },
+    };
+
+    let reason = match response.status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Posts `body` to an http or https `url` and returns the status and body of
+/// the response. Meant for the few calls to sinks and webhooks, so every
+/// request opens its own connection
+pub async fn post(
+    url: &str,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> io::Result<(u16, String)> {
+    let invalid = |error: &str| io::Error::new(io::ErrorKind::InvalidInput, error.to_owned());
+    let (url, tls) = match url.split_once("://") {
+        Some(("http", url)) => (url, false),
+        Some(("https", url)) => (url, true),
+        _ => return Err(invalid("only http and https urls are supported")),
+    };
+    let (host, path) = match url.find('/') {
+        Some(i) => (&url[..i], &url[i..]),
+        None => (url, "/"),
+    };
+    if host.is_empty() {
+        return Err(invalid("url has no host"));
+    }
+
+    let (name, addr) = match host.split_once(':') {
+        Some((name, _)) => (name, host.to_owned()),
+        None if tls => (host, format!("{host}:443")),
+        None => (host, format!("{host}:80")),
+    };
+
+    let mut request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        request += &format!("{name}: {value}\r\n");
+    }
+    request += "\r\n";
+    request += body;
+
+    let response = time::timeout(TIMEOUT, async {
+        let stream = TcpStream::connect(addr).await?;
+        match tls {
+            true => exchange(connect_tls(name, stream).await?, &request).await,
+            false => exchange(stream, &request).await,
+        }
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+
+    parse(&response)
+}
+
+/// Verifies `name` with the native root certificates
+async fn connect_tls(
+    name: &str,
+    stream: TcpStream,
+) -> io::Result<impl AsyncRead + AsyncWrite + Unpin> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs()? {
+        // certificates rustls can't parse can't verify anything either
+        let _ = roots.add(&Certificate(cert.0));
+    }
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid host name"))?;
+    TlsConnector::from(Arc::new(config))
+        .connect(name, stream)
+        .await
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &str,
+) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response).await {
+        Ok(_) => Ok(response),
+        // plenty of tls servers close without a close_notify
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => Ok(response),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse(response: &[u8]) -> io::Result<(u16, String)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid http response");
+    let end = find(response, b"\r\n\r\n").ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|v| v.split_whitespace().nth(1))
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(invalid)?;
+
+    let chunked = lines.any(|v| {
+        let v = v.to_ascii_lowercase();
+        v.starts_with("transfer-encoding:") && v.contains("chunked")
+    });
+
+    let body = &response[end + 4..];
+    let body = match chunked {
+        true => dechunk(body).ok_or_else(invalid)?,
+        false => body.to_vec(),
+    };
+
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// Body of a chunked response, as `<hex size>\r\n<chunk>\r\n` till an empty
+/// chunk
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let end = find(body, b"\r\n")?;
+        let size = std::str::from_utf8(&body[..end]).ok()?;
+        // chunk extensions follow a `;`
+        let size = size.split(';').next()?.trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Gets `path` of a server of `serve`
+#[cfg(test)]
+pub async fn get(addr: SocketAddr, path: &str) -> io::Result<(u16, String)> {
+    let stream = TcpStream::connect(addr).await?;
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+    parse(&exchange(stream, &request).await?)
+}
+
+/// Server which answers its connections with `responses` in order, for
+/// tests of clients. Returns its url and the requests it got
+#[cfg(test)]
+pub async fn stub(
+    responses: Vec<String>,
+) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+    task::spawn(async move {
+        for response in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            // head, then as much body as its length
+            loop {
+                if let Some(end) = find(&request, b"\r\n\r\n") {
+                    let head = String::from_utf8_lossy(&request[..end]).to_ascii_lowercase();
+                    let length = head
+                        .lines()
+                        .find_map(|v| v.strip_prefix("content-length:"))
+                        .map_or(0, |v| v.trim().parse().unwrap());
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+
+            let _ = tx.send(String::from_utf8_lossy(&request).into_owned());
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    });
+
+    (url, rx)
+}
@@ -6,21 +6,33 @@
 //! - Spawn n clients with publish and subscribe on the same topic (and report thoughput and latencies)
 //! - Spawn n clinets with publishes and 1 subscription to pull all the data (used to simulate a sink in the cloud)
 
-use std::fmt::Display;
+use std::{fmt::Display, net::SocketAddr};
 
+use assertion::Assertion;
 use clap::{Parser, ValueEnum};
+use runtime::{Runtime, RuntimeConfig, Threads};
 
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate colour;
 
+mod assertion;
 mod bench;
+mod canary;
 mod common;
+mod compare;
 mod conformance;
+mod http;
+mod metrics;
+mod replay;
+mod report;
 mod round;
+mod runtime;
+mod scenario;
 mod simulator;
 mod test;
+mod verify;
 
 #[derive(Debug, Parser)]
 #[command(
@@ -31,8 +43,20 @@ mod test;
 enum Config {
     Bench(BenchConfig),
     Round(RoundConfig),
-    Simulator(SimulatorConfig),
+    Simulator(Box<SimulatorConfig>),
     Conformance(ConformanceConfig),
+    /// Run a scenario file mixing several groups of clients
+    Run(ScenarioConfig),
+    /// Compare two saved run reports and flag regressions
+    Compare(CompareConfig),
+    /// Record the messages of topic filters to a log file
+    Record(RecordConfig),
+    /// Publish a recorded log again, as it was timed or faster
+    Replay(ReplayConfig),
+    /// Check that a sink stored every record of a simulator run
+    Verify(VerifyConfig),
+    /// Probe a broker forever, with a health endpoint and webhook alerts
+    Canary(CanaryConfig),
     Test,
 }
 
@@ -83,13 +107,42 @@ struct BenchConfig {
     /// Show subscriber stats
     #[arg(long, default_value = "false")]
     show_sub_stat: bool,
+    /// Check to run on the results, e.g. "p99_latency<50ms", "loss==0" or
+    /// "throughput>20000". Exits with a non zero code if any check fails
+    #[arg(long = "assert", value_name = "EXPR")]
+    asserts: Vec<Assertion>,
+    /// Save a json report of the run, to compare runs later
+    #[arg(long, value_name = "FILE")]
+    report: Option<String>,
+    /// Serve live prometheus metrics of the run on this address
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    #[command(flatten)]
+    runtime: RuntimeConfig,
 }
 
 #[derive(Clone, Debug, Parser)]
 struct RoundConfig {
+    /// Connections of each iteration. A list (1,2,5) or an inclusive range
+    /// with an optional step (10..100:10)
     #[arg(short = 'c', long = "connections")]
-    #[allow(dead_code)]
-    connections: Option<usize>,
+    connections: Option<round::Ladder>,
+    /// Keep raising connections until throughput stops growing or latency
+    /// passes `--max-latency`, starting from the first of `--connections`
+    #[arg(long = "auto")]
+    auto: bool,
+    /// Factor connections grow by between automatic iterations
+    #[arg(long = "growth", default_value = "2")]
+    growth: f64,
+    /// Throughput gain in percent below which throughput stopped growing
+    #[arg(long = "min-gain", default_value = "5")]
+    min_gain: f64,
+    /// p99 round trip limit in milliseconds for automatic iterations
+    #[arg(long = "max-latency")]
+    max_latency: Option<u64>,
+    /// Upper limit of connections for automatic iterations
+    #[arg(long = "max-connections", default_value = "10000")]
+    max_connections: usize,
     #[arg(short = 'i', long = "in-flight", default_value = "100")]
     in_flight: usize,
     #[arg(short = 'b', long = "broker", default_value = "localhost")]
@@ -98,10 +151,30 @@ struct RoundConfig {
     port: u16,
     #[arg(short = 's', long = "payload-size", default_value = "100")]
     payload_size: usize,
+    /// QoS used for every publish
+    #[arg(long, default_value = "1", value_name = "QoS")]
+    publish_qos: i16,
+    /// QoS used by the subscription of every connection
+    #[arg(long, default_value = "1", value_name = "QoS")]
+    subscribe_qos: i16,
     #[arg(short = 'd', long = "duration", default_value = "10")]
     duration: u64,
+    /// Which connection receives the publishes of a connection
+    #[arg(long, value_enum, default_value = "loopback")]
+    routing: round::Routing,
+    /// Seconds to wait between iterations
+    #[arg(long = "cool-down", default_value = "5", value_name = "SECS")]
+    cool_down: u64,
     #[arg(short = 'n', long = "count")]
     max_publishes: Option<u64>,
+    /// Save the results of every iteration as json
+    #[arg(long, value_name = "FILE")]
+    report: Option<String>,
+    /// Serve live prometheus metrics of the run on this address
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    #[command(flatten)]
+    runtime: RuntimeConfig,
 }
 
 #[derive(Debug, Parser)]
@@ -161,8 +234,125 @@ struct SimulatorConfig {
     #[arg(long, default_value = "false")]
     show_sub_stat: bool,
     /// Type of data to send
-    #[arg(long, value_enum)]
-    data_type: DataType,
+    #[arg(long, value_enum, required_unless_present_any = ["schema", "streams", "fleet"])]
+    data_type: Option<DataType>,
+    /// Json file declaring the fields of a custom device type, used
+    /// instead of `--data-type`
+    #[arg(long, value_name = "FILE")]
+    schema: Option<String>,
+    /// Make every device follow smooth time series (random walks, waves,
+    /// battery discharge, gps tracks) instead of independent random values
+    #[arg(long, default_value = "false")]
+    signals: bool,
+    /// Stream every device publishes over its connection, as a built in
+    /// type or a schema file and messages per second, e.g. `imu:10`. Can be
+    /// repeated. `-n` is the count of the fastest stream
+    #[arg(long = "stream", value_name = "DEVICE:RATE")]
+    streams: Vec<simulator::StreamSpec>,
+    /// Mix of device types over the publishers, e.g. `imu=60%,bms=30%,gps=10%`
+    /// at `--rate-pub`, or a toml file of weighted profiles with their own
+    /// streams
+    #[arg(long, value_name = "MIX|FILE", conflicts_with_all = ["data_type", "schema", "streams"])]
+    fleet: Option<String>,
+    /// Records per jsonarray publish, like devices which buffer readings
+    /// before sending them
+    #[arg(long, default_value = "1")]
+    batch_size: usize,
+    /// Publish a partial batch once its oldest record waited this long
+    #[arg(long, value_name = "MILLIS")]
+    batch_timeout: Option<u64>,
+    /// Wire format of the payloads
+    #[arg(long, value_enum, default_value = "json")]
+    encoding: simulator::Encoding,
+    /// `.proto` file with a message per device type for `--encoding protobuf`,
+    /// named like the device. Built in types have a bundled layout
+    #[arg(long, value_name = "FILE")]
+    proto: Option<String>,
+    /// Make devices subscribe to their actions and report progress on the
+    /// `action_status` stream
+    #[arg(long, default_value = "false")]
+    actions: bool,
+    /// Topic of the actions of a device. `{pub_id}` is replaced by
+    /// publisher_id
+    #[arg(long, default_value = "/tenants/demo/devices/{pub_id}/actions")]
+    actions_topic: String,
+    /// Time devices take to complete an action
+    #[arg(long, value_name = "MILLIS", default_value = "1000")]
+    action_delay: u64,
+    /// Send actions to the devices at this rate (actions/s, 0 => no
+    /// throttle) and measure how long they take to complete
+    #[arg(long, value_name = "RATE", requires = "actions")]
+    send_actions: Option<f64>,
+    /// Number of actions `--send-actions` sends, round robin over the devices
+    #[arg(long, default_value = "100")]
+    action_count: usize,
+    /// Decode the records subscribers receive and check their fields,
+    /// sequences and timestamps
+    #[arg(long, default_value = "false")]
+    validate: bool,
+    /// Make devices go offline this many times per minute on average, like
+    /// fleets on flaky cellular networks
+    #[arg(long, value_name = "RATE", value_parser = positive)]
+    churn: Option<f64>,
+    /// Mean time devices stay offline under `--churn`
+    #[arg(long, value_name = "MILLIS", default_value = "5000")]
+    offline_time: u64,
+    /// Share of the disconnects which drop the connection without a
+    /// disconnect packet, so that the broker publishes the last will
+    #[arg(long, default_value = "0.5", value_parser = ratio)]
+    abrupt_ratio: f64,
+    /// Reconnect with persistent sessions (clean session = false)
+    #[arg(long, default_value = "false")]
+    persistent: bool,
+    /// Last will topic of the devices under `--churn`. `{pub_id}` is
+    /// replaced by publisher_id
+    #[arg(long, default_value = "/tenants/demo/devices/{pub_id}/will")]
+    will_topic: String,
+    /// Records devices keep while offline under `--churn`, sent in a burst
+    /// when they are back. The oldest are dropped past this, 0 holds the
+    /// publishes instead
+    #[arg(long, value_name = "RECORDS", default_value = "10000")]
+    offline_buffer: usize,
+    /// Percentage of devices whose clock is off, as set by `--clock-skew`,
+    /// `--clock-drift`, `--out-of-order` and `--duplicates`
+    #[arg(long, value_name = "PERCENT", default_value = "0", value_parser = percent)]
+    bad_clocks: f64,
+    /// Offset of bad clocks, negative is behind
+    #[arg(
+        long,
+        value_name = "MILLIS",
+        default_value = "0",
+        allow_negative_numbers = true
+    )]
+    clock_skew: i64,
+    /// Drift of bad clocks in parts per million, negative is slow
+    #[arg(
+        long,
+        value_name = "PPM",
+        default_value = "0",
+        allow_negative_numbers = true
+    )]
+    clock_drift: f64,
+    /// Percentage of the records of bad clocks stamped before the previous
+    /// record
+    #[arg(long, value_name = "PERCENT", default_value = "0", value_parser = percent)]
+    out_of_order: f64,
+    /// Percentage of the records of bad clocks which repeat the previous
+    /// record
+    #[arg(long, value_name = "PERCENT", default_value = "0", value_parser = percent)]
+    duplicates: f64,
+    /// Check to run on the results, e.g. "p99_latency<50ms", "loss==0" or
+    /// "throughput>20000". Exits with a non zero code if any check fails
+    #[arg(long = "assert", value_name = "EXPR")]
+    asserts: Vec<Assertion>,
+    /// Save a json report of the run, to compare runs later
+    #[arg(long, value_name = "FILE")]
+    report: Option<String>,
+    /// Serve live prometheus metrics of the run on this address
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    #[command(flatten)]
+    runtime: RuntimeConfig,
 }
 
 #[derive(Debug, Parser)]
@@ -173,6 +363,198 @@ pub struct ConformanceConfig {
     /// Port
     #[arg(short = 'P', long, default_value = "1883")]
     port: u16,
+    #[command(flatten)]
+    threads: Threads,
+    /// Only run tests with this in their name, with this tag or in this
+    /// spec section. Can be repeated
+    #[arg(long = "filter", value_name = "PATTERN")]
+    filters: Vec<String>,
+    /// Don't run tests matching this, like `--filter`. Can be repeated
+    #[arg(long = "skip", value_name = "PATTERN")]
+    skips: Vec<String>,
+    /// List the selected tests instead of running them
+    #[arg(long)]
+    list: bool,
+    /// Seconds a test may take before it fails
+    #[arg(short = 't', long, default_value = "60", value_name = "SECS")]
+    timeout: u64,
+}
+
+#[derive(Debug, Parser)]
+struct ScenarioConfig {
+    /// Path to the scenario toml file
+    #[arg(value_name = "FILE")]
+    file: String,
+    #[command(flatten)]
+    runtime: RuntimeConfig,
+}
+
+#[derive(Debug, Parser)]
+struct CompareConfig {
+    /// Report of the reference run
+    #[arg(value_name = "BASELINE")]
+    baseline: String,
+    /// Report of the run to check
+    #[arg(value_name = "CURRENT")]
+    current: String,
+    /// Allowed change in percent before a metric counts as a regression
+    #[arg(short = 't', long, default_value = "5")]
+    tolerance: f64,
+}
+
+#[derive(Debug, Parser)]
+pub struct RecordConfig {
+    /// Broker's address
+    #[arg(short = 'S', long, default_value = "localhost", value_name = "URL")]
+    server: String,
+    /// Port
+    #[arg(short = 'P', long, default_value = "1883")]
+    port: u16,
+    /// Topic filter to record, can be repeated
+    #[arg(
+        short = 'f',
+        long = "filter",
+        default_value = "#",
+        value_name = "FILTER"
+    )]
+    filters: Vec<String>,
+    /// Log file to write
+    #[arg(short = 'o', long, value_name = "FILE")]
+    output: String,
+    /// QoS of the subscriptions
+    #[arg(short = 'q', long, default_value = "1", value_name = "QoS", value_parser = clap::value_parser!(u8).range(0..=2))]
+    qos: u8,
+    /// Stop after this many messages
+    #[arg(short = 'n', long, value_name = "NUM")]
+    count: Option<usize>,
+    /// Stop after this many seconds, ctrl-c stops otherwise
+    #[arg(short = 'd', long, value_name = "SECS")]
+    duration: Option<u64>,
+    /// Client id of the recorder
+    #[arg(long, default_value = "mqttwrk-recorder")]
+    id: String,
+    /// Largest packet in bytes the recorder accepts
+    #[arg(long, default_value = "1048576", value_name = "BYTES")]
+    max_packet_size: usize,
+}
+
+#[derive(Debug, Parser)]
+pub struct ReplayConfig {
+    /// Log file written by `record`
+    #[arg(value_name = "FILE")]
+    file: String,
+    /// Broker's address
+    #[arg(short = 'S', long, default_value = "localhost", value_name = "URL")]
+    server: String,
+    /// Port
+    #[arg(short = 'P', long, default_value = "1883")]
+    port: u16,
+    /// Pace of the replay, a factor of the recorded one like 1, 10x, 0.5x,
+    /// or max
+    #[arg(long, default_value = "1")]
+    speed: replay::Speed,
+    /// No. of clients the messages are spread across, by topic
+    #[arg(short = 'c', long, default_value = "1", value_name = "NUM")]
+    clients: usize,
+    /// Client id, suffixed with the client's index when there are several
+    #[arg(long, default_value = "mqttwrk-replay")]
+    id: String,
+    /// QoS of every publish instead of the recorded ones
+    #[arg(short = 'q', long, value_name = "QoS", value_parser = clap::value_parser!(u8).range(0..=2))]
+    qos: Option<u8>,
+    /// Max Inflight Messages of each client
+    #[arg(short = 'i', long, default_value = "100")]
+    max_inflight: u16,
+    /// Largest packet in bytes the clients send
+    #[arg(long, default_value = "1048576", value_name = "BYTES")]
+    max_packet_size: usize,
+    #[command(flatten)]
+    threads: Threads,
+}
+
+#[derive(Debug, Parser)]
+pub struct VerifyConfig {
+    /// ClickHouse http interface of the sink
+    #[arg(
+        short = 'u',
+        long,
+        default_value = "http://localhost:8123",
+        value_name = "URL"
+    )]
+    url: String,
+    /// ClickHouse user
+    #[arg(long, default_value = "default")]
+    user: String,
+    /// ClickHouse password
+    #[arg(long)]
+    password: Option<String>,
+    /// Table the records are stored in
+    #[arg(short = 't', long, default_value = "demo.imu")]
+    table: String,
+    /// Column of the device ids
+    #[arg(long, default_value = "id")]
+    id_column: String,
+    /// Column of the record sequences
+    #[arg(long, default_value = "sequence")]
+    sequence_column: String,
+    /// No. of publishers of the run
+    #[arg(short = 'p', long, default_value = "1", value_name = "NUM")]
+    publishers: usize,
+    /// No. of records per publisher of the run, of its fastest stream
+    #[arg(short = 'n', long, default_value = "100", value_name = "NUM")]
+    count: usize,
+    /// Streams of the run, as its `--stream`s
+    #[arg(long = "stream", value_name = "DEVICE:RATE")]
+    streams: Vec<simulator::StreamSpec>,
+    /// Fleet of the run, as its `--fleet`
+    #[arg(long, value_name = "MIX|FILE", conflicts_with = "streams")]
+    fleet: Option<String>,
+    /// Device whose records the table holds, for runs with several devices
+    #[arg(long)]
+    device: Option<String>,
+    /// Seconds to wait for the sink to have every record
+    #[arg(long, default_value = "60", value_name = "SECS")]
+    timeout: u64,
+    /// Seconds between queries while records are missing
+    #[arg(long, default_value = "2", value_name = "SECS")]
+    interval: u64,
+}
+
+#[derive(Debug, Parser)]
+pub struct CanaryConfig {
+    /// Broker's address
+    #[arg(short = 'S', long, default_value = "localhost", value_name = "URL")]
+    server: String,
+    /// Port
+    #[arg(short = 'P', long, default_value = "1883")]
+    port: u16,
+    /// Client id prefix of the probe's publisher and subscriber
+    #[arg(long, default_value = "mqttwrk-canary")]
+    id: String,
+    /// Topic the probe messages go through
+    #[arg(long, default_value = "mqttwrk/canary")]
+    topic: String,
+    /// No. of messages of each probe
+    #[arg(short = 'n', long, default_value = "10", value_name = "NUM")]
+    count: usize,
+    /// QoS of the probe messages
+    #[arg(short = 'q', long, default_value = "1", value_name = "QoS", value_parser = clap::value_parser!(u8).range(0..=2))]
+    qos: u8,
+    /// Seconds between the start of probes
+    #[arg(short = 'i', long, default_value = "60", value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
+    /// Seconds a probe may take before it fails
+    #[arg(short = 't', long, default_value = "10", value_name = "SECS")]
+    timeout: u64,
+    /// Fail probes with a message slower than this many milliseconds
+    #[arg(long, value_name = "MILLIS")]
+    max_latency: Option<u64>,
+    /// Serve the status of the last probe on this address, at /health
+    #[arg(long, value_name = "ADDR")]
+    health_addr: Option<SocketAddr>,
+    /// Url to post a json alert to when probes start failing or recover
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -198,19 +580,217 @@ fn main() {
 
     match config {
         Config::Bench(config) => {
-            bench::start(config);
+            let runtime = Runtime::new(&config.runtime);
+            let asserts = config.asserts.clone();
+            let path = config.report.clone();
+            let report = runtime.block_on(bench::start(config, runtime.shards()));
+            save_report(&report, path);
+            if !assertion::check(&asserts, &report) {
+                std::process::exit(1);
+            }
         }
         Config::Simulator(config) => {
-            simulator::start(config);
+            let fleet = match simulator::fleet(&config) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Couldn't load devices: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let runtime = Runtime::new(&config.runtime);
+            let asserts = config.asserts.clone();
+            let path = config.report.clone();
+            let report = runtime.block_on(simulator::start(*config, fleet, runtime.shards()));
+            save_report(&report, path);
+            if !assertion::check(&asserts, &report) {
+                std::process::exit(1);
+            }
         }
         Config::Round(config) => {
-            round::start(config).unwrap();
+            let runtime = Runtime::new(&config.runtime);
+            runtime
+                .block_on(round::start(config, runtime.shards()))
+                .unwrap();
         }
         Config::Conformance(config) => {
-            conformance::start(config);
+            let runtime = Runtime::new(&config.threads.clone().into());
+            if !runtime.block_on(conformance::start(config)) {
+                std::process::exit(1);
+            }
+        }
+        Config::Run(config) => {
+            let scenario = match scenario::Scenario::load(&config.file) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Couldn't load {}: {}", config.file, e);
+                    std::process::exit(1);
+                }
+            };
+            let runtime = Runtime::new(&config.runtime);
+            runtime.block_on(scenario::start(scenario, runtime.shards()));
+        }
+        Config::Compare(config) => {
+            if !compare::start(config) {
+                std::process::exit(1);
+            }
+        }
+        Config::Record(config) => {
+            let runtime = Runtime::new(&Threads { count: Some(1) }.into());
+            if let Err(e) = runtime.block_on(replay::record(config)) {
+                eprintln!("Couldn't record: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Config::Replay(config) => {
+            let runtime = Runtime::new(&config.threads.clone().into());
+            if let Err(e) = runtime.block_on(replay::replay(config)) {
+                eprintln!("Couldn't replay: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Config::Verify(config) => {
+            let runtime = Runtime::new(&Threads { count: Some(1) }.into());
+            if !runtime.block_on(verify::start(config)) {
+                std::process::exit(1);
+            }
+        }
+        Config::Canary(config) => {
+            let runtime = Runtime::new(&Threads { count: Some(1) }.into());
+            runtime.block_on(canary::start(config));
         }
         Config::Test => {
             test::start();
         }
     }
 }
+
+fn save_report(report: &report::Report, path: Option<String>) {
+    if let Some(path) = path {
+        if let Err(e) = report.save(&path) {
+            error!("Couldn't save report to {}: {}", path, e);
+        }
+    }
+}
+
+/// Finite number above 0
+fn positive(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        Ok(_) => Err("should be a positive number".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Share from 0 to 1
+fn ratio(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+        Ok(_) => Err("should be from 0 to 1".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Percentage from 0 to 100
+fn percent(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(v) if (0.0..=100.0).contains(&v) => Ok(v),
+        Ok(_) => Err("should be from 0 to 100".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn arguments_are_consistent() {
+        Config::command().debug_assert();
+    }
+
+    #[test]
+    fn runtime_options_keep_the_about_of_commands() {
+        let command = Config::command();
+        let about = |name| {
+            let subcommand = command.find_subcommand(name).unwrap();
+            subcommand.get_about().map(|v| v.to_string())
+        };
+        assert_eq!(about("bench"), None);
+        assert_eq!(about("simulator"), None);
+        assert_eq!(
+            about("replay").unwrap(),
+            "Publish a recorded log again, as it was timed or faster"
+        );
+    }
+
+    #[test]
+    fn runtimes_need_a_thread() {
+        for command in ["bench", "conformance", "replay log", "run scenario.toml"] {
+            let args = format!("mqttwrk {command} --threads 0");
+            assert!(Config::try_parse_from(args.split_whitespace()).is_err());
+        }
+
+        let args = ["mqttwrk", "replay", "log", "--threads", "2"];
+        match Config::try_parse_from(args).unwrap() {
+            Config::Replay(config) => assert_eq!(config.threads.count, Some(2)),
+            config => panic!("expected a replay, got {:?}", config),
+        }
+    }
+
+    #[test]
+    fn clock_percentages_are_finite() {
+        for flag in ["--bad-clocks", "--out-of-order", "--duplicates"] {
+            for value in ["NaN", "inf", "-1", "101"] {
+                let args = format!("mqttwrk simulator --data-type gps {flag} {value}");
+                assert!(Config::try_parse_from(args.split_whitespace()).is_err());
+            }
+        }
+
+        let args = "mqttwrk simulator --data-type gps --bad-clocks 100 --duplicates 2.5";
+        match Config::try_parse_from(args.split_whitespace()).unwrap() {
+            Config::Simulator(config) => {
+                assert_eq!((config.bad_clocks, config.duplicates), (100.0, 2.5))
+            }
+            config => panic!("expected a simulator, got {:?}", config),
+        }
+    }
+
+    #[test]
+    fn canaries_need_a_qos_and_an_interval() {
+        for arg in ["-q 3", "-i 0"] {
+            let args = format!("mqttwrk canary {arg}");
+            assert!(Config::try_parse_from(args.split_whitespace()).is_err());
+        }
+
+        let args = "mqttwrk canary -q 2 -i 1";
+        match Config::try_parse_from(args.split_whitespace()).unwrap() {
+            Config::Canary(config) => assert_eq!((config.qos, config.interval), (2, 1)),
+            config => panic!("expected a canary, got {:?}", config),
+        }
+    }
+
+    #[test]
+    fn churn_needs_a_rate_and_a_ratio() {
+        for arg in ["--churn 0", "--churn -1", "--churn inf", "--churn NaN"] {
+            let args = format!("mqttwrk simulator --data-type gps {arg}");
+            assert!(Config::try_parse_from(args.split_whitespace()).is_err());
+        }
+
+        for arg in [
+            "--abrupt-ratio 1.5",
+            "--abrupt-ratio -0.1",
+            "--abrupt-ratio NaN",
+        ] {
+            let args = format!("mqttwrk simulator --data-type gps --churn 1 {arg}");
+            assert!(Config::try_parse_from(args.split_whitespace()).is_err());
+        }
+
+        let args = "mqttwrk simulator --data-type gps --churn 1e-300 --abrupt-ratio 1";
+        match Config::try_parse_from(args.split_whitespace()).unwrap() {
+            Config::Simulator(config) => assert_eq!(config.abrupt_ratio, 1.0),
+            config => panic!("expected a simulator, got {:?}", config),
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::http::{self, Response};
+
+/// Live counters of the current run, updated by every client as events
+/// happen and served in prometheus exposition format
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Upper bounds of the latency histogram buckets in milliseconds
+const LATENCY_BUCKETS: [u64; 14] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 30000,
+];
+
+#[derive(Default)]
+pub struct Metrics {
+    published: AtomicU64,
+    acked: AtomicU64,
+    received: AtomicU64,
+    reconnects: AtomicU64,
+    connected: AtomicI64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_sum: AtomicU64,
+    latency_count: AtomicU64,
+}
+
+impl Metrics {
+    pub fn published(&self) {
+        self.published.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn acked(&self) {
+        self.acked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Publish which took `latency` milliseconds to be acked
+    pub fn latency(&self, latency: u64) {
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| latency <= *le) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_sum.fetch_add(latency, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self) {
+        self.connected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.connected.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client as connected until the connection is dropped, for
+    /// tasks which can end anywhere
+    pub fn connection(&'static self) -> Connection {
+        self.connected();
+        Connection(self)
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("published", "Publishes sent", &self.published),
+            ("acked", "Publishes acked by the broker", &self.acked),
+            (
+                "received",
+                "Publishes received by subscribers",
+                &self.received,
+            ),
+            (
+                "reconnects",
+                "Connection errors of clients",
+                &self.reconnects,
+            ),
+        ];
+
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP mqttwrk_{name}_total {help}");
+            let _ = writeln!(out, "# TYPE mqttwrk_{name}_total counter");
+            let _ = writeln!(
+                out,
+                "mqttwrk_{name}_total {}",
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "# HELP mqttwrk_connected_clients Clients connected");
+        let _ = writeln!(out, "# TYPE mqttwrk_connected_clients gauge");
+        let _ = writeln!(
+            out,
+            "mqttwrk_connected_clients {}",
+            self.connected.load(Ordering::Relaxed)
+        );
+
+        let name = "mqttwrk_publish_latency_ms";
+        let _ = writeln!(out, "# HELP {name} Publish to ack latency");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(
+            out,
+            "{name}_sum {}",
+            self.latency_sum.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "{name}_count {count}");
+
+        out
+    }
+}
+
+/// Connected client of `Metrics::connection`
+pub struct Connection(&'static Metrics);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.disconnected();
+    }
+}
+
+/// Serve `METRICS` on `addr`. Errors are logged as a run shouldn't fail
+/// just because its metrics can't be scraped
+pub async fn start(addr: Option<SocketAddr>) {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return,
+    };
+
+    let handler = |path: &str| match path {
+        "/metrics" => Response::ok("text/plain; version=0.0.4", METRICS.render()),
+        _ => Response::not_found(),
+    };
+
+    match http::serve(addr, handler).await {
+        Ok(()) => info!("Serving metrics on http://{}/metrics", addr),
+        Err(e) => error!("Couldn't serve metrics on {}: {}", addr, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Samples of an exposition by name and labels, checking that every
+    /// sample follows the help and type of its metric
+    fn parse(exposition: &str) -> HashMap<String, f64> {
+        let mut samples = HashMap::new();
+        let mut family = None;
+        for line in exposition.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                family = help.split_whitespace().next();
+                continue;
+            }
+
+            if let Some(kind) = line.strip_prefix("# TYPE ") {
+                let mut kind = kind.split_whitespace();
+                assert_eq!(kind.next(), family, "{}", line);
+                assert!(matches!(
+                    kind.next(),
+                    Some("counter" | "gauge" | "histogram")
+                ));
+                continue;
+            }
+
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            assert!(name.starts_with(family.unwrap()), "{}", line);
+            samples.insert(name.to_owned(), value.parse().unwrap());
+        }
+
+        samples
+    }
+
+    #[test]
+    fn expositions_parse() {
+        let metrics = Metrics::default();
+        for _ in 0..3 {
+            metrics.published();
+        }
+        metrics.acked();
+        metrics.received();
+        metrics.connected();
+        metrics.connected();
+        metrics.disconnected();
+        for latency in [1, 3, 3, 40, 60_000] {
+            metrics.latency(latency);
+        }
+
+        let samples = parse(&metrics.render());
+        assert_eq!(samples["mqttwrk_published_total"], 3.0);
+        assert_eq!(samples["mqttwrk_acked_total"], 1.0);
+        assert_eq!(samples["mqttwrk_received_total"], 1.0);
+        assert_eq!(samples["mqttwrk_reconnects_total"], 0.0);
+        assert_eq!(samples["mqttwrk_connected_clients"], 1.0);
+
+        // buckets are cumulative
+        let bucket =
+            |le: &str| samples[&format!("mqttwrk_publish_latency_ms_bucket{{le=\"{le}\"}}")];
+        assert_eq!(bucket("1"), 1.0);
+        assert_eq!(bucket("2"), 1.0);
+        assert_eq!(bucket("5"), 3.0);
+        assert_eq!(bucket("50"), 4.0);
+        assert_eq!(bucket("30000"), 4.0);
+        assert_eq!(bucket("+Inf"), 5.0);
+        assert_eq!(samples["mqttwrk_publish_latency_ms_count"], 5.0);
+        assert_eq!(samples["mqttwrk_publish_latency_ms_sum"], 60_047.0);
+    }
+
+    #[test]
+    fn connections_end_when_dropped() {
+        let metrics: &'static Metrics = Box::leak(Box::default());
+        let connection = metrics.connection();
+        let task = metrics.connection();
+        assert_eq!(metrics.connected.load(Ordering::Relaxed), 2);
+
+        drop(task);
+        assert_eq!(metrics.connected.load(Ordering::Relaxed), 1);
+        drop(connection);
+        assert_eq!(metrics.connected.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_scraped() {
+        // a free port for the server
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        start(Some(addr)).await;
+
+        let (status, body) = http::get(addr, "/metrics").await.unwrap();
+        assert_eq!(status, 200);
+        let samples = parse(&body);
+        assert!(samples.contains_key("mqttwrk_published_total"));
+        assert!(samples.contains_key("mqttwrk_publish_latency_ms_bucket{le=\"+Inf\"}"));
+
+        assert_eq!(http::get(addr, "/").await.unwrap().0, 404);
+    }
+}
@@ -0,0 +1,313 @@
+//! Recording of real traffic and its replay. `mqttwrk record` stores the
+//! messages of a topic filter in a compact log and `mqttwrk replay`
+//! publishes them again, as they were timed or faster
+//!
+//! A log starts with `MQTTWRK1`, followed by an entry per message
+//!
+//! - microseconds since the previous message, as a varint
+//! - flags, with the qos in the lower two bits and retain in the third
+//! - topic id as a varint. Topics get ids in the order they first appear and
+//!   a new id is followed by the topic, as a varint length and its bytes
+//! - payload as a varint length and its bytes
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    time::Duration,
+};
+
+use rumqttc::QoS;
+
+mod play;
+mod record;
+
+pub use play::{start as replay, Speed};
+pub use record::start as record;
+
+const MAGIC: &[u8; 8] = b"MQTTWRK1";
+
+/// Longest topic or payload mqtt allows, anything longer is corrupt
+const MAX_LEN: usize = 268_435_455;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error = {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Not a mqttwrk log")]
+    NotALog,
+    #[error("Corrupt log = {0}")]
+    Corrupt(&'static str),
+    // boxed to keep the results of log reads small
+    #[error("Connection error = {0:?}")]
+    Connection(Box<rumqttc::ConnectionError>),
+    #[error("Wrong packet = {0:?}")]
+    WrongPacket(Box<rumqttc::Incoming>),
+    #[error("Client error = {0:?}")]
+    Client(#[from] rumqttc::ClientError),
+}
+
+impl From<rumqttc::ConnectionError> for Error {
+    fn from(e: rumqttc::ConnectionError) -> Self {
+        Error::Connection(Box::new(e))
+    }
+}
+
+/// Message of a log
+#[derive(Debug)]
+pub struct Entry {
+    /// Time since the first message
+    pub offset: Duration,
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+}
+
+/// Appends messages to a log
+pub struct Writer<W: Write> {
+    inner: W,
+    topics: HashMap<String, u64>,
+    /// Offset of the previous message
+    last: Duration,
+}
+
+impl Writer<BufWriter<File>> {
+    pub fn create(path: &str) -> Result<Self, Error> {
+        Writer::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut inner: W) -> Result<Self, Error> {
+        inner.write_all(MAGIC)?;
+        Ok(Writer {
+            inner,
+            topics: HashMap::new(),
+            last: Duration::ZERO,
+        })
+    }
+
+    /// Logs a message received `offset` after the first one. Returns the
+    /// bytes it took
+    pub fn write(
+        &mut self,
+        offset: Duration,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> io::Result<usize> {
+        let mut entry = Vec::with_capacity(payload.len() + 16);
+        let delta = offset.saturating_sub(self.last);
+        self.last = self.last.max(offset);
+        varint(&mut entry, delta.as_micros() as u64);
+        entry.push(qos as u8 | (retain as u8) << 2);
+
+        let next = self.topics.len() as u64;
+        match self.topics.get(topic) {
+            Some(id) => varint(&mut entry, *id),
+            None => {
+                self.topics.insert(topic.to_owned(), next);
+                varint(&mut entry, next);
+                varint(&mut entry, topic.len() as u64);
+                entry.extend_from_slice(topic.as_bytes());
+            }
+        }
+
+        varint(&mut entry, payload.len() as u64);
+        entry.extend_from_slice(payload);
+        self.inner.write_all(&entry)?;
+        Ok(entry.len())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Messages of a log, in the order they were recorded
+pub struct Reader<R: BufRead> {
+    inner: R,
+    topics: Vec<String>,
+    offset: Duration,
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Reader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(mut inner: R) -> Result<Self, Error> {
+        let mut magic = [0; 8];
+        match inner.read_exact(&mut magic) {
+            Ok(()) if &magic == MAGIC => {}
+            Ok(()) => return Err(Error::NotALog),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(Error::NotALog),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Reader {
+            inner,
+            topics: Vec::new(),
+            offset: Duration::ZERO,
+        })
+    }
+
+    fn entry(&mut self) -> Result<Option<Entry>, Error> {
+        // logs end between entries
+        if self.inner.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let delta = read_varint(&mut self.inner)?;
+        self.offset += Duration::from_micros(delta);
+        let flags = read_bytes(&mut self.inner, 1)?[0];
+        let qos = match flags & 0b11 {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            _ => return Err(Error::Corrupt("invalid qos")),
+        };
+
+        let id = read_varint(&mut self.inner)? as usize;
+        if id == self.topics.len() {
+            let len = read_varint(&mut self.inner)? as usize;
+            let topic = String::from_utf8(read_bytes(&mut self.inner, len)?)
+                .map_err(|_| Error::Corrupt("topic isn't utf-8"))?;
+            self.topics.push(topic);
+        }
+
+        let topic = match self.topics.get(id) {
+            Some(v) => v.clone(),
+            None => return Err(Error::Corrupt("unknown topic id")),
+        };
+        let len = read_varint(&mut self.inner)? as usize;
+        let payload = read_bytes(&mut self.inner, len)?;
+
+        Ok(Some(Entry {
+            offset: self.offset,
+            topic,
+            qos,
+            retain: flags & 0b100 != 0,
+            payload,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entry().transpose()
+    }
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buf.push(value as u8);
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = read_bytes(reader, 1)?[0];
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::Corrupt("varint too long"))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>, Error> {
+    if len > MAX_LEN {
+        return Err(Error::Corrupt("length out of range"));
+    }
+
+    let mut bytes = vec![0; len];
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => Ok(bytes),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(Error::Corrupt("truncated")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Log of a few messages, two of them on the same topic
+    fn log() -> Vec<u8> {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        let messages = [
+            (0, "a/b", QoS::AtMostOnce, false, &b"one"[..]),
+            (1500, "c", QoS::ExactlyOnce, true, &b""[..]),
+            (1500, "a/b", QoS::AtLeastOnce, false, &[0xff; 300][..]),
+        ];
+        for (offset, topic, qos, retain, payload) in messages {
+            let offset = Duration::from_micros(offset);
+            writer.write(offset, topic, qos, retain, payload).unwrap();
+        }
+
+        writer.flush().unwrap();
+        writer.inner
+    }
+
+    fn read(log: &[u8]) -> Result<Vec<Entry>, Error> {
+        Reader::new(log)?.collect()
+    }
+
+    #[test]
+    fn logs_round_trip() {
+        let entries = read(&log()).unwrap();
+        assert_eq!(entries.len(), 3);
+
+        let offsets: Vec<u128> = entries.iter().map(|v| v.offset.as_micros()).collect();
+        assert_eq!(offsets, [0, 1500, 1500]);
+        let topics: Vec<&str> = entries.iter().map(|v| v.topic.as_str()).collect();
+        assert_eq!(topics, ["a/b", "c", "a/b"]);
+        let qos: Vec<QoS> = entries.iter().map(|v| v.qos).collect();
+        assert_eq!(qos, [QoS::AtMostOnce, QoS::ExactlyOnce, QoS::AtLeastOnce]);
+        assert!(entries[1].retain && !entries[0].retain);
+        assert_eq!(entries[0].payload, b"one");
+        assert!(entries[1].payload.is_empty());
+        assert_eq!(entries[2].payload, [0xff; 300]);
+
+        // just the magic
+        assert!(read(MAGIC).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_logs_are_corrupt() {
+        let log = log();
+        let entries = Reader::new(&log[..log.len() - 1]).unwrap();
+        let entries: Vec<Result<Entry, Error>> = entries.collect();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[..2].iter().all(Result::is_ok));
+        assert!(matches!(entries[2], Err(Error::Corrupt("truncated"))));
+
+        // a lone byte of the next entry
+        let mut log = MAGIC.to_vec();
+        log.push(0x80);
+        assert!(matches!(read(&log), Err(Error::Corrupt("truncated"))));
+    }
+
+    #[test]
+    fn other_files_are_not_logs() {
+        assert!(matches!(read(b"MQTTWRK2"), Err(Error::NotALog)));
+        assert!(matches!(read(b"MQTT"), Err(Error::NotALog)));
+        assert!(matches!(read(b""), Err(Error::NotALog)));
+
+        let mut log = log();
+        log[0] = b'X';
+        assert!(matches!(read(&log), Err(Error::NotALog)));
+    }
+}
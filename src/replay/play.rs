@@ -0,0 +1,236 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    time::Duration,
+};
+
+use rumqttc::{AsyncClient, Incoming, MqttOptions, QoS};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use super::{Entry, Error, Reader};
+use crate::{
+    common::{self, Latencies, WrappedEventLoop},
+    ReplayConfig,
+};
+
+/// Messages queued for a client before the log reader waits for it
+const QUEUE: usize = 1000;
+
+/// Pace of a replay, relative to the recording
+#[derive(Debug, Clone, Copy)]
+pub enum Speed {
+    Times(f64),
+    /// As fast as the broker takes them
+    Max,
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "max" {
+            return Ok(Speed::Max);
+        }
+
+        match s.trim_end_matches('x').parse::<f64>() {
+            Ok(v) if v.is_finite() && v > 0.0 => Ok(Speed::Times(v)),
+            _ => Err(format!("expected max or a factor like 10x but got `{s}`")),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    published: u64,
+    acked: u64,
+    /// Milliseconds publishes were sent after their time in the replay,
+    /// unless replaying at max speed
+    lag: Latencies,
+}
+
+/// Publishes a log again. Messages of a topic always go through the same
+/// client, so their order survives spreading them over `--clients`
+pub async fn start(config: ReplayConfig) -> Result<(), Error> {
+    let reader = Reader::open(&config.file)?;
+    let mut queues = Vec::with_capacity(config.clients);
+    let mut handles = Vec::with_capacity(config.clients);
+    for i in 0..config.clients {
+        let id = match config.clients {
+            1 => config.id.clone(),
+            _ => format!("{}-{:05}", config.id, i),
+        };
+
+        let (client, eventloop) = connect(&config, &id).await?;
+        let (tx, rx) = mpsc::channel(QUEUE);
+        queues.push(tx);
+        handles.push(tokio::spawn(run(id, client, eventloop, rx, config.qos)));
+    }
+
+    let start = Instant::now();
+    for entry in reader {
+        let entry = match entry {
+            Ok(v) => v,
+            // keep what's been replayed of a log cut short
+            Err(e) => {
+                error!("Stopped reading {}: {}", config.file, e);
+                break;
+            }
+        };
+
+        let due = match config.speed {
+            Speed::Times(v) => Some(start + entry.offset.div_f64(v)),
+            Speed::Max => None,
+        };
+        if let Some(due) = due {
+            time::sleep_until(due).await;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        entry.topic.hash(&mut hasher);
+        let queue = &queues[hasher.finish() as usize % queues.len()];
+        if queue.send((entry, due)).await.is_err() {
+            break;
+        }
+    }
+
+    drop(queues);
+    let mut stats = Stats::default();
+    for handle in handles {
+        let client = handle.await.unwrap()?;
+        stats.published += client.published;
+        stats.acked += client.acked;
+        stats.lag.merge(&client.lag);
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "Replayed {} messages with {} clients in {:.1}s, {:.0} messages/s, {} acked",
+        stats.published,
+        config.clients,
+        elapsed,
+        stats.published as f64 / elapsed,
+        stats.acked
+    );
+    if !stats.lag.0.is_empty() {
+        println!(
+            "Lag: p50 = {}ms, p99 = {}ms, max = {}ms",
+            stats.lag.percentile(50.0),
+            stats.lag.percentile(99.0),
+            stats.lag.0.max()
+        );
+    }
+
+    Ok(())
+}
+
+async fn connect(
+    config: &ReplayConfig,
+    id: &str,
+) -> Result<(AsyncClient, WrappedEventLoop), Error> {
+    let mut options = MqttOptions::new(id, &config.server, config.port);
+    options.set_keep_alive(Duration::from_secs(10));
+    options.set_max_packet_size(config.max_packet_size, config.max_packet_size);
+    options.set_inflight(config.max_inflight);
+
+    let (client, mut eventloop) = common::get_client(options);
+    match eventloop.poll().await? {
+        Incoming::ConnAck(_) => Ok((client, eventloop)),
+        packet => Err(Error::WrongPacket(Box::new(packet))),
+    }
+}
+
+/// Publishes the messages of a client and waits for their acks
+async fn run(
+    id: String,
+    client: AsyncClient,
+    eventloop: WrappedEventLoop,
+    mut queue: mpsc::Receiver<(Entry, Option<Instant>)>,
+    qos: Option<u8>,
+) -> Result<Stats, Error> {
+    let (expected_tx, expected_rx) = oneshot::channel();
+    let acks: JoinHandle<Result<u64, Error>> =
+        tokio::spawn(acks(id, client.clone(), eventloop, expected_rx));
+
+    let mut stats = Stats::default();
+    let mut expected = 0;
+    while let Some((entry, due)) = queue.recv().await {
+        let qos = match qos {
+            Some(0) => QoS::AtMostOnce,
+            Some(1) => QoS::AtLeastOnce,
+            Some(_) => QoS::ExactlyOnce,
+            None => entry.qos,
+        };
+
+        if let Some(due) = due {
+            stats.lag.record(due.elapsed().as_millis() as u64);
+        }
+        client
+            .publish(entry.topic, qos, entry.retain, entry.payload)
+            .await?;
+        stats.published += 1;
+        if qos != QoS::AtMostOnce {
+            expected += 1;
+        }
+    }
+
+    let _ = expected_tx.send(expected);
+    stats.acked = acks.await.unwrap()?;
+    Ok(stats)
+}
+
+/// Drives the eventloop of a client, counting acks until it got the
+/// `expected` ones. Disconnects after, so that qos 0 publishes are flushed
+async fn acks(
+    id: String,
+    client: AsyncClient,
+    mut eventloop: WrappedEventLoop,
+    mut expected: oneshot::Receiver<u64>,
+) -> Result<u64, Error> {
+    let mut required = None;
+    let mut acked = 0;
+    loop {
+        if required.is_some_and(|v| acked >= v) {
+            break;
+        }
+
+        tokio::select! {
+            v = &mut expected, if required.is_none() => {
+                required = Some(v.unwrap_or(0));
+            }
+            incoming = eventloop.poll() => match incoming {
+                Ok(Incoming::PubAck(_)) | Ok(Incoming::PubComp(_)) => acked += 1,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Id = {}, Connection error = {:?}", id, e);
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    client.disconnect().await?;
+    // ends once the broker closes the connection
+    while eventloop.poll().await.is_ok() {}
+    Ok(acked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speeds_parse() {
+        assert!(matches!("max".parse(), Ok(Speed::Max)));
+        assert!(matches!("10x".parse(), Ok(Speed::Times(v)) if v == 10.0));
+        assert!(matches!("0.5".parse(), Ok(Speed::Times(v)) if v == 0.5));
+
+        for speed in ["0x", "-2x", "fast", "infx", "NaN", "", "x"] {
+            assert!(speed.parse::<Speed>().is_err(), "{}", speed);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use rumqttc::{Incoming, MqttOptions, QoS, SubscribeFilter};
+use tokio::time;
+
+use super::{Error, Writer};
+use crate::{common, RecordConfig};
+
+/// Records the messages of the filters until `--count` messages, the
+/// `--duration` or ctrl-c
+pub async fn start(config: RecordConfig) -> Result<(), Error> {
+    let mut writer = Writer::create(&config.output)?;
+    let mut options = MqttOptions::new(&config.id, &config.server, config.port);
+    options.set_keep_alive(Duration::from_secs(10));
+    options.set_max_packet_size(config.max_packet_size, config.max_packet_size);
+    let qos = match config.qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    };
+
+    let (client, mut eventloop) = common::get_client(options);
+    let deadline = config
+        .duration
+        .map(|v| time::Instant::now() + Duration::from_secs(v));
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    // offsets are relative to the first message
+    let mut first: Option<Instant> = None;
+    let mut messages = 0;
+    let mut bytes = 0;
+    loop {
+        let incoming = tokio::select! {
+            _ = &mut ctrl_c => break,
+            _ = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)), if deadline.is_some() => break,
+            incoming = eventloop.poll() => incoming,
+        };
+
+        let publish = match incoming {
+            Ok(Incoming::Publish(v)) => v,
+            // subscriptions don't survive reconnects of clean sessions. A
+            // single request, as the eventloop isn't polled while it's queued
+            Ok(Incoming::ConnAck(_)) => {
+                let filters = config
+                    .filters
+                    .iter()
+                    .map(|v| SubscribeFilter::new(v.clone(), qos));
+                client.subscribe_many(filters).await?;
+                continue;
+            }
+            Ok(_) => continue,
+            Err(e) if first.is_some() => {
+                error!("Id = {}, Connection error = {:?}", config.id, e);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            // nothing to keep recording
+            Err(e) => return Err(e.into()),
+        };
+
+        let offset = first.get_or_insert_with(Instant::now).elapsed();
+        bytes += writer.write(
+            offset,
+            &publish.topic,
+            publish.qos,
+            publish.retain,
+            &publish.payload,
+        )?;
+        messages += 1;
+        if config.count.is_some_and(|v| messages >= v) {
+            break;
+        }
+    }
+
+    writer.flush()?;
+    let elapsed = first.map_or(Duration::ZERO, |v| v.elapsed());
+    println!(
+        "Recorded {messages} messages in {:.1}s to {}, {bytes} bytes",
+        elapsed.as_secs_f64(),
+        config.output
+    );
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use std::{fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::{PubStats, SubStats};
+
+/// Summary of a finished run which assertions are checked against
+///
+/// The simulator counts records instead of publishes in `sent`, `received`
+/// and `expected`, which are the same unless records are batched
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Report {
+    /// Publishes sent (acked for QoS 1 and 2) by all publishers
+    pub sent: u64,
+    /// Publishes received by all subscribers
+    pub received: u64,
+    /// Publishes subscribers should have received
+    pub expected: u64,
+    /// Total publish throughput in messages/s
+    pub throughput: f64,
+    /// Total subscriber throughput in messages/s
+    pub sub_throughput: f64,
+    /// Publish to ack latency percentiles in milliseconds
+    pub p50_latency: u64,
+    pub p90_latency: u64,
+    pub p99_latency: u64,
+    pub max_latency: u64,
+    pub reconnects: u64,
+    /// Payload checks of the subscribers, when enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validation: Option<Validation>,
+    /// Offline buffers of churning devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffering: Option<Buffering>,
+}
+
+/// Records devices kept while offline and sent in bursts when back
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Buffering {
+    /// Records generated while offline
+    pub buffered: u64,
+    /// Records dropped from full buffers
+    pub dropped: u64,
+    /// Backlogs sent after reconnects
+    pub bursts: u64,
+    /// Records of the largest backlog
+    pub max_burst: u64,
+    /// Time to send backlogs in milliseconds
+    pub p50_drain: u64,
+    pub p99_drain: u64,
+    pub max_drain: u64,
+}
+
+/// Content checks of the records subscribers received
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Validation {
+    /// Records which were checked
+    pub records: u64,
+    /// Payloads which didn't decode
+    pub undecodable: u64,
+    /// Fields missing from the records
+    pub missing_fields: u64,
+    /// Fields of the wrong type or out of their range
+    pub invalid_values: u64,
+    /// Sequences skipped by devices
+    pub sequence_gaps: u64,
+    /// Records with a sequence at or below the previous one of their device
+    pub sequence_regressions: u64,
+    /// Records with a timestamp before the previous one of their device
+    pub timestamp_regressions: u64,
+}
+
+impl Validation {
+    pub fn merge(&mut self, other: &Validation) {
+        self.records += other.records;
+        self.undecodable += other.undecodable;
+        self.missing_fields += other.missing_fields;
+        self.invalid_values += other.invalid_values;
+        self.sequence_gaps += other.sequence_gaps;
+        self.sequence_regressions += other.sequence_regressions;
+        self.timestamp_regressions += other.timestamp_regressions;
+    }
+
+    /// Problems found over all the checks
+    pub fn errors(&self) -> u64 {
+        self.undecodable
+            + self.missing_fields
+            + self.invalid_values
+            + self.sequence_gaps
+            + self.sequence_regressions
+            + self.timestamp_regressions
+    }
+}
+
+impl Report {
+    pub fn new(pubstats: &PubStats, substats: &SubStats, expected: u64) -> Report {
+        Report {
+            sent: pubstats.outgoing_publish,
+            received: substats.publish_count,
+            expected,
+            throughput: pubstats.throughput as f64,
+            sub_throughput: substats.throughput as f64,
+            p50_latency: pubstats.latencies.percentile(50.0),
+            p90_latency: pubstats.latencies.percentile(90.0),
+            p99_latency: pubstats.latencies.percentile(99.0),
+            max_latency: pubstats.latencies.0.max(),
+            reconnects: pubstats.reconnects + substats.reconnects,
+            validation: None,
+            buffering: None,
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json)
+    }
+
+    pub fn load(path: &str) -> io::Result<Report> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Publishes subscribers missed
+    pub fn loss(&self) -> u64 {
+        self.expected.saturating_sub(self.received)
+    }
+
+    /// Missed publishes as a percentage of the expected ones
+    pub fn loss_percent(&self) -> f64 {
+        if self.expected == 0 {
+            return 0.0;
+        }
+
+        self.loss() as f64 * 100.0 / self.expected as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process, time::Duration};
+
+    use super::*;
+    use crate::common;
+
+    #[test]
+    fn instant_runs_have_no_throughput() {
+        assert_eq!(common::throughput(100, Duration::ZERO), 0.0);
+        assert_eq!(common::throughput(100, Duration::from_millis(500)), 200.0);
+        assert!(common::throughput(100, Duration::from_nanos(1)).is_finite());
+    }
+
+    #[test]
+    fn saved_reports_load() {
+        let pubstats = PubStats {
+            outgoing_publish: 100,
+            throughput: common::throughput(100, Duration::ZERO),
+            ..Default::default()
+        };
+        let substats = SubStats {
+            publish_count: 90,
+            throughput: common::throughput(90, Duration::from_micros(300)),
+            ..Default::default()
+        };
+        let mut report = Report::new(&pubstats, &substats, 100);
+        report.validation = Some(Validation {
+            records: 90,
+            sequence_gaps: 10,
+            ..Default::default()
+        });
+
+        let path = env::temp_dir().join(format!("mqttwrk-report-{}.json", process::id()));
+        let path = path.to_str().unwrap();
+        report.save(path).unwrap();
+        let loaded = Report::load(path);
+        fs::remove_file(path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.sent, 100);
+        assert_eq!(loaded.received, 90);
+        assert_eq!(loaded.throughput, 0.0);
+        assert_eq!(loaded.sub_throughput, report.sub_throughput);
+        assert_eq!(loaded.loss(), 10);
+        assert_eq!(loaded.validation.unwrap().sequence_gaps, 10);
+        assert!(loaded.buffering.is_none());
+    }
+}
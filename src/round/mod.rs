@@ -1,131 +1,444 @@
 use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, BufMut};
+use clap::ValueEnum;
 use futures::future::try_join_all;
+use hdrhistogram::Histogram;
 use log::debug;
+use once_cell::sync::Lazy;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rumqttc::{AsyncClient, Event, MqttOptions, QoS};
+use serde::Serialize;
+use std::fs;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::{sync::Barrier, task, time};
 use tokio_util::sync::CancellationToken;
 
-use crate::RoundConfig;
+use crate::{metrics::METRICS, runtime::Shards, RoundConfig};
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-pub(crate) async fn start(opt: RoundConfig) -> Result<()> {
-    let connections = vec![1usize, 2, 5, 10, 15, 20, 30, 40, 50, 75, 100, 150, 200];
-    // let connections = vec![70];
-    let execution_time = opt.duration;
+/// Reference point of the timestamps carried in publish payloads. All the
+/// connections of a run share it, so any of them can compute round trips
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Length of the timestamp at the start of every payload
+const TIMESTAMP_LEN: usize = 8;
+
+pub(crate) async fn start(opt: RoundConfig, shards: Shards) -> Result<()> {
+    crate::metrics::start(opt.metrics_addr).await;
+
+    if opt.auto {
+        return saturate(opt, shards).await;
+    }
 
-    for (iteration, connections) in connections.iter().enumerate() {
-        if iteration != 0 {
+    let connections = match &opt.connections {
+        Some(ladder) => ladder.0.clone(),
+        None => vec![1usize, 2, 5, 10, 15, 20, 30, 40, 50, 75, 100, 150, 200],
+    };
+
+    let mut summaries = Vec::new();
+    for (i, connections) in connections.iter().enumerate() {
+        if i != 0 {
             // Cool down between each iteration
-            time::sleep(Duration::from_secs(5)).await;
+            time::sleep(Duration::from_secs(opt.cool_down)).await;
         }
 
-        // Barrier to synchronize all connections after connect and subscribe
-        let barrier = Arc::new(Barrier::new(*connections + 1));
-        // Stop token to stop the connections
-        let stop = CancellationToken::new();
-
-        // Start connections
-        let mut tasks = Vec::new();
-        for c in 0..*connections {
-            let barrier = barrier.clone();
-            let stop = stop.clone();
-            let opt = opt.clone();
-            let task = task::spawn(async move {
-                let v = time::timeout(
-                    Duration::from_secs(opt.duration + 10),
-                    connection(c, opt, stop, barrier),
-                )
-                .await;
-
-                let v = match v {
-                    Ok(v) => v,
-                    Err(e) => bail!("connection: {:<5} error: {}", c, e),
-                };
+        let summary = iteration(&opt, *connections, &shards).await?;
+        summary.print();
+        summaries.push(summary);
+    }
 
-                let v = match v {
-                    Ok(v) => v,
-                    Err(e) => bail!("connection: {:<5} error: {}", c, e),
-                };
+    finish(&opt, &summaries)
+}
+
+/// Prints the results of all iterations as a table and saves them as json
+/// if a report file is configured
+fn finish(opt: &RoundConfig, summaries: &[Summary]) -> Result<()> {
+    println!();
+    println!(
+        "{:>11} {:>7} {:>12} {:>12} {:>10} {:>10} {:>10} {:>10}",
+        "Connections", "QoS", "Total/s", "Per conn/s", "p50 RTT", "p90 RTT", "p99 RTT", "Max RTT"
+    );
+    for v in summaries {
+        println!(
+            "{:>11} {:>7} {:>12} {:>12} {:>8.2}ms {:>8.2}ms {:>8.2}ms {:>8.2}ms",
+            v.connections,
+            v.qos_mix(),
+            v.throughput,
+            v.per_connection,
+            v.p50_rtt,
+            v.p90_rtt,
+            v.p99_rtt,
+            v.max_rtt
+        );
+    }
+
+    if let Some(path) = &opt.report {
+        let json = serde_json::to_string_pretty(summaries)?;
+        fs::write(path, json)?;
+    }
+
+    Ok(())
+}
+
+/// Throughput gain in percent, `None` without a previous throughput to
+/// compare with
+fn gain(previous: u128, current: u128) -> Option<f64> {
+    match previous {
+        0 => None,
+        _ => Some((current as f64 - previous as f64) * 100.0 / previous as f64),
+    }
+}
+
+/// Keeps raising the no. of connections until total throughput stops growing
+/// or latency passes the limit and reports the knee point
+async fn saturate(opt: RoundConfig, shards: Shards) -> Result<()> {
+    let mut connections = match &opt.connections {
+        Some(ladder) => ladder.0[0],
+        None => 1,
+    };
+
+    let mut summaries = Vec::new();
+    let mut best: Option<Summary> = None;
+    let (stop, summary) = loop {
+        if best.is_some() {
+            // Cool down between each iteration
+            time::sleep(Duration::from_secs(opt.cool_down)).await;
+        }
+
+        let summary = iteration(&opt, connections, &shards).await?;
+        summary.print();
+        summaries.push(summary.clone());
 
-                Ok::<_, anyhow::Error>(v)
+        match next(&opt, best.as_ref(), &summary) {
+            Ok(next) => {
+                best = Some(summary);
+                connections = next;
+            }
+            Err(stop) => break (stop, summary),
+        }
+    };
+
+    match stop {
+        Stop::Latency { p99, limit } => println!(
+            "p99 RTT {:.2}ms passed the limit of {}ms at {} connections",
+            p99, limit, connections
+        ),
+        Stop::Gain(gain) => println!(
+            "Throughput gain {:.2}% is below {}% at {} connections",
+            gain, opt.min_gain, connections
+        ),
+        Stop::NoThroughput => println!("No throughput at {} connections", connections),
+        Stop::MaxConnections => {
+            println!("Reached the limit of {} connections", opt.max_connections)
+        }
+    }
+
+    // iterations past the latency limit don't count, slower ones aren't better
+    let better = match (&stop, &best) {
+        (Stop::Latency { .. }, _) => false,
+        (Stop::MaxConnections, _) | (_, None) => true,
+        (_, Some(best)) => summary.throughput > best.throughput,
+    };
+    if better {
+        best = Some(summary);
+    }
+
+    match best {
+        Some(best) => println!(
+            "Saturation point: {} connections, Total: {}/s, p99 RTT: {:.2}ms",
+            best.connections, best.throughput, best.p99_rtt
+        ),
+        None => println!("No iteration stayed within the latency limit"),
+    }
+
+    finish(&opt, &summaries)
+}
+
+/// Why automatic iterations stopped
+#[derive(Debug, PartialEq)]
+enum Stop {
+    /// p99 RTT passed `--max-latency`
+    Latency {
+        p99: f64,
+        limit: u64,
+    },
+    /// Throughput grew less than `--min-gain` percent
+    Gain(f64),
+    /// Neither this nor the best iteration got any throughput
+    NoThroughput,
+    MaxConnections,
+}
+
+/// Connections of the automatic iteration after `summary`, which is compared
+/// with the best iteration before it
+fn next(opt: &RoundConfig, best: Option<&Summary>, summary: &Summary) -> Result<usize, Stop> {
+    if let Some(max_latency) = opt.max_latency {
+        if summary.p99_rtt > max_latency as f64 {
+            let p99 = summary.p99_rtt;
+            return Err(Stop::Latency {
+                p99,
+                limit: max_latency,
             });
+        }
+    }
 
-            tasks.push(task);
+    if let Some(best) = best {
+        match gain(best.throughput, summary.throughput) {
+            Some(gain) if gain < opt.min_gain => return Err(Stop::Gain(gain)),
+            // anything is a gain over nothing
+            None if summary.throughput == 0 => return Err(Stop::NoThroughput),
+            _ => {}
         }
+    }
 
-        // Start execution time count in a task. Or else, connection errors
-        // won't propogate to try_join_all immediately
-        task::spawn(async move {
-            // Wait until all connections are subscribed before waiting for execution_time seconds
-            barrier.wait().await;
+    let connections = summary.connections;
+    if connections >= opt.max_connections {
+        return Err(Stop::MaxConnections);
+    }
 
-            // Wait for the test duration
-            time::sleep(Duration::from_secs(execution_time)).await;
+    let next = (connections as f64 * opt.growth).ceil() as usize;
+    Ok(next.max(connections + 1).min(opt.max_connections))
+}
+
+/// Runs `connections` request/response connections for the configured duration
+async fn iteration(opt: &RoundConfig, connections: usize, shards: &Shards) -> Result<Summary> {
+    let execution_time = opt.duration;
 
-            // Stop and shutdown the connections
-            stop.cancel();
+    // Barrier to synchronize all connections after connect and subscribe
+    let barrier = Arc::new(Barrier::new(connections + 1));
+    // Stop token to stop the connections
+    let stop = CancellationToken::new();
+
+    // Start connections
+    let mut tasks = Vec::new();
+    for c in 0..connections {
+        let barrier = barrier.clone();
+        let stop = stop.clone();
+        let opt = opt.clone();
+        let task = shards.spawn(c, async move {
+            let v = time::timeout(
+                Duration::from_secs(opt.duration + 10),
+                connection(c, connections, opt, stop, barrier),
+            )
+            .await;
+
+            let v = match v {
+                Ok(v) => v,
+                Err(e) => bail!("connection: {:<5} error: {}", c, e),
+            };
+
+            let v = match v {
+                Ok(v) => v,
+                Err(e) => bail!("connection: {:<5} error: {}", c, e),
+            };
+
+            Ok::<_, anyhow::Error>(v)
         });
 
-        // Wait for connection tasks to finish
-        let results = try_join_all(tasks).await?;
-        let mut success: Vec<Status> = results
-            .iter()
-            .filter_map(|v| v.as_ref().ok())
-            .cloned()
-            .collect();
-        let total: u128 = success.iter().map(|v| v.throughput).sum();
-        success.sort();
-
-        let mut sent = 0;
-        let mut received = 0;
-        for v in success {
-            sent += v.sent;
-            received += v.received;
-        }
+        tasks.push(task);
+    }
+
+    // Start execution time count in a task. Or else, connection errors
+    // won't propogate to try_join_all immediately
+    task::spawn(async move {
+        // Wait until all connections are subscribed before waiting for execution_time seconds
+        barrier.wait().await;
+
+        // Wait for the test duration
+        time::sleep(Duration::from_secs(execution_time)).await;
+
+        // Stop and shutdown the connections
+        stop.cancel();
+    });
+
+    // Wait for connection tasks to finish
+    let results = try_join_all(tasks).await?;
+    let mut success: Vec<Status> = results
+        .iter()
+        .filter_map(|v| v.as_ref().ok())
+        .cloned()
+        .collect();
+    let total: u128 = success.iter().map(|v| v.throughput).sum();
+    success.sort_by_key(|v| v.id);
+
+    let mut sent = 0;
+    let mut received = 0;
+    let mut rtt = Histogram::<u64>::new(4)?;
+    for v in success {
+        sent += v.sent;
+        received += v.received;
+        rtt.add(&v.rtt)?;
+    }
 
-        // println!("-------------------------------------------------------------------------------");
-        // for v in success {
-        //     println!(
-        //         "connection: {:<5} out: {:<10} in: {:<10} miss: {:<5} throughput: {}/s",
-        //         v.id,
-        //         v.sent,
-        //         v.received,
-        //         v.sent - v.received,
-        //         v.throughput
-        //     )
-        // }
-        // println!("-------------------------------------------------------------------------------");
-
-        // let failures: Vec<&anyhow::Error> = results.iter().filter_map(|v| v.as_ref().err()).collect();
-        // for v in failures {
-        //     println!("{}", v);
-        // }
+    // Round trips are recorded in microseconds
+    let millis = |quantile: f64| rtt.value_at_quantile(quantile) as f64 / 1000.0;
+    Ok(Summary {
+        connections,
+        routing: opt.routing,
+        publish_qos: opt.publish_qos,
+        subscribe_qos: opt.subscribe_qos,
+        payload_size: opt.payload_size.max(TIMESTAMP_LEN),
+        sent,
+        received,
+        throughput: total,
+        per_connection: total / connections as u128,
+        p50_rtt: millis(0.5),
+        p90_rtt: millis(0.9),
+        p99_rtt: millis(0.99),
+        max_rtt: rtt.max() as f64 / 1000.0,
+    })
+}
 
+/// Result of one iteration of the connection ladder. Round trips are in
+/// milliseconds
+#[derive(Debug, Clone, Serialize)]
+struct Summary {
+    connections: usize,
+    routing: Routing,
+    publish_qos: i16,
+    subscribe_qos: i16,
+    payload_size: usize,
+    sent: u64,
+    received: u64,
+    throughput: u128,
+    per_connection: u128,
+    p50_rtt: f64,
+    p90_rtt: f64,
+    p99_rtt: f64,
+    max_rtt: f64,
+}
+
+impl Summary {
+    /// Publish and subscribe QoS of the iteration, e.g. `1/0`. Publishes
+    /// are delivered at the lower of both
+    fn qos_mix(&self) -> String {
+        format!("{}/{}", self.publish_qos, self.subscribe_qos)
+    }
+
+    fn print(&self) {
         println!(
-            "Connections: {:3} Sent: {:10} Miss: {:5} Per connection avg: {:7}/s Total: {}/s",
-            connections,
-            sent,
-            sent - received,
-            total / *connections as u128,
-            total
+            "Connections: {:3} Routing: {:?} QoS: {} Sent: {:10} Miss: {:5} Per connection avg: {:7}/s Total: {}/s p50 RTT: {:.2}ms p99 RTT: {:.2}ms",
+            self.connections,
+            self.routing,
+            self.qos_mix(),
+            self.sent,
+            self.sent.saturating_sub(self.received),
+            self.per_connection,
+            self.throughput,
+            self.p50_rtt,
+            self.p99_rtt
         );
     }
+}
 
-    Ok(())
+/// Connection counts of the ladder. Either a comma separated list (`1,2,5`)
+/// or an inclusive range with an optional step (`10..100` or `10..100:10`)
+#[derive(Debug, Clone)]
+pub struct Ladder(Vec<usize>);
+
+impl FromStr for Ladder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |v: &str| {
+            v.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid connection count `{v}`"))
+        };
+
+        let connections: Vec<usize> = match s.split_once("..") {
+            Some((from, to)) => {
+                let (to, step) = match to.split_once(':') {
+                    Some((to, step)) => (to, number(step)?),
+                    None => (to, 1),
+                };
+
+                if step == 0 {
+                    return Err("step should be greater than 0".to_owned());
+                }
+
+                (number(from)?..=number(to)?).step_by(step).collect()
+            }
+            None => s.split(',').map(number).collect::<Result<_, _>>()?,
+        };
+
+        if connections.is_empty() {
+            return Err("no connections in ladder".to_owned());
+        }
+
+        if connections.contains(&0) {
+            return Err("connections should be greater than 0".to_owned());
+        }
+
+        Ok(Ladder(connections))
+    }
 }
 
-#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd)]
+/// Which connection receives the publishes of a connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Routing {
+    /// Every connection publishes to itself
+    Loopback,
+    /// Connection i publishes to connection (i + 1) mod N
+    Ring,
+    /// Every publish goes to a random other connection
+    Random,
+}
+
+impl Routing {
+    /// Connection which receives the next publish of connection `n`
+    fn peer(self, n: usize, connections: usize, rng: &mut StdRng) -> usize {
+        match self {
+            Routing::Loopback => n,
+            Routing::Ring => (n + 1) % connections,
+            Routing::Random if connections == 1 => n,
+            Routing::Random => {
+                // Skip over ourselves so that every peer is equally likely
+                let peer = rng.gen_range(0..connections - 1);
+                if peer >= n {
+                    peer + 1
+                } else {
+                    peer
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Status {
     id: usize,
     sent: u64,
     received: u64,
     throughput: u128,
+    /// Round trips of received publishes in microseconds
+    rtt: Histogram<u64>,
+}
+
+/// Payload of `size` bytes (at least `TIMESTAMP_LEN`) starting with the
+/// microseconds since `EPOCH` at which it is published
+fn stamped(size: usize) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(size.max(TIMESTAMP_LEN));
+    payload.put_u64(EPOCH.elapsed().as_micros() as u64);
+    payload.resize(size.max(TIMESTAMP_LEN), 0);
+    payload
+}
+
+/// Microseconds since the payload was stamped
+fn round_trip(mut payload: &[u8]) -> Option<u64> {
+    if payload.len() < TIMESTAMP_LEN {
+        return None;
+    }
+
+    let sent = payload.get_u64();
+    Some((EPOCH.elapsed().as_micros() as u64).saturating_sub(sent))
 }
 
 async fn connection(
     n: usize,
+    connections: usize,
     opt: RoundConfig,
     stop: CancellationToken,
     barrier: Arc<Barrier>,
@@ -136,15 +449,25 @@ async fn connection(
     mqttoptions.set_clean_session(true);
     mqttoptions.set_inflight(opt.in_flight as u16);
     mqttoptions.set_keep_alive(Duration::from_secs(opt.duration));
-    mqttoptions.set_request_channel_capacity(opt.in_flight + 10);
+
+    // Publishes are made from the eventloop task without waiting for room in
+    // the request queue, so it should never fill up. Routed to peers, all the
+    // messages in flight might arrive at the same connection
+    let capacity = match opt.routing {
+        Routing::Loopback => opt.in_flight + 10,
+        _ => connections * opt.in_flight + 10,
+    };
+    mqttoptions.set_request_channel_capacity(capacity);
 
     // Initialize the client with a request queue size that is bigger than the in flight number
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, opt.in_flight + 10);
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, capacity);
 
-    // Each connection uses it's own random topic
+    // Each connection subscribes to it's own topic and publishes to the
+    // topics of its peers
     // let topic = uuid::Uuid::new_v4().to_string();
     // let topic = "hello/1/world".to_string();
     let topic = n.to_string();
+    let mut rng = StdRng::from_entropy();
 
     // Count publications sent
     let mut publications_sent = 0u64;
@@ -152,22 +475,46 @@ async fn connection(
     // Count publications received
     let mut publications_received = 0u64;
 
+    let publish_qos = get_qos(opt.publish_qos);
+
+    // Round trips of received publications
+    let mut rtt = Histogram::<u64>::new(4)?;
+
     // Start timestamp
     let mut start = Instant::now();
 
-    // Publication data
-    let mut data = bytes::BytesMut::new();
-    data.extend(std::iter::repeat(0u8).take(opt.payload_size));
-    let data = data.freeze();
+    // Counted as connected from the connack till the end of the task
+    let mut _connection = None;
 
     'outer: loop {
-        match eventloop.poll().await? {
+        // Peers might stop before sending us anything else, so don't wait for
+        // a publish to notice the end of the iteration
+        let event = tokio::select! {
+            _ = stop.cancelled() => {
+                // Calculate the rate in pub + res in per s
+                let micros = Instant::now().duration_since(start).as_micros() + 1;
+                let rate = (publications_received as u128 * 1_000_000) / micros;
+                let v = Status {
+                    id: n,
+                    sent: publications_sent,
+                    received: publications_received,
+                    throughput: rate,
+                    rtt,
+                };
+
+                break 'outer Ok(v);
+            }
+            event = eventloop.poll() => event?,
+        };
+
+        match event {
             Event::Incoming(p) => {
                 match p {
                     rumqttc::Packet::ConnAck(_) => {
                         debug!("[{}]: Connected", n);
+                        _connection = Some(METRICS.connection());
                         // We're connected. Subscribe to our topic
-                        client.subscribe(topic.clone(), QoS::AtLeastOnce).await?;
+                        client.try_subscribe(topic.clone(), get_qos(opt.subscribe_qos))?;
                     }
                     rumqttc::Packet::SubAck(_) => {
                         // This test codes does only one subscription. Receiving the
@@ -183,30 +530,27 @@ async fn connection(
                         // Start the publication loop by publishing n messages
                         for _ in 0..opt.in_flight {
                             publications_sent += 1;
-                            client
-                                .publish_bytes(topic.clone(), QoS::AtLeastOnce, false, data.clone())
-                                .await?;
+                            let peer = opt.routing.peer(n, connections, &mut rng);
+                            let payload = stamped(opt.payload_size);
+                            client.try_publish(peer.to_string(), publish_qos, false, payload)?;
                         }
                     }
-                    rumqttc::Packet::Publish(_) if stop.is_cancelled() => {
-                        // Calculate the rate in pub + res in per s
-                        let micros = Instant::now().duration_since(start).as_micros() + 1;
-                        let rate = (publications_received as u128 * 1_000_000) / micros;
-                        let v = Status {
-                            id: n,
-                            sent: publications_sent,
-                            received: publications_received,
-                            throughput: rate,
-                        };
-
-                        break 'outer Ok(v);
-                    }
                     rumqttc::Packet::Publish(v) => {
                         debug!("[{}]: Incoming publish {:?}", n, v);
+                        METRICS.received();
                         publications_received += 1;
+                        if let Some(elapsed) = round_trip(&v.payload) {
+                            rtt.record(elapsed)?;
+                        }
 
                         if let Some(max_publishes) = opt.max_publishes {
                             if publications_sent >= max_publishes {
+                                // Peers depend on our publishes to make progress, so
+                                // the first connection done ends the iteration
+                                if opt.routing != Routing::Loopback {
+                                    stop.cancel();
+                                }
+
                                 let micros = Instant::now().duration_since(start).as_micros() + 1;
                                 let rate = (publications_received as u128 * 1_000_000) / micros;
 
@@ -215,15 +559,16 @@ async fn connection(
                                     sent: publications_sent,
                                     received: publications_received,
                                     throughput: rate,
+                                    rtt,
                                 };
 
                                 break 'outer Ok(v);
                             }
                         }
 
-                        client
-                            .publish(&topic, QoS::AtMostOnce, false, vec![0; 100])
-                            .await?;
+                        let peer = opt.routing.peer(n, connections, &mut rng);
+                        let payload = stamped(opt.payload_size);
+                        client.try_publish(peer.to_string(), publish_qos, false, payload)?;
                         // Not yet finished. Increment the publications sent counter and publish again
                         publications_sent += 1;
                     }
@@ -232,10 +577,168 @@ async fn connection(
                         debug!("[{}]: Disconnected", n);
                         break Err(anyhow!("Disconnected"));
                     }
+                    rumqttc::Packet::PubAck(_) | rumqttc::Packet::PubComp(_) => METRICS.acked(),
                     v => debug!("Incoming = {:?}", v),
                 }
             }
+            Event::Outgoing(rumqttc::Outgoing::Publish(_)) => METRICS.published(),
             Event::Outgoing(_) => continue,
         }
     }
 }
+
+fn get_qos(qos: i16) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn gains_need_a_baseline() {
+        assert_eq!(gain(1000, 1500), Some(50.0));
+        assert_eq!(gain(1000, 900), Some(-10.0));
+        assert_eq!(gain(0, 1000), None);
+        assert_eq!(gain(0, 0), None);
+    }
+
+    fn summary(connections: usize, throughput: u128, p99_rtt: f64) -> Summary {
+        Summary {
+            connections,
+            routing: Routing::Loopback,
+            publish_qos: 1,
+            subscribe_qos: 1,
+            payload_size: 100,
+            sent: 0,
+            received: 0,
+            throughput,
+            per_connection: 0,
+            p50_rtt: 0.0,
+            p90_rtt: 0.0,
+            p99_rtt,
+            max_rtt: 0.0,
+        }
+    }
+
+    fn ladder(s: &str) -> Result<Vec<usize>, String> {
+        s.parse::<Ladder>().map(|v| v.0)
+    }
+
+    #[test]
+    fn ladders_parse() {
+        assert_eq!(ladder("1,2,5").unwrap(), [1, 2, 5]);
+        assert_eq!(ladder(" 3 , 1").unwrap(), [3, 1]);
+        assert_eq!(ladder("10..13").unwrap(), [10, 11, 12, 13]);
+        assert_eq!(ladder("10..100:30").unwrap(), [10, 40, 70, 100]);
+        assert_eq!(ladder("10..10").unwrap(), [10]);
+
+        for invalid in [
+            "", "1,,2", "a", "-1", "0,1", "0..2", "5..1", "1..5:0", "1..x", "1..5:",
+        ] {
+            assert!(ladder(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn saturation_stops_growing() {
+        let opt = RoundConfig::parse_from(["round", "--auto", "--max-connections", "20"]);
+        // the first iteration has nothing to compare with
+        assert_eq!(next(&opt, None, &summary(1, 100, 1.0)), Ok(2));
+        assert_eq!(next(&opt, None, &summary(1, 0, 1.0)), Ok(2));
+
+        let best = summary(5, 1000, 1.0);
+        assert_eq!(next(&opt, Some(&best), &summary(10, 1100, 1.0)), Ok(20));
+        assert_eq!(
+            next(&opt, Some(&best), &summary(10, 1040, 1.0)),
+            Err(Stop::Gain(4.0))
+        );
+        assert_eq!(
+            next(&opt, Some(&best), &summary(10, 500, 1.0)),
+            Err(Stop::Gain(-50.0))
+        );
+
+        // anything is a gain over nothing
+        let best = summary(5, 0, 1.0);
+        assert_eq!(next(&opt, Some(&best), &summary(10, 1, 1.0)), Ok(20));
+        assert_eq!(
+            next(&opt, Some(&best), &summary(10, 0, 1.0)),
+            Err(Stop::NoThroughput)
+        );
+
+        // growth is capped by the limit, which ends the ladder
+        assert_eq!(next(&opt, None, &summary(15, 100, 1.0)), Ok(20));
+        assert_eq!(
+            next(&opt, None, &summary(20, 100, 1.0)),
+            Err(Stop::MaxConnections)
+        );
+    }
+
+    #[test]
+    fn saturation_grows_by_the_factor() {
+        let args = ["round", "--auto", "--growth", "1.5", "--min-gain", "20"];
+        let opt = RoundConfig::parse_from(args);
+        // at least one more connection
+        assert_eq!(next(&opt, None, &summary(1, 100, 1.0)), Ok(2));
+        assert_eq!(next(&opt, None, &summary(10, 100, 1.0)), Ok(15));
+
+        let best = summary(10, 1000, 1.0);
+        assert_eq!(
+            next(&opt, Some(&best), &summary(15, 1100, 1.0)),
+            Err(Stop::Gain(10.0))
+        );
+    }
+
+    #[test]
+    fn saturation_respects_the_latency_limit() {
+        let opt = RoundConfig::parse_from(["round", "--auto", "--max-latency", "50"]);
+        assert_eq!(next(&opt, None, &summary(1, 100, 50.0)), Ok(2));
+        // latency stops even growing throughputs
+        let best = summary(1, 100, 10.0);
+        let stop = Stop::Latency {
+            p99: 60.5,
+            limit: 50,
+        };
+        assert_eq!(next(&opt, Some(&best), &summary(2, 1000, 60.5)), Err(stop));
+    }
+
+    #[test]
+    fn peers_follow_the_routing() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for n in 0..5 {
+            assert_eq!(Routing::Loopback.peer(n, 5, &mut rng), n);
+            assert_eq!(Routing::Ring.peer(n, 5, &mut rng), (n + 1) % 5);
+        }
+        assert_eq!(Routing::Ring.peer(0, 1, &mut rng), 0);
+        assert_eq!(Routing::Random.peer(0, 1, &mut rng), 0);
+
+        // random peers are never the sender, and all the others come up
+        for connections in [2, 3, 10] {
+            for n in 0..connections {
+                let mut seen = vec![false; connections];
+                for _ in 0..1000 {
+                    let peer = Routing::Random.peer(n, connections, &mut rng);
+                    assert_ne!(peer, n);
+                    seen[peer] = true;
+                }
+                assert_eq!(seen.iter().filter(|v| **v).count(), connections - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn payloads_carry_their_send_time() {
+        let payload = stamped(100);
+        assert_eq!(payload.len(), 100);
+        assert!(round_trip(&payload).is_some());
+        assert_eq!(stamped(2).len(), TIMESTAMP_LEN);
+        assert_eq!(round_trip(&payload[..TIMESTAMP_LEN - 1]), None);
+    }
+}
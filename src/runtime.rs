@@ -0,0 +1,190 @@
+use std::{future::Future, sync::Arc, thread};
+
+use clap::{builder::RangedU64ValueParser, Args};
+use tokio::{runtime::Handle, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Clone, Debug, Args)]
+pub struct RuntimeConfig {
+    #[command(flatten)]
+    pub threads: Threads,
+    /// Run one single threaded runtime per core and pin clients to them
+    #[arg(long, default_value = "false")]
+    pub sharded: bool,
+}
+
+// Worker threads of a runtime, for commands which can't be sharded. Not a
+// doc comment, clap would make it the about of the commands flattening it
+#[derive(Clone, Debug, Args)]
+pub struct Threads {
+    /// No. of runtime worker threads (defaults to all cores)
+    #[arg(id = "threads", long = "threads", value_name = "NUM", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub count: Option<usize>,
+}
+
+impl From<Threads> for RuntimeConfig {
+    fn from(threads: Threads) -> RuntimeConfig {
+        RuntimeConfig {
+            threads,
+            sharded: false,
+        }
+    }
+}
+
+/// Tokio runtime driving a run. In sharded mode, clients are spawned on
+/// per core single threaded runtimes instead of the main runtime
+pub struct Runtime {
+    inner: tokio::runtime::Runtime,
+    shards: Shards,
+    shutdown: CancellationToken,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Runtime {
+    pub fn new(config: &RuntimeConfig) -> Runtime {
+        let threads = config.threads.count.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        });
+
+        if !config.sharded {
+            let inner = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(threads)
+                .enable_all()
+                .build()
+                .expect("runtime should be created");
+
+            return Runtime {
+                inner,
+                shards: Shards::default(),
+                shutdown: CancellationToken::new(),
+                threads: Vec::new(),
+            };
+        }
+
+        // Main runtime only orchestrates, clients live on the shards
+        let inner = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime should be created");
+
+        let shutdown = CancellationToken::new();
+        let cores = core_affinity::get_core_ids().unwrap_or_default();
+        let mut handles = Vec::with_capacity(threads);
+        let mut shard_threads = Vec::with_capacity(threads);
+        for shard in 0..threads {
+            let core = cores.get(shard % cores.len().max(1)).copied();
+            let shutdown = shutdown.clone();
+            let (tx, rx) = std::sync::mpsc::channel();
+            let thread = thread::Builder::new()
+                .name(format!("shard-{shard}"))
+                .spawn(move || {
+                    if let Some(core) = core {
+                        if !core_affinity::set_for_current(core) {
+                            warn!("Shard = {}, Failed to pin to core {:?}", shard, core);
+                        }
+                    }
+
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .expect("shard runtime should be created");
+
+                    tx.send(runtime.handle().clone()).unwrap();
+                    runtime.block_on(shutdown.cancelled());
+                })
+                .expect("shard thread should be spawned");
+
+            handles.push(rx.recv().expect("shard should send its handle"));
+            shard_threads.push(thread);
+        }
+
+        Runtime {
+            inner,
+            shards: Shards {
+                handles: Arc::new(handles),
+            },
+            shutdown,
+            threads: shard_threads,
+        }
+    }
+
+    pub fn shards(&self) -> Shards {
+        self.shards.clone()
+    }
+
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.inner.block_on(future)
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Handle to spawn client tasks. Tasks with the same key always land on the
+/// same shard. Without shards, tasks are spawned on the current runtime
+#[derive(Clone, Default)]
+pub struct Shards {
+    handles: Arc<Vec<Handle>>,
+}
+
+impl Shards {
+    pub fn spawn<F>(&self, key: usize, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        if self.handles.is_empty() {
+            return tokio::spawn(future);
+        }
+
+        self.handles[key % self.handles.len()].spawn(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runtime(count: usize, sharded: bool) -> Runtime {
+        let threads = Threads { count: Some(count) };
+        Runtime::new(&RuntimeConfig { threads, sharded })
+    }
+
+    /// Thread which runs the task of `key`, spawned from the main runtime
+    /// like clients are
+    fn placement(runtime: &Runtime, key: usize) -> String {
+        let shards = runtime.shards();
+        let task = async { thread::current().name().unwrap_or_default().to_owned() };
+        runtime.block_on(async { shards.spawn(key, task).await.unwrap() })
+    }
+
+    #[test]
+    fn keys_pick_their_shard() {
+        let runtime = runtime(3, true);
+        for key in 0..7 {
+            assert_eq!(placement(&runtime, key), format!("shard-{}", key % 3));
+        }
+    }
+
+    #[test]
+    fn unsharded_tasks_run_on_the_runtime() {
+        let runtime = runtime(2, false);
+        assert!(runtime.shards().handles.is_empty());
+        for key in 0..3 {
+            assert!(!placement(&runtime, key).starts_with("shard-"));
+        }
+
+        let shards = runtime.shards();
+        let task = async { Handle::current().runtime_flavor() };
+        let flavor = runtime.block_on(async { shards.spawn(0, task).await.unwrap() });
+        assert_eq!(flavor, tokio::runtime::RuntimeFlavor::MultiThread);
+    }
+}
@@ -0,0 +1,423 @@
+use std::{fs, io, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use rumqttc::{MqttOptions, QoS, Transport};
+use serde::Deserialize;
+use tokio::sync::Barrier;
+
+use crate::{
+    common::{PubStats, Stats, SubStats, PROGRESS_STYLE},
+    runtime::Shards,
+};
+
+mod publisher;
+mod subscriber;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error = {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Invalid scenario = {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Scenario has no groups")]
+    NoGroups,
+    #[error("Duplicate group name = {0}")]
+    DuplicateGroup(String),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectionError {
+    #[error("IO error = {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Connection error = {0:?}")]
+    Connection(#[from] rumqttc::ConnectionError),
+    #[error("Wrong packet = {0:?}")]
+    WrongPacket(rumqttc::Incoming),
+    #[error("Client error = {0:?}")]
+    Client(#[from] rumqttc::ClientError),
+}
+
+/// A run made of several groups of clients sharing one broker
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    /// Broker's address
+    pub server: String,
+    /// Port
+    pub port: u16,
+    /// Keep alive of every client in seconds
+    pub keep_alive: u64,
+    /// Max inflight messages of every client
+    pub max_inflight: u16,
+    /// Path to PEM encoded x509 ca-chain file
+    pub ca_file: Option<String>,
+    /// Connection timeout in seconds
+    pub conn_timeout: u64,
+    /// Seconds a subscriber waits for the next publish before giving up
+    pub idle_timeout: u64,
+    #[serde(rename = "group")]
+    pub groups: Vec<Group>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            server: "localhost".to_owned(),
+            port: 1883,
+            keep_alive: 10,
+            max_inflight: 100,
+            ca_file: None,
+            conn_timeout: 10,
+            idle_timeout: 10,
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> Result<Scenario, Error> {
+        let scenario: Scenario = toml::from_str(&fs::read_to_string(path)?)?;
+        if scenario.groups.is_empty() {
+            return Err(Error::NoGroups);
+        }
+
+        for (i, group) in scenario.groups.iter().enumerate() {
+            if scenario.groups[..i].iter().any(|g| g.name == group.name) {
+                return Err(Error::DuplicateGroup(group.name.clone()));
+            }
+        }
+
+        Ok(scenario)
+    }
+
+    fn clients(&self, role: Role) -> usize {
+        self.groups
+            .iter()
+            .filter(|g| g.role == role)
+            .map(|g| g.clients)
+            .sum()
+    }
+
+    /// Publishes a subscriber of `group` should receive on `filter`, from
+    /// every publisher whose topic matches the subscription
+    fn expected_publishes(&self, group: &Group, filter: &str) -> usize {
+        if let Some(count) = group.count {
+            return count;
+        }
+
+        let mut expected = 0;
+        for publishers in self.groups.iter().filter(|g| g.role == Role::Publisher) {
+            for i in 0..publishers.clients {
+                let topic = publishers.topic(i);
+                if rumqttc::matches(&topic, filter) {
+                    expected += publishers.publish_count();
+                }
+            }
+        }
+
+        expected
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Publisher,
+    Subscriber,
+}
+
+/// A homogeneous set of clients in a scenario
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub name: String,
+    pub role: Role,
+    /// No. of clients in this group
+    #[serde(default = "default_clients")]
+    pub clients: usize,
+    /// Topic to publish to or filter to subscribe to.
+    /// if present:
+    ///     `{group}` is replaced by the group name
+    ///     `{id}` is replaced by the client's index in the group
+    pub topic: String,
+    /// QoS used for publishes or subscription
+    #[serde(default)]
+    pub qos: i16,
+    /// Message rate per second per publisher. (0 means no throttle)
+    #[serde(default)]
+    pub rate: u64,
+    /// No. of messages per publisher, defaults to 100. For subscribers,
+    /// defaults to the publishes of all matching publishers
+    pub count: Option<usize>,
+    /// Payload size in Bytes
+    #[serde(default = "default_payload_size")]
+    pub payload_size: usize,
+}
+
+impl Group {
+    fn client_id(&self, i: usize) -> String {
+        format!("{}-{i:05}", self.name)
+    }
+
+    fn topic(&self, i: usize) -> String {
+        self.topic
+            .replace("{group}", &self.name)
+            .replace("{id}", &i.to_string())
+    }
+
+    fn publish_count(&self) -> usize {
+        self.count.unwrap_or(100)
+    }
+}
+
+fn default_clients() -> usize {
+    1
+}
+
+fn default_payload_size() -> usize {
+    100
+}
+
+pub(crate) async fn start(scenario: Scenario, shards: Shards) {
+    let scenario = Arc::new(scenario);
+    let mut handles = futures::stream::FuturesUnordered::new();
+    let barrier_sub = Arc::new(Barrier::new(scenario.clients(Role::Subscriber)));
+    let barrier_pub = Arc::new(Barrier::new(scenario.clients(Role::Publisher)));
+
+    // spawning subscribers first so that they don't miss any publishes
+    let sub_bar = ProgressBar::new(scenario.clients(Role::Subscriber) as u64)
+        .with_prefix("Subscribers Spawned:")
+        .with_style((*PROGRESS_STYLE).clone());
+
+    let mut shard = 0;
+    for (g, group) in scenario.groups.iter().enumerate() {
+        if group.role != Role::Subscriber {
+            continue;
+        }
+
+        for i in 0..group.clients {
+            let id = group.client_id(i);
+            let filter = group.topic(i);
+            let expected = scenario.expected_publishes(group, &filter);
+            let scenario = Arc::clone(&scenario);
+            let barrier_handle = barrier_sub.clone();
+            sub_bar.set_message(format!("spawning {id}"));
+            let mut subscriber = shards
+                .spawn(
+                    shard,
+                    subscriber::Subscriber::new(id, filter, g, expected, scenario),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            handles.push(shards.spawn(shard, async move {
+                (g, Stats::SubStats(subscriber.start(barrier_handle).await))
+            }));
+            sub_bar.inc(1);
+            shard += 1;
+        }
+    }
+    sub_bar.finish_with_message("Done!");
+
+    // spawing publishers
+    let pub_bar = ProgressBar::new(scenario.clients(Role::Publisher) as u64)
+        .with_prefix("Publishers Spawned:")
+        .with_style((*PROGRESS_STYLE).clone());
+
+    for (g, group) in scenario.groups.iter().enumerate() {
+        if group.role != Role::Publisher {
+            continue;
+        }
+
+        for i in 0..group.clients {
+            let scenario = Arc::clone(&scenario);
+            let id = group.client_id(i);
+            let topic = group.topic(i);
+            let barrier_handle = barrier_pub.clone();
+            pub_bar.set_message(format!("spawning {id}"));
+            let mut publisher = shards
+                .spawn(shard, publisher::Publisher::new(id, topic, g, scenario))
+                .await
+                .unwrap()
+                .unwrap();
+            handles.push(shards.spawn(shard, async move {
+                (g, Stats::PubStats(publisher.start(barrier_handle).await))
+            }));
+            pub_bar.inc(1);
+            shard += 1;
+        }
+    }
+    pub_bar.finish_with_message("Done!");
+
+    let mut aggregate: Vec<Stats> = scenario
+        .groups
+        .iter()
+        .map(|group| match group.role {
+            Role::Publisher => Stats::PubStats(PubStats::default()),
+            Role::Subscriber => Stats::SubStats(SubStats::default()),
+        })
+        .collect();
+
+    // await and consume all futures
+    while let Some(some_stat) = handles.next().await {
+        let (g, stats) = some_stat.unwrap();
+        match (&mut aggregate[g], stats) {
+            (Stats::SubStats(aggregate), Stats::SubStats(substats)) => aggregate.merge(&substats),
+            (Stats::PubStats(aggregate), Stats::PubStats(pubstats)) => aggregate.merge(&pubstats),
+            _ => unreachable!("stats of a group always match its role"),
+        }
+    }
+
+    for (group, stats) in scenario.groups.iter().zip(aggregate) {
+        match stats {
+            Stats::PubStats(pubstats) => println!(
+                "Group {} ({} publishers) PubStats: {:#?}",
+                group.name, group.clients, &pubstats
+            ),
+            Stats::SubStats(substats) => println!(
+                "Group {} ({} subscribers) SubStats: {:#?}",
+                group.name, group.clients, &substats
+            ),
+        }
+    }
+}
+
+pub(crate) fn options(scenario: &Scenario, id: &str) -> io::Result<MqttOptions> {
+    let mut options = MqttOptions::new(id, &scenario.server, scenario.port);
+    options.set_keep_alive(Duration::from_secs(scenario.keep_alive));
+    options.set_inflight(scenario.max_inflight);
+
+    if let Some(ca_file) = &scenario.ca_file {
+        let ca = fs::read(ca_file)?;
+        options.set_transport(Transport::tls(ca, None, None));
+    }
+
+    Ok(options)
+}
+
+/// get QoS level. Default is AtLeastOnce.
+fn get_qos(qos: i16) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Scenario of a file with these contents
+    fn load(name: &str, contents: &str) -> Result<Scenario, Error> {
+        let path = env::temp_dir().join(format!("mqttwrk-scenario-{}-{name}.toml", process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, contents).unwrap();
+        let scenario = Scenario::load(path);
+        fs::remove_file(path).unwrap();
+        scenario
+    }
+
+    const SCENARIO: &str = r#"
+        port = 1884
+
+        [[group]]
+        name = "chatty"
+        role = "publisher"
+        clients = 3
+        topic = "devices/{group}/{id}/events"
+        count = 50
+
+        [[group]]
+        name = "quiet"
+        role = "publisher"
+        clients = 2
+        topic = "devices/{group}/{id}/events"
+
+        [[group]]
+        name = "sink"
+        role = "subscriber"
+        topic = "devices/+/+/events"
+
+        [[group]]
+        name = "first"
+        role = "subscriber"
+        clients = 2
+        topic = "devices/chatty/{id}/#"
+
+        [[group]]
+        name = "counted"
+        role = "subscriber"
+        topic = "devices/#"
+        count = 7
+    "#;
+
+    #[test]
+    fn scenarios_load() {
+        let scenario = load("valid", SCENARIO).unwrap();
+        assert_eq!(scenario.server, "localhost");
+        assert_eq!(scenario.port, 1884);
+        assert_eq!(scenario.groups.len(), 5);
+        assert_eq!(scenario.clients(Role::Publisher), 5);
+        assert_eq!(scenario.clients(Role::Subscriber), 4);
+
+        let quiet = &scenario.groups[1];
+        assert_eq!(quiet.qos, 0);
+        assert_eq!(quiet.payload_size, 100);
+        assert_eq!(quiet.publish_count(), 100);
+        assert_eq!(quiet.client_id(1), "quiet-00001");
+        assert_eq!(quiet.topic(1), "devices/quiet/1/events");
+    }
+
+    #[test]
+    fn invalid_scenarios_are_rejected() {
+        assert!(matches!(load("empty", "port = 1883"), Err(Error::NoGroups)));
+
+        let group = "[[group]]\nname = \"a\"\nrole = \"publisher\"\ntopic = \"a\"\n";
+        let error = load("duplicate", &format!("{group}{group}")).unwrap_err();
+        assert!(matches!(error, Error::DuplicateGroup(v) if v == "a"));
+
+        let invalid = [
+            ("unknown", "ports = 1883"),
+            (
+                "role",
+                "[[group]]\nname = \"a\"\nrole = \"broker\"\ntopic = \"a\"",
+            ),
+            ("topic", "[[group]]\nname = \"a\"\nrole = \"publisher\""),
+        ];
+        for (name, contents) in invalid {
+            let error = load(name, contents).unwrap_err();
+            assert!(matches!(error, Error::Toml(_)), "{}: {:?}", name, error);
+        }
+
+        let missing = Scenario::load("missing-scenario.toml");
+        assert!(matches!(missing, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn subscribers_expect_matching_publishes() {
+        let scenario = load("expected", SCENARIO).unwrap();
+        let expected = |group: usize, i: usize| {
+            let group = &scenario.groups[group];
+            scenario.expected_publishes(group, &group.topic(i))
+        };
+
+        // 3 chatty publishers of 50 and 2 quiet ones of 100
+        assert_eq!(expected(2, 0), 350);
+        // one chatty publisher each
+        assert_eq!(expected(3, 0), 50);
+        assert_eq!(expected(3, 1), 50);
+        // counts are taken as they are
+        assert_eq!(expected(4, 0), 7);
+
+        let group = &scenario.groups[2];
+        assert_eq!(
+            scenario.expected_publishes(group, "devices/none/+/events"),
+            0
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::{sync::Arc, time::Instant};
+
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, QoS};
+use tokio::{
+    sync::Barrier,
+    time::{self, Duration},
+};
+
+use crate::{
+    bench::publisher::{publish, Load},
+    common::{self, PubStats},
+    scenario::{get_qos, options, ConnectionError, Scenario},
+};
+
+pub struct Publisher {
+    id: String,
+    topic: String,
+    group: usize,
+    scenario: Arc<Scenario>,
+    client: AsyncClient,
+    eventloop: EventLoop,
+}
+
+impl Publisher {
+    pub(crate) async fn new(
+        id: String,
+        topic: String,
+        group: usize,
+        scenario: Arc<Scenario>,
+    ) -> Result<Publisher, ConnectionError> {
+        let (client, mut eventloop) = AsyncClient::new(options(&scenario, &id)?, 10);
+        eventloop
+            .network_options
+            .set_connection_timeout(scenario.conn_timeout);
+
+        loop {
+            let event = match eventloop.poll().await {
+                Ok(v) => v,
+                Err(rumqttc::ConnectionError::NetworkTimeout)
+                | Err(rumqttc::ConnectionError::FlushTimeout) => {
+                    println!("{id} reconnecting");
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if let Event::Incoming(v) = event {
+                match v {
+                    Incoming::ConnAck(_) => break,
+                    incoming => return Err(ConnectionError::WrongPacket(incoming)),
+                }
+            }
+        }
+
+        Ok(Publisher {
+            id,
+            topic,
+            group,
+            scenario,
+            client,
+            eventloop,
+        })
+    }
+
+    pub async fn start(&mut self, barrier_handle: Arc<Barrier>) -> PubStats {
+        let group = &self.scenario.groups[self.group];
+        let qos = get_qos(group.qos);
+        let count = group.publish_count();
+        let load = Load {
+            topic: self.topic.clone(),
+            qos,
+            payload_size: group.payload_size,
+            count,
+            rate: group.rate,
+        };
+
+        let wait = barrier_handle.wait();
+        tokio::pin!(wait);
+
+        // Keep sending pings until all publishers are spawned
+        loop {
+            tokio::select! {
+                _ = wait.as_mut() => {
+                    break;
+                }
+                _ = self.eventloop.poll() => {
+                }
+            };
+        }
+
+        let start = Instant::now();
+        let inflight = self.scenario.max_inflight;
+        let client = self.client.clone();
+        let acks = publish(&self.id, client, &mut self.eventloop, inflight, load, start).await;
+        let outgoing_elapsed = acks.elapsed.unwrap_or_else(|| start.elapsed());
+        let outgoing_throughput = common::throughput(count as u64, outgoing_elapsed);
+
+        // if qos is 0 assume we send all publishes
+        let mut acks_count = acks.count;
+        if qos == QoS::AtMostOnce && acks.elapsed.is_some() {
+            acks_count = count;
+        }
+
+        PubStats {
+            outgoing_publish: acks_count as u64,
+            throughput: outgoing_throughput,
+            reconnects: acks.reconnects,
+            latencies: acks.latencies,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, Outgoing};
+use tokio::{sync::Barrier, time};
+
+use crate::{
+    common::{self, SubStats},
+    scenario::{get_qos, options, ConnectionError, Scenario},
+};
+
+pub struct Subscriber {
+    id: String,
+    expected: usize,
+    scenario: Arc<Scenario>,
+    #[allow(dead_code)]
+    client: AsyncClient,
+    eventloop: EventLoop,
+}
+
+impl Subscriber {
+    pub(crate) async fn new(
+        id: String,
+        filter: String,
+        group: usize,
+        expected: usize,
+        scenario: Arc<Scenario>,
+    ) -> Result<Subscriber, ConnectionError> {
+        let (client, mut eventloop) = AsyncClient::new(options(&scenario, &id)?, 10);
+        eventloop
+            .network_options
+            .set_connection_timeout(scenario.conn_timeout);
+
+        // waiting for connection
+        loop {
+            let event = eventloop.poll().await?;
+            if let Event::Incoming(v) = event {
+                match v {
+                    Incoming::ConnAck(_) => break,
+                    incoming => return Err(ConnectionError::WrongPacket(incoming)),
+                }
+            }
+        }
+
+        // subscribing
+        let qos = get_qos(scenario.groups[group].qos);
+        client.subscribe(filter, qos).await?;
+
+        // waiting for subscription confirmation
+        loop {
+            let event = eventloop.poll().await?;
+            if let Event::Incoming(v) = event {
+                match v {
+                    Incoming::SubAck(_) => break,
+                    incoming => return Err(ConnectionError::WrongPacket(incoming)),
+                }
+            }
+        }
+
+        Ok(Subscriber {
+            id,
+            expected,
+            scenario,
+            client,
+            eventloop,
+        })
+    }
+
+    pub(crate) async fn start(&mut self, barrier_handle: Arc<Barrier>) -> SubStats {
+        let idle_timeout = Duration::from_secs(self.scenario.idle_timeout);
+        // total number of publishes received
+        let mut publish_count = 0;
+        // total number of pubacks sent
+        let mut puback_count = 0;
+        // when the very first publish arrived
+        let mut start = None;
+        // when the latest publish arrived, or the barrier before the first
+        let mut last_publish;
+        // number of reconnects attempted
+        let mut reconnects = 0;
+
+        barrier_handle.wait().await;
+        last_publish = Instant::now();
+        while publish_count < self.expected {
+            // Publishers of other groups might be slower, so only give up once
+            // no publish arrived for a while. Pings don't count
+            let idle_deadline = time::Instant::from_std(last_publish + idle_timeout);
+            let event = match time::timeout_at(idle_deadline, self.eventloop.poll()).await {
+                Ok(v) => v,
+                Err(_) => {
+                    warn!(
+                        "Id = {}, No publishes for {:?}, received {}/{}",
+                        self.id, idle_timeout, publish_count, self.expected
+                    );
+                    break;
+                }
+            };
+
+            let event = match event {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Id = {}, Connection error = {:?}", self.id, e);
+                    reconnects += 1;
+                    break;
+                }
+            };
+
+            match event {
+                Event::Incoming(Incoming::Publish(_)) => {
+                    publish_count += 1;
+                    last_publish = Instant::now();
+                    start.get_or_insert(last_publish);
+                }
+                Event::Outgoing(Outgoing::PubAck(_)) => {
+                    puback_count += 1;
+                }
+                Event::Incoming(Incoming::PingResp) | Event::Outgoing(_) => {}
+                incoming => error!(
+                    "Id = {}, Unexpected incoming packet = {:?}",
+                    self.id, incoming
+                ),
+            }
+        }
+
+        let elapsed = start.map(|start| last_publish - start).unwrap_or_default();
+        let incoming_throughput = common::throughput(publish_count as u64, elapsed);
+
+        SubStats {
+            publish_count: publish_count as u64,
+            puback_count,
+            reconnects,
+            throughput: incoming_throughput,
+        }
+    }
+}
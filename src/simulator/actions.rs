@@ -0,0 +1,206 @@
+//! Cloud to device commands. Devices get actions on their actions topic and
+//! report progress on the `action_status` stream, the driver sends actions
+//! to the devices and times them until they complete
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use rumqttc::{AsyncClient, Event, Incoming, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    common::Latencies,
+    metrics::METRICS,
+    simulator::{options, ConnectionError},
+    SimulatorConfig,
+};
+
+/// Device type of the action status stream, used for `{data_type}` in topics
+pub const STATUS_STREAM: &str = "action_status";
+
+/// How long the driver waits for the last actions to complete
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Command sent to a device
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Action {
+    pub action_id: String,
+    pub kind: String,
+    pub name: String,
+    pub payload: String,
+}
+
+/// Progress of an action as reported by the device
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActionStatus {
+    pub sequence: u32,
+    pub timestamp: u64,
+    pub action_id: String,
+    pub state: String,
+    pub progress: u8,
+    pub errors: Vec<String>,
+}
+
+impl ActionStatus {
+    pub fn is_done(&self) -> bool {
+        self.state == "Completed" || self.state == "Failed"
+    }
+}
+
+/// Actions of a driver and their command to completion latencies
+#[derive(Debug, Default)]
+pub struct ActionStats {
+    pub sent: u64,
+    pub completed: u64,
+    pub failed: u64,
+    /// Actions without a final status when the driver gave up
+    pub timed_out: u64,
+    /// Send to final status latencies in milliseconds
+    pub latencies: Latencies,
+}
+
+/// Topic of the actions of a device
+pub fn actions_topic(config: &SimulatorConfig, pub_id: &str) -> String {
+    config.actions_topic.replacen("{pub_id}", pub_id, 1)
+}
+
+/// Sends `--action-count` actions round robin over the publishers at
+/// `rate` per second and waits for them to complete. Cancels `done` at the
+/// end so that the devices stop listening for actions
+pub(crate) async fn drive(
+    config: Arc<SimulatorConfig>,
+    rate: f64,
+    done: CancellationToken,
+) -> Result<ActionStats, ConnectionError> {
+    let id = "actions-driver";
+    let (client, mut eventloop) = AsyncClient::new(options(config.clone(), id)?, 10);
+    eventloop
+        .network_options
+        .set_connection_timeout(config.conn_timeout);
+
+    // waiting for connection
+    loop {
+        let event = eventloop.poll().await?;
+        if let Event::Incoming(v) = event {
+            match v {
+                Incoming::ConnAck(_) => {
+                    METRICS.connected();
+                    break;
+                }
+                incoming => return Err(ConnectionError::WrongPacket(incoming)),
+            }
+        }
+    }
+
+    let topic = config.topic_format.replacen("{pub_id}", "+", 1);
+    let topic = topic.replacen("{data_type}", STATUS_STREAM, 1);
+    client.subscribe(topic, QoS::AtLeastOnce).await?;
+
+    // waiting for subscription confirmation
+    loop {
+        let event = eventloop.poll().await?;
+        if let Event::Incoming(v) = event {
+            match v {
+                Incoming::SubAck(_) => break,
+                incoming => return Err(ConnectionError::WrongPacket(incoming)),
+            }
+        }
+    }
+
+    // send time of the actions without a final status
+    let inflight: Arc<Mutex<HashMap<String, Instant>>> = Default::default();
+    let count = config.action_count;
+    let sender = {
+        let client = client.clone();
+        let config = config.clone();
+        let inflight = inflight.clone();
+        tokio::spawn(async move {
+            let mut next = time::Instant::now();
+            for i in 0..count {
+                time::sleep_until(next).await;
+                if rate > 0.0 {
+                    next += Duration::from_secs_f64(1.0 / rate);
+                }
+
+                let pub_id = super::publisher_id(i % config.publishers.max(1));
+                let action = Action {
+                    action_id: i.to_string(),
+                    kind: "process".to_owned(),
+                    name: "update_config".to_owned(),
+                    payload: "{}".to_owned(),
+                };
+                let payload = serde_json::to_vec(&action).unwrap();
+                inflight
+                    .lock()
+                    .unwrap()
+                    .insert(action.action_id, Instant::now());
+                let topic = actions_topic(&config, &pub_id);
+                if let Err(e) = client
+                    .publish(topic, QoS::AtLeastOnce, false, payload)
+                    .await
+                {
+                    error!("Id = {}, Couldn't send action = {:?}", id, e);
+                    break;
+                }
+            }
+        })
+    };
+    tokio::pin!(sender);
+
+    let mut stats = ActionStats::default();
+    let mut deadline = None;
+    while stats.completed + stats.failed < count as u64 {
+        let event = tokio::select! {
+            _ = &mut sender, if deadline.is_none() => {
+                deadline = Some(time::Instant::now() + COMPLETION_TIMEOUT);
+                continue;
+            }
+            _ = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)), if deadline.is_some() => {
+                break;
+            }
+            event = eventloop.poll() => event,
+        };
+
+        let publish = match event {
+            Ok(Event::Incoming(Incoming::Publish(publish))) => publish,
+            Ok(_) => continue,
+            Err(e) => {
+                error!("Id = {}, Connection error = {:?}", id, e);
+                break;
+            }
+        };
+
+        let statuses: Vec<ActionStatus> = match serde_json::from_slice(&publish.payload) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Id = {}, Invalid action status = {:?}", id, e);
+                continue;
+            }
+        };
+
+        for status in statuses.into_iter().filter(ActionStatus::is_done) {
+            let sent = match inflight.lock().unwrap().remove(&status.action_id) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            stats.latencies.record(sent.elapsed().as_millis() as u64);
+            if status.state == "Completed" {
+                stats.completed += 1;
+            } else {
+                stats.failed += 1;
+            }
+        }
+    }
+
+    METRICS.disconnected();
+    stats.timed_out = inflight.lock().unwrap().len() as u64;
+    stats.sent = stats.completed + stats.failed + stats.timed_out;
+    done.cancel();
+    Ok(stats)
+}
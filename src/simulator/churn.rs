@@ -0,0 +1,120 @@
+//! Devices going offline and back online. Time between disconnects and time
+//! spent offline are exponential, like independent network drops
+
+use rand::Rng;
+use tokio::time::Duration;
+
+use crate::{common::Latencies, report::Buffering, SimulatorConfig};
+
+/// When and how a device drops its connection
+pub struct Churn {
+    /// Mean seconds between disconnects
+    online: f64,
+    /// Mean seconds offline
+    offline: f64,
+    /// Share of abrupt disconnects
+    abrupt: f64,
+}
+
+impl Churn {
+    /// `None` without `--churn`
+    pub fn new(config: &SimulatorConfig) -> Option<Churn> {
+        let rate = config.churn.filter(|v| *v > 0.0)?;
+        Some(Churn {
+            online: 60.0 / rate,
+            offline: config.offline_time as f64 / 1000.0,
+            abrupt: config.abrupt_ratio.clamp(0.0, 1.0),
+        })
+    }
+
+    /// Time until the next disconnect
+    pub fn online_time(&self) -> Duration {
+        exponential(self.online)
+    }
+
+    pub fn offline_time(&self) -> Duration {
+        exponential(self.offline)
+    }
+
+    /// Whether the next disconnect skips the disconnect packet
+    pub fn abrupt(&self) -> bool {
+        rand::thread_rng().gen_bool(self.abrupt)
+    }
+}
+
+/// Longest wait, devices which churn slower hardly ever do
+const MAX_WAIT: Duration = Duration::from_secs(365 * 24 * 3600);
+
+fn exponential(mean: f64) -> Duration {
+    let u: f64 = rand::thread_rng().gen();
+    Duration::try_from_secs_f64(-mean * (1.0 - u).ln()).map_or(MAX_WAIT, |v| v.min(MAX_WAIT))
+}
+
+/// Disconnects and reconnects of devices
+#[derive(Debug, Default)]
+pub struct ChurnStats {
+    /// Disconnects with a disconnect packet
+    pub clean: u64,
+    /// Dropped connections, which trigger the last will
+    pub abrupt: u64,
+    pub reconnects: u64,
+    /// Reconnects which found their session on the broker
+    pub resumed_sessions: u64,
+    /// Failed connection attempts while coming back online
+    pub failed_reconnects: u64,
+    /// Publishes sent again after reconnects because they weren't acked
+    pub replayed: u64,
+    pub offline_ms: u64,
+    /// Records generated while offline
+    pub buffered: u64,
+    /// Records dropped from full offline buffers
+    pub dropped: u64,
+    /// Backlogs sent after reconnects
+    pub bursts: u64,
+    /// Records of the largest backlog
+    pub max_burst: u64,
+    /// Time to send backlogs in milliseconds
+    pub drain: Latencies,
+}
+
+impl ChurnStats {
+    pub fn merge(&mut self, other: &ChurnStats) {
+        self.clean += other.clean;
+        self.abrupt += other.abrupt;
+        self.reconnects += other.reconnects;
+        self.resumed_sessions += other.resumed_sessions;
+        self.failed_reconnects += other.failed_reconnects;
+        self.replayed += other.replayed;
+        self.offline_ms += other.offline_ms;
+        self.buffered += other.buffered;
+        self.dropped += other.dropped;
+        self.bursts += other.bursts;
+        self.max_burst = self.max_burst.max(other.max_burst);
+        self.drain.merge(&other.drain);
+    }
+
+    /// Offline buffering section of the report
+    pub fn buffering(&self) -> Buffering {
+        Buffering {
+            buffered: self.buffered,
+            dropped: self.dropped,
+            bursts: self.bursts,
+            max_burst: self.max_burst,
+            p50_drain: self.drain.percentile(50.0),
+            p99_drain: self.drain.percentile(99.0),
+            max_drain: self.drain.percentile(100.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_are_capped() {
+        // --churn 1e-300
+        assert_eq!(exponential(60.0 / 1e-300), MAX_WAIT);
+        assert!(exponential(1.0) < MAX_WAIT);
+    }
+}
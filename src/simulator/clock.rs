@@ -0,0 +1,66 @@
+//! Device clocks which are off. Ingestion has to cope with devices which
+//! are ahead or behind, drift, and resend or reorder records
+
+use rand::Rng;
+
+use crate::SimulatorConfig;
+
+/// Out of order records are stamped up to this long before the previous one
+const MAX_REORDER_MS: u64 = 1000;
+
+/// Bad clock of a device
+#[derive(Debug, Clone)]
+pub struct Clock {
+    /// Milliseconds ahead of the real time, negative is behind
+    skew: i64,
+    /// Parts per million the clock gains, negative loses
+    drift: f64,
+    /// Real time the clock started drifting at
+    start: u64,
+    /// Chance of a record stamped before the previous one
+    out_of_order: f64,
+    /// Chance of a record repeating the previous one
+    duplicates: f64,
+}
+
+impl Clock {
+    /// `--bad-clocks` percent of the devices get one, the others keep the
+    /// real time
+    pub fn new(config: &SimulatorConfig, now: u64) -> Option<Clock> {
+        let mut rng = rand::thread_rng();
+        if !rng.gen_bool(percent(config.bad_clocks)) {
+            return None;
+        }
+
+        Some(Clock {
+            skew: config.clock_skew,
+            drift: config.clock_drift,
+            start: now,
+            out_of_order: percent(config.out_of_order),
+            duplicates: percent(config.duplicates),
+        })
+    }
+
+    /// Timestamp of a record made at `now`, after one stamped `previous`
+    pub fn stamp(&self, now: u64, previous: Option<u64>) -> u64 {
+        let drift = now.saturating_sub(self.start) as f64 * self.drift / 1_000_000.0;
+        let timestamp = (now as i64 + self.skew + drift as i64).max(0) as u64;
+
+        let mut rng = rand::thread_rng();
+        match previous {
+            Some(previous) if rng.gen_bool(self.out_of_order) => {
+                previous.saturating_sub(rng.gen_range(1..=MAX_REORDER_MS))
+            }
+            _ => timestamp,
+        }
+    }
+
+    /// Whether the next record repeats the previous one
+    pub fn duplicate(&self) -> bool {
+        rand::thread_rng().gen_bool(self.duplicates)
+    }
+}
+
+fn percent(value: f64) -> f64 {
+    (value / 100.0).clamp(0.0, 1.0)
+}
@@ -0,0 +1,83 @@
+use clap::ValueEnum;
+use serde::de::IgnoredAny;
+use serde_json::Value;
+
+use super::proto::{self, Message};
+
+/// Wire format of the payloads
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Encoding {
+    /// Json array of records
+    #[default]
+    Json,
+    /// Cbor array of records
+    Cbor,
+    /// MessagePack array of records
+    Msgpack,
+    /// Protobuf batch with the records as `repeated <Device> records = 1`
+    Protobuf,
+}
+
+/// Encoding of the records of one stream
+#[derive(Debug, Clone)]
+pub enum Encoder {
+    Json,
+    Cbor,
+    Msgpack,
+    Protobuf(Message),
+}
+
+impl Encoder {
+    pub fn new(
+        encoding: Encoding,
+        messages: &[Message],
+        device: &str,
+    ) -> Result<Encoder, proto::Error> {
+        let encoder = match encoding {
+            Encoding::Json => Encoder::Json,
+            Encoding::Cbor => Encoder::Cbor,
+            Encoding::Msgpack => Encoder::Msgpack,
+            Encoding::Protobuf => Encoder::Protobuf(proto::find(messages, device)?),
+        };
+
+        Ok(encoder)
+    }
+
+    /// Payload of a batch of records
+    pub fn encode(&self, records: &[Value]) -> Vec<u8> {
+        match self {
+            Encoder::Json => serde_json::to_vec(records).unwrap(),
+            Encoder::Cbor => serde_cbor::to_vec(&records).unwrap(),
+            Encoder::Msgpack => rmp_serde::to_vec(&records).unwrap(),
+            Encoder::Protobuf(message) => message.encode(records),
+        }
+    }
+
+    /// Records of a payload, `None` if it doesn't decode
+    pub fn decode(&self, payload: &[u8]) -> Option<Vec<Value>> {
+        match self {
+            Encoder::Json => serde_json::from_slice(payload).ok(),
+            Encoder::Cbor => serde_cbor::from_slice(payload).ok(),
+            Encoder::Msgpack => rmp_serde::from_slice(payload).ok(),
+            Encoder::Protobuf(message) => message.decode(payload),
+        }
+    }
+}
+
+/// Records in a payload. Anything which doesn't decode counts as one
+pub fn records(encoding: Encoding, payload: &[u8]) -> usize {
+    let records = match encoding {
+        Encoding::Json => serde_json::from_slice::<Vec<IgnoredAny>>(payload)
+            .map(|v| v.len())
+            .ok(),
+        Encoding::Cbor => serde_cbor::from_slice::<Vec<IgnoredAny>>(payload)
+            .map(|v| v.len())
+            .ok(),
+        Encoding::Msgpack => rmp_serde::from_slice::<Vec<IgnoredAny>>(payload)
+            .map(|v| v.len())
+            .ok(),
+        Encoding::Protobuf => proto::records(payload),
+    };
+
+    records.unwrap_or(1)
+}
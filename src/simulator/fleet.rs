@@ -0,0 +1,277 @@
+//! Fleets mixing several kinds of devices, as weights of device types like
+//! `imu=60%,bms=30%,gps=10%` or a toml file of weighted profiles
+//!
+//! ```toml
+//! [[profile]]
+//! name = "scooter"
+//! weight = 70
+//! streams = ["imu:10", "gps:1"]
+//!
+//! [[profile]]
+//! weight = 30
+//! device = "bms"
+//! rate = 1
+//! ```
+
+use std::{fs, io};
+
+use serde::Deserialize;
+
+use super::StreamSpec;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error = {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Invalid fleet file = {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid fleet = {0}")]
+    Spec(String),
+    #[error("Fleet has no devices")]
+    Empty,
+}
+
+/// Devices of one kind in a fleet
+#[derive(Debug)]
+pub struct ProfileSpec {
+    /// Names of the devices when there's none
+    pub name: Option<String>,
+    /// Share of the publishers, relative to the other profiles
+    pub weight: f64,
+    pub streams: Vec<StreamSpec>,
+}
+
+/// Profile of a fleet file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileProfile {
+    name: Option<String>,
+    weight: f64,
+    /// Single stream device, at `rate` or `--rate-pub`
+    device: Option<String>,
+    rate: Option<f64>,
+    /// Several streams as `DEVICE:RATE`, like `--stream`
+    #[serde(default)]
+    streams: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FleetFile {
+    #[serde(rename = "profile")]
+    profiles: Vec<FileProfile>,
+}
+
+/// Profiles of `--fleet`, a list of `DEVICE=WEIGHT[%]` or a file. Single
+/// device profiles publish at `rate`
+pub fn load(fleet: &str, rate: f64) -> Result<Vec<ProfileSpec>, Error> {
+    let profiles = match fleet.contains('=') {
+        true => parse(fleet, rate)?,
+        false => read(fleet, rate)?,
+    };
+
+    if profiles.iter().all(|v| v.weight <= 0.0) {
+        return Err(Error::Empty);
+    }
+
+    Ok(profiles)
+}
+
+fn parse(fleet: &str, rate: f64) -> Result<Vec<ProfileSpec>, Error> {
+    let mut profiles = Vec::new();
+    for entry in fleet.split(',') {
+        let (device, weight) = entry
+            .split_once('=')
+            .ok_or_else(|| Error::Spec(format!("expected <device>=<weight> but got `{entry}`")))?;
+        let weight = weight.trim().trim_end_matches('%');
+        let weight = weight
+            .parse()
+            .map_err(|_| Error::Spec(format!("invalid weight `{weight}`")))?;
+        let device = device.trim().to_owned();
+        profiles.push(ProfileSpec {
+            name: None,
+            weight: check_weight(weight)?,
+            streams: vec![StreamSpec { device, rate }],
+        });
+    }
+
+    Ok(profiles)
+}
+
+fn read(path: &str, rate: f64) -> Result<Vec<ProfileSpec>, Error> {
+    let file: FleetFile = toml::from_str(&fs::read_to_string(path)?)?;
+    let mut profiles = Vec::new();
+    for profile in file.profiles {
+        let weight = check_weight(profile.weight)?;
+        if profile.rate.is_some() && !profile.streams.is_empty() {
+            let error = "streams have their own rates, `rate` is for a device".to_owned();
+            return Err(Error::Spec(error));
+        }
+
+        let streams = match (profile.device, profile.streams.is_empty()) {
+            (Some(device), true) => vec![StreamSpec {
+                device,
+                rate: profile.rate.unwrap_or(rate),
+            }],
+            (None, false) => profile
+                .streams
+                .iter()
+                .map(|v| v.parse())
+                .collect::<Result<_, _>>()
+                .map_err(Error::Spec)?,
+            _ => {
+                let error = "profiles need either a device or streams".to_owned();
+                return Err(Error::Spec(error));
+            }
+        };
+
+        profiles.push(ProfileSpec {
+            name: profile.name,
+            weight,
+            streams,
+        });
+    }
+
+    Ok(profiles)
+}
+
+fn check_weight(weight: f64) -> Result<f64, Error> {
+    match weight.is_finite() && weight >= 0.0 {
+        true => Ok(weight),
+        false => Err(Error::Spec(format!("invalid weight `{weight}`"))),
+    }
+}
+
+/// Profile of every publisher. Profiles are interleaved so that any first
+/// publishers follow the weights as closely as possible
+pub fn assign(weights: &[f64], publishers: usize) -> Vec<usize> {
+    let total: f64 = weights.iter().sum();
+    let mut assigned = vec![0usize; weights.len()];
+    let mut profiles = Vec::with_capacity(publishers);
+    for i in 0..publishers {
+        // profile furthest behind its share of the first i + 1 publishers
+        let behind = |p: usize| weights[p] / total * (i + 1) as f64 - assigned[p] as f64;
+        let profile = (0..weights.len())
+            .max_by(|a, b| behind(*a).total_cmp(&behind(*b)).then(b.cmp(a)))
+            .unwrap();
+        assigned[profile] += 1;
+        profiles.push(profile);
+    }
+
+    profiles
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn streams(profile: &ProfileSpec) -> Vec<(&str, f64)> {
+        profile
+            .streams
+            .iter()
+            .map(|v| (v.device.as_str(), v.rate))
+            .collect()
+    }
+
+    /// Profiles of a fleet file with these contents
+    fn read_file(name: &str, contents: &str) -> Result<Vec<ProfileSpec>, Error> {
+        let path = env::temp_dir().join(format!("mqttwrk-fleet-{}-{name}.toml", process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, contents).unwrap();
+        let profiles = load(path, 5.0);
+        fs::remove_file(path).unwrap();
+        profiles
+    }
+
+    #[test]
+    fn mixes_parse() {
+        let profiles = load("imu=60%, bms = 30%,gps=10", 2.0).unwrap();
+        let weights: Vec<f64> = profiles.iter().map(|v| v.weight).collect();
+        assert_eq!(weights, [60.0, 30.0, 10.0]);
+        assert_eq!(streams(&profiles[1]), [("bms", 2.0)]);
+        assert!(profiles.iter().all(|v| v.name.is_none()));
+
+        let error = |fleet| load(fleet, 1.0).unwrap_err();
+        assert!(matches!(error("imu=60,bms"), Error::Spec(_)));
+        assert!(matches!(error("imu=lots"), Error::Spec(_)));
+        assert!(matches!(error("imu=-1"), Error::Spec(_)));
+        assert!(matches!(error("imu=NaN"), Error::Spec(_)));
+        assert!(matches!(error("imu=0,gps=0"), Error::Empty));
+    }
+
+    #[test]
+    fn fleet_files_are_read() {
+        let contents = r#"
+            [[profile]]
+            name = "scooter"
+            weight = 70
+            streams = ["imu:10", "gps:1"]
+
+            [[profile]]
+            weight = 20
+            device = "bms"
+            rate = 1
+
+            [[profile]]
+            weight = 10
+            device = "gps"
+        "#;
+        let profiles = read_file("valid", contents).unwrap();
+        assert_eq!(profiles[0].name.as_deref(), Some("scooter"));
+        assert_eq!(profiles[0].weight, 70.0);
+        assert_eq!(streams(&profiles[0]), [("imu", 10.0), ("gps", 1.0)]);
+        assert_eq!(streams(&profiles[1]), [("bms", 1.0)]);
+        // --rate-pub
+        assert_eq!(streams(&profiles[2]), [("gps", 5.0)]);
+    }
+
+    #[test]
+    fn invalid_fleet_files_are_rejected() {
+        let invalid = [
+            (
+                "both",
+                "[[profile]]\nweight = 1\ndevice = \"bms\"\nstreams = [\"imu:1\"]",
+            ),
+            ("none", "[[profile]]\nweight = 1"),
+            (
+                "rates",
+                "[[profile]]\nweight = 1\nrate = 2\nstreams = [\"imu:1\"]",
+            ),
+            ("stream", "[[profile]]\nweight = 1\nstreams = [\"imu\"]"),
+            ("weight", "[[profile]]\nweight = -1\ndevice = \"bms\""),
+        ];
+        for (name, contents) in invalid {
+            let error = read_file(name, contents).unwrap_err();
+            assert!(matches!(error, Error::Spec(_)), "{}: {:?}", name, error);
+        }
+
+        let error = read_file("unknown", "[[profile]]\nweight = 1\nspeed = 2").unwrap_err();
+        assert!(matches!(error, Error::Toml(_)));
+        let error = read_file("empty", "[[profile]]\nweight = 0\ndevice = \"bms\"");
+        assert!(matches!(error.unwrap_err(), Error::Empty));
+        assert!(matches!(load("missing-fleet.toml", 1.0), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn profiles_are_interleaved_by_weight() {
+        // ties go to the first profile
+        assert_eq!(assign(&[3.0, 1.0], 4), [0, 0, 1, 0]);
+        assert_eq!(assign(&[1.0, 1.0], 4), [0, 1, 0, 1]);
+        assert_eq!(assign(&[0.0, 1.0], 3), [1, 1, 1]);
+        assert!(assign(&[1.0], 0).is_empty());
+
+        // every prefix is within a publisher of the weights
+        let weights = [60.0, 30.0, 10.0];
+        let profiles = assign(&weights, 1000);
+        for n in 1..=profiles.len() {
+            for (p, weight) in weights.iter().enumerate() {
+                let assigned = profiles[..n].iter().filter(|v| **v == p).count();
+                let share = weight / 100.0 * n as f64;
+                assert!((assigned as f64 - share).abs() < 1.0, "{} of {}", p, n);
+            }
+        }
+        assert_eq!(profiles.iter().filter(|v| **v == 2).count(), 100);
+    }
+}
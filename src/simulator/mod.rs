@@ -1,17 +1,49 @@
-use std::{fs, io, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use clap::ValueEnum;
 use futures::StreamExt;
 use indicatif::ProgressBar;
 use rumqttc::{MqttOptions, QoS, Transport};
-use tokio::{sync::Barrier, task};
+use tokio::sync::Barrier;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    common::{PubStats, Stats, SubStats, PROGRESS_STYLE},
-    SimulatorConfig,
+    common::{Latencies, PubStats, SubStats, PROGRESS_STYLE},
+    metrics,
+    report::{Report, Validation},
+    runtime::Shards,
+    DataType, SimulatorConfig,
 };
 
+mod actions;
+mod churn;
+mod clock;
+mod encoding;
+mod fleet;
+mod proto;
 mod publisher;
+mod schema;
+mod signal;
 mod subscriber;
+mod validation;
+
+pub use encoding::Encoding;
+pub use schema::Schema;
+
+use actions::ActionStats;
+use churn::ChurnStats;
+use clock::Clock;
+use encoding::Encoder;
+use validation::{Layouts, Validator};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ConnectionError {
@@ -25,12 +57,418 @@ pub enum ConnectionError {
     Client(#[from] rumqttc::ClientError),
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-pub(crate) async fn start(config: SimulatorConfig) {
+/// Invalid device types or payload layouts
+#[derive(thiserror::Error, Debug)]
+pub enum SetupError {
+    #[error("Schema error = {0}")]
+    Schema(#[from] schema::Error),
+    #[error("Proto error = {0}")]
+    Proto(#[from] proto::Error),
+    #[error("Fleet error = {0}")]
+    Fleet(#[from] fleet::Error),
+}
+
+/// Kind of device which publishers simulate
+#[derive(Clone)]
+pub enum Device {
+    Builtin(DataType),
+    Schema(Arc<Schema>),
+}
+
+impl Device {
+    /// `--schema` takes precedence over `--data-type`
+    pub fn from_config(config: &SimulatorConfig) -> Result<Device, schema::Error> {
+        match (&config.schema, config.data_type) {
+            (Some(path), _) => Device::load(path),
+            (None, Some(data_type)) => Ok(Device::Builtin(data_type)),
+            // clap requires one of both
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// Built in type by name or the schema at `path`
+    pub fn load(name: &str) -> Result<Device, schema::Error> {
+        match <DataType as ValueEnum>::from_str(name, true) {
+            Ok(data_type) => Ok(Device::Builtin(data_type)),
+            Err(_) => Ok(Device::Schema(Arc::new(Schema::load(name)?))),
+        }
+    }
+
+    /// Name of the device type, used for `{data_type}` in topics
+    pub fn name(&self) -> String {
+        match self {
+            Device::Builtin(data_type) => data_type.to_string(),
+            Device::Schema(schema) => schema.name.clone(),
+        }
+    }
+}
+
+/// Stream of `--stream`, e.g. `imu:10` for 10 messages per second or
+/// `my_device.json:0.5` for a schema every 2 seconds
+#[derive(Debug, Clone)]
+pub struct StreamSpec {
+    pub device: String,
+    /// Messages per second, 0 is no throttle
+    pub rate: f64,
+}
+
+impl FromStr for StreamSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (device, rate) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected <device>:<rate> but got `{s}`"))?;
+        let rate: f64 = rate.parse().map_err(|_| format!("invalid rate `{rate}`"))?;
+        if !rate.is_finite() || rate < 0.0 {
+            return Err(format!("invalid rate `{rate}`"));
+        }
+
+        Ok(StreamSpec {
+            device: device.to_owned(),
+            rate,
+        })
+    }
+}
+
+/// One kind of records a publisher sends on its own topic and rate
+#[derive(Clone)]
+pub struct Stream {
+    pub device: Device,
+    /// Messages per second, 0 is no throttle
+    pub rate: f64,
+    pub count: usize,
+    pub encoder: Encoder,
+}
+
+/// Devices of one kind in a fleet
+#[derive(Clone)]
+pub struct Profile {
+    pub name: String,
+    pub streams: Vec<Stream>,
+}
+
+impl Profile {
+    /// Profile of devices with these devices and rates, named after its
+    /// devices by default
+    fn new(
+        config: &SimulatorConfig,
+        name: Option<String>,
+        devices: Vec<(Device, f64)>,
+        messages: &[proto::Message],
+    ) -> Result<Profile, SetupError> {
+        let name = name.unwrap_or_else(|| {
+            let names: Vec<String> = devices.iter().map(|(v, _)| v.name()).collect();
+            names.join("+")
+        });
+
+        Ok(Profile {
+            name,
+            streams: streams(config, devices, messages)?,
+        })
+    }
+
+    /// Records a device of this kind publishes
+    pub fn count(&self) -> usize {
+        self.streams.iter().map(|v| v.count).sum()
+    }
+}
+
+/// Kinds of devices of a run and which publisher is which
+pub struct Fleet {
+    pub profiles: Vec<Profile>,
+    /// Profile of every publisher
+    pub publishers: Vec<usize>,
+}
+
+impl Fleet {
+    /// Profile of a publisher
+    pub fn profile(&self, publisher: usize) -> &Profile {
+        &self.profiles[self.publishers[publisher]]
+    }
+
+    /// Streams of all the kinds of devices
+    pub fn streams(&self) -> Vec<Stream> {
+        self.profiles
+            .iter()
+            .flat_map(|v| v.streams.iter().cloned())
+            .collect()
+    }
+}
+
+/// Profiles of `--fleet` over the publishers. Without it every publisher
+/// takes the `--stream`s, or a single stream of the device at `--rate-pub`
+pub fn fleet(config: &SimulatorConfig) -> Result<Fleet, SetupError> {
+    let messages = match config.encoding {
+        Encoding::Protobuf => proto::load(config.proto.as_deref())?,
+        _ => Vec::new(),
+    };
+
+    let specs = match &config.fleet {
+        Some(fleet) => fleet::load(fleet, config.rate_pub as f64)?,
+        None => {
+            let devices = match config.streams.is_empty() {
+                true => vec![(Device::from_config(config)?, config.rate_pub as f64)],
+                false => stream_devices(&config.streams)?,
+            };
+
+            return Ok(Fleet {
+                profiles: vec![Profile::new(config, None, devices, &messages)?],
+                publishers: vec![0; config.publishers],
+            });
+        }
+    };
+
+    let mut profiles = Vec::new();
+    for spec in specs.iter() {
+        let devices = stream_devices(&spec.streams)?;
+        let profile = Profile::new(config, spec.name.clone(), devices, &messages)?;
+        profiles.push(profile);
+    }
+
+    let weights: Vec<f64> = specs.iter().map(|v| v.weight).collect();
+    Ok(Fleet {
+        profiles,
+        publishers: fleet::assign(&weights, config.publishers),
+    })
+}
+
+fn stream_devices(specs: &[StreamSpec]) -> Result<Vec<(Device, f64)>, schema::Error> {
+    specs
+        .iter()
+        .map(|v| Ok((Device::load(&v.device)?, v.rate)))
+        .collect()
+}
+
+/// Streams of a device with these devices and rates. `-n` is the count of
+/// the fastest stream and slower streams send proportionally less, so that
+/// all of them span the same time
+fn streams(
+    config: &SimulatorConfig,
+    devices: Vec<(Device, f64)>,
+    messages: &[proto::Message],
+) -> Result<Vec<Stream>, SetupError> {
+    let rates: Vec<f64> = devices.iter().map(|(_, rate)| *rate).collect();
+    let counts = counts(&rates, config.count);
+    let mut streams = Vec::new();
+    for ((device, rate), count) in devices.into_iter().zip(counts) {
+        streams.push(Stream {
+            encoder: Encoder::new(config.encoding, messages, &device.name())?,
+            device,
+            rate,
+            count,
+        });
+    }
+
+    Ok(streams)
+}
+
+/// Records of streams at these rates when the fastest sends `count`
+fn counts(rates: &[f64], count: usize) -> Vec<usize> {
+    let throttled = rates.iter().all(|rate| *rate > 0.0);
+    let fastest = rates.iter().copied().fold(0.0, f64::max);
+    rates
+        .iter()
+        .map(|rate| match throttled {
+            true => (count as f64 * rate / fastest).ceil() as usize,
+            false => count,
+        })
+        .collect()
+}
+
+/// Device names and record counts of the streams of every publisher of a
+/// run with these `--stream`s or `--fleet`, without setting up encoders
+pub(crate) fn plan(
+    streams: &[StreamSpec],
+    fleet: Option<&str>,
+    publishers: usize,
+    count: usize,
+) -> Result<Vec<Vec<(String, usize)>>, SetupError> {
+    let planned = |specs: &[StreamSpec]| -> Result<Vec<(String, usize)>, SetupError> {
+        let devices = stream_devices(specs)?;
+        let rates: Vec<f64> = devices.iter().map(|(_, rate)| *rate).collect();
+        let names = devices.iter().map(|(device, _)| device.name());
+        Ok(names.zip(counts(&rates, count)).collect())
+    };
+
+    // single device profiles send `count` records at any rate
+    let specs = match fleet {
+        Some(fleet) => fleet::load(fleet, 0.0)?,
+        None => return Ok(vec![planned(streams)?; publishers]),
+    };
+
+    let profiles = specs
+        .iter()
+        .map(|v| planned(&v.streams))
+        .collect::<Result<Vec<_>, _>>()?;
+    let weights: Vec<f64> = specs.iter().map(|v| v.weight).collect();
+    Ok(fleet::assign(&weights, publishers)
+        .into_iter()
+        .map(|v| profiles[v].clone())
+        .collect())
+}
+
+/// Publishes and acks of one stream over all the publishers
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    pub outgoing_publish: u64,
+    /// Records in the outgoing publishes
+    pub records: u64,
+    /// Payload bytes of the outgoing publishes
+    pub bytes: u64,
+    pub acks: u64,
+    /// Publish to ack latencies in milliseconds
+    pub latencies: Latencies,
+}
+
+impl StreamStats {
+    pub fn merge(&mut self, other: &StreamStats) {
+        self.outgoing_publish += other.outgoing_publish;
+        self.records += other.records;
+        self.bytes += other.bytes;
+        self.acks += other.acks;
+        self.latencies.merge(&other.latencies);
+    }
+}
+
+/// Client stats along with the records in their publishes
+enum ClientStats {
+    PubStats(
+        PubStats,
+        BTreeMap<String, StreamStats>,
+        u64,
+        Box<ChurnStats>,
+    ),
+    SubStats(SubStats, u64, Option<Validation>),
+    /// Stats of the actions driver, unless it failed
+    Actions(Option<ActionStats>),
+}
+
+/// Generates the records of one device. Keeps the state of its signals, so
+/// that consecutive records follow each other
+pub struct Generator {
+    device: Device,
+    state: State,
+    /// Real time of the previous record
+    last: Option<u64>,
+    /// Clock of devices whose clock is off
+    clock: Option<Clock>,
+    /// Previous record and its timestamp
+    previous: Option<(serde_json::Value, u64)>,
+    /// Records which repeated the previous one. Sequences carry on from the
+    /// repeated record
+    repeated: usize,
+}
+
+enum State {
+    /// Independent random values
+    Random,
+    Builtin(publisher::Signals),
+    Schema(Vec<Option<signal::Signal>>),
+}
+
+impl Generator {
+    /// Built in types follow time series only with `signals`. Schemas
+    /// declare their signals per field. Records are stamped with `clock`
+    /// when there's one
+    pub fn new(device: Device, signals: bool, clock: Option<Clock>) -> Generator {
+        let state = match &device {
+            Device::Builtin(data_type) if signals => {
+                State::Builtin(publisher::Signals::new(*data_type))
+            }
+            Device::Builtin(_) => State::Random,
+            Device::Schema(schema) => State::Schema(schema.signals()),
+        };
+
+        Generator {
+            device,
+            state,
+            last: None,
+            clock,
+            previous: None,
+            repeated: 0,
+        }
+    }
+
+    /// Next record of the device, the `sequence`th one it sends
+    pub fn record(&mut self, sequence: usize) -> serde_json::Value {
+        if let (Some(clock), Some((record, _))) = (&self.clock, &self.previous) {
+            if clock.duplicate() {
+                self.repeated += 1;
+                return record.clone();
+            }
+        }
+
+        let sequence = sequence - self.repeated;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        // seconds since the previous record
+        let dt = match self.last {
+            Some(last) => now.saturating_sub(last) as f64 / 1000.0,
+            None => 0.0,
+        };
+        self.last = Some(now);
+
+        let timestamp = match &self.clock {
+            Some(clock) => clock.stamp(now, self.previous.as_ref().map(|(_, v)| *v)),
+            None => now,
+        };
+
+        let record = match (&self.device, &mut self.state) {
+            (Device::Schema(schema), State::Schema(signals)) => {
+                let record = schema.generate(sequence as u32, timestamp, signals, dt);
+                serde_json::Value::Object(record)
+            }
+            (_, State::Builtin(signals)) => signals.generate(sequence as u32, timestamp, dt),
+            (Device::Builtin(data_type), _) => {
+                publisher::generate_data(sequence, timestamp, *data_type)
+            }
+            (Device::Schema(_), _) => unreachable!(),
+        };
+
+        if self.clock.is_some() {
+            self.previous = Some((record.clone(), timestamp));
+        }
+
+        record
+    }
+}
+
+/// Client id of the `i`th publisher, which its topics and records are
+/// stored under
+pub(crate) fn publisher_id(i: usize) -> String {
+    format!("pub-{i:05}")
+}
+
+pub(crate) async fn start(config: SimulatorConfig, fleet: Fleet, shards: Shards) -> Report {
+    metrics::start(config.metrics_addr).await;
     let config = Arc::new(config);
+    let streams = fleet.streams();
+    // subscribers take all the streams when publishers have several
+    let mut names: Vec<String> = streams.iter().map(|v| v.device.name()).collect();
+    names.sort();
+    names.dedup();
+    let data_type = match names.len() {
+        1 => names.remove(0),
+        _ => "+".to_owned(),
+    };
+    // records of all the publishers
+    let count: usize = (0..config.publishers)
+        .map(|i| fleet.profile(i).count())
+        .sum();
     let mut handles = futures::stream::FuturesUnordered::new();
     let barrier_sub = Arc::new(Barrier::new(config.subscribers));
+    let layouts = Arc::new(Layouts::new(&streams, &config.topic_format));
     let barrier_pub = Arc::new(Barrier::new(config.publishers));
+    // devices handle actions until the driver is done with them
+    let actions_done = CancellationToken::new();
+    if config.send_actions.is_none() {
+        actions_done.cancel();
+    }
+    // records of offline buffers which were dropped, over all the devices
+    let dropped = Arc::new(AtomicUsize::new(0));
 
     let sub_bar = ProgressBar::new(config.subscribers as u64)
         .with_prefix("Subscribers Spawned:")
@@ -41,10 +479,21 @@ pub(crate) async fn start(config: SimulatorConfig) {
         let config = Arc::clone(&config);
         let id = format!("sub-{i:05}");
         let barrier_handle = barrier_sub.clone();
+        let validator = config.validate.then(|| Validator::new(layouts.clone()));
+        let dropped = dropped.clone();
         sub_bar.set_message(format!("spawning {id}"));
-        let mut subscriber = subscriber::Subscriber::new(id, config).await.unwrap();
-        handles.push(task::spawn(async move {
-            Stats::SubStats(subscriber.start(barrier_handle).await)
+        // connect on the shard which will drive this client
+        let mut subscriber = shards
+            .spawn(
+                i,
+                subscriber::Subscriber::new(id, config, data_type.clone(), count, validator),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        handles.push(shards.spawn(i, async move {
+            let (substats, records, validation) = subscriber.start(barrier_handle, dropped).await;
+            ClientStats::SubStats(substats, records, validation)
         }));
         sub_bar.inc(1);
     }
@@ -56,33 +505,78 @@ pub(crate) async fn start(config: SimulatorConfig) {
         .with_style((*PROGRESS_STYLE).clone());
 
     for i in 0..config.publishers {
+        // publishers take the shards after subscribers
+        let shard = config.subscribers + i;
         let config = Arc::clone(&config);
-        let id = format!("pub-{i:05}");
+        let id = publisher_id(i);
         let barrier_handle = barrier_pub.clone();
+        let actions_done = actions_done.clone();
+        let dropped = dropped.clone();
         pub_bar.set_message(format!("spawning {id}"));
-        let mut publisher = publisher::Publisher::new(id, config).await.unwrap();
-        handles.push(task::spawn(async move {
-            Stats::PubStats(publisher.start(barrier_handle).await)
+        // connect on the shard which will drive this client
+        let mut publisher = shards
+            .spawn(
+                shard,
+                publisher::Publisher::new(id, config, fleet.profile(i).streams.clone()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        handles.push(shards.spawn(shard, async move {
+            let (pubstats, streams, records, churn) =
+                publisher.start(barrier_handle, actions_done, dropped).await;
+            ClientStats::PubStats(pubstats, streams, records, Box::new(churn))
         }));
         pub_bar.inc(1);
     }
     pub_bar.finish_with_message("Done!");
 
+    // devices are subscribed to their actions by now
+    if let Some(rate) = config.send_actions {
+        let shard = config.subscribers + config.publishers;
+        let done = actions_done.clone();
+        let driver = actions::drive(config.clone(), rate, actions_done.clone());
+        handles.push(shards.spawn(shard, async move {
+            match driver.await {
+                Ok(stats) => ClientStats::Actions(Some(stats)),
+                // devices would wait on actions which never come
+                Err(e) => {
+                    error!("Actions driver error = {:?}", e);
+                    done.cancel();
+                    ClientStats::Actions(None)
+                }
+            }
+        }));
+    }
+
     let mut aggregate_substats = SubStats::default();
     let mut aggregate_pubstats = PubStats::default();
+    let mut aggregate_streams: BTreeMap<String, StreamStats> = BTreeMap::new();
+    let mut records_sent = 0;
+    let mut records_received = 0;
+    let mut action_stats = None;
+    let mut churn_stats = ChurnStats::default();
+    let mut aggregate_validation: Option<Validation> = None;
     // await and consume all futures
     while let Some(some_stat) = handles.next().await {
         match some_stat.unwrap() {
-            Stats::SubStats(substats) => {
-                aggregate_substats.publish_count += substats.publish_count;
-                aggregate_substats.puback_count += substats.puback_count;
-                aggregate_substats.reconnects += substats.reconnects;
-                aggregate_substats.throughput += substats.throughput;
+            ClientStats::Actions(stats) => action_stats = stats,
+            ClientStats::SubStats(substats, records, validation) => {
+                aggregate_substats.merge(&substats);
+                records_received += records;
+                if let Some(validation) = validation {
+                    aggregate_validation
+                        .get_or_insert_with(Validation::default)
+                        .merge(&validation);
+                }
             }
-            Stats::PubStats(pubstats) => {
-                aggregate_pubstats.outgoing_publish += pubstats.outgoing_publish;
-                aggregate_pubstats.throughput += pubstats.throughput;
-                aggregate_pubstats.reconnects += pubstats.reconnects;
+            ClientStats::PubStats(pubstats, streams, records, churn) => {
+                aggregate_pubstats.merge(&pubstats);
+                churn_stats.merge(&churn);
+                records_sent += records;
+                for (name, stats) in streams {
+                    aggregate_streams.entry(name).or_default().merge(&stats);
+                }
             }
         }
     }
@@ -91,6 +585,63 @@ pub(crate) async fn start(config: SimulatorConfig) {
         "Aggregate PubStats: {:#?}\nAggregate SubStats: {:#?}",
         &aggregate_pubstats, &aggregate_substats
     );
+
+    if fleet.profiles.len() > 1 {
+        let mut devices = vec![0; fleet.profiles.len()];
+        for profile in fleet.publishers.iter() {
+            devices[*profile] += 1;
+        }
+
+        let fleet: Vec<String> = fleet
+            .profiles
+            .iter()
+            .zip(devices)
+            .map(|(profile, devices)| format!("{} = {devices}", profile.name))
+            .collect();
+        println!("Fleet: {}", fleet.join(", "));
+    }
+
+    if aggregate_streams.len() > 1 {
+        for (name, stats) in aggregate_streams.iter() {
+            println!("Stream {name} PubStats: {stats:#?}");
+        }
+    }
+
+    println!("Records: sent = {records_sent}, received = {records_received}");
+    let bytes: u64 = aggregate_streams.values().map(|v| v.bytes).sum();
+    let records: u64 = aggregate_streams.values().map(|v| v.records).sum();
+    if records > 0 {
+        println!(
+            "Payload bytes ({:?}): {bytes}, {:.1} per record",
+            config.encoding,
+            bytes as f64 / records as f64
+        );
+    }
+
+    if config.churn.is_some() {
+        println!("Churn: {churn_stats:#?}");
+    }
+
+    if let Some(stats) = action_stats {
+        println!("Actions: {stats:#?}");
+    }
+
+    if let Some(validation) = &aggregate_validation {
+        println!("Validation: {validation:#?}");
+    }
+
+    // every subscriber receives records of all the publishers. Batched
+    // publishes carry several records, so the report counts records
+    let sent = count - dropped.load(Ordering::Relaxed);
+    let expected = config.subscribers * sent;
+    let mut report = Report::new(&aggregate_pubstats, &aggregate_substats, expected as u64);
+    report.sent = records_sent;
+    report.received = records_received;
+    report.validation = aggregate_validation;
+    if config.churn.is_some() && config.offline_buffer > 0 {
+        report.buffering = Some(churn_stats.buffering());
+    }
+    report
 }
 
 pub(crate) fn options(config: Arc<SimulatorConfig>, id: &str) -> io::Result<MqttOptions> {
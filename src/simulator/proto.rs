@@ -0,0 +1,671 @@
+//! Protobuf encoding of records, with the layout of every device read from
+//! a `.proto` file. Only flat messages of scalar fields are supported, which
+//! is what devices send
+
+use std::{fs, io};
+
+use serde_json::{Map, Number, Value};
+
+/// Layout of the built in device types
+const BUNDLED: &str = include_str!("devices.proto");
+
+/// Field number of the records in a batch
+const RECORDS: u32 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error = {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Invalid proto = {0}")]
+    Syntax(String),
+    #[error("Field {0} has unsupported type {1}")]
+    UnsupportedType(String, String),
+    #[error("No message for device {0}")]
+    NoMessage(String),
+}
+
+/// Message of one device type
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub name: String,
+    fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    name: String,
+    number: u32,
+    kind: Type,
+    repeated: bool,
+    /// Explicit presence, sent even with the default value
+    optional: bool,
+    /// Repeated numbers are sent in a single length delimited entry
+    packed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Double,
+    Float,
+    Int32,
+    Int64,
+    Uint32,
+    Uint64,
+    Sint32,
+    Sint64,
+    Fixed32,
+    Fixed64,
+    Sfixed32,
+    Sfixed64,
+    Bool,
+    String,
+    Bytes,
+}
+
+impl Type {
+    fn parse(name: &str) -> Option<Type> {
+        let kind = match name {
+            "double" => Type::Double,
+            "float" => Type::Float,
+            "int32" => Type::Int32,
+            "int64" => Type::Int64,
+            "uint32" => Type::Uint32,
+            "uint64" => Type::Uint64,
+            "sint32" => Type::Sint32,
+            "sint64" => Type::Sint64,
+            "fixed32" => Type::Fixed32,
+            "fixed64" => Type::Fixed64,
+            "sfixed32" => Type::Sfixed32,
+            "sfixed64" => Type::Sfixed64,
+            "bool" => Type::Bool,
+            "string" => Type::String,
+            "bytes" => Type::Bytes,
+            _ => return None,
+        };
+
+        Some(kind)
+    }
+
+    fn wire_type(self) -> u32 {
+        match self {
+            Type::Double | Type::Fixed64 | Type::Sfixed64 => 1,
+            Type::Float | Type::Fixed32 | Type::Sfixed32 => 5,
+            Type::String | Type::Bytes => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Messages of the `.proto` file at `path` followed by the ones of the built
+/// in types, so that the file can override them
+pub fn load(path: Option<&str>) -> Result<Vec<Message>, Error> {
+    let mut messages = match path {
+        Some(path) => parse(&fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+
+    messages.extend(parse(BUNDLED)?);
+    Ok(messages)
+}
+
+/// Message of a device type, matched by name ignoring case
+pub fn find(messages: &[Message], device: &str) -> Result<Message, Error> {
+    messages
+        .iter()
+        .find(|v| v.name.eq_ignore_ascii_case(device))
+        .cloned()
+        .ok_or_else(|| Error::NoMessage(device.to_owned()))
+}
+
+fn parse(source: &str) -> Result<Vec<Message>, Error> {
+    let tokens = tokenize(source);
+    let mut tokens = tokens.iter().map(String::as_str).peekable();
+    let mut messages = Vec::new();
+    // repeated numbers are only packed by default since proto3
+    let mut packed = true;
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "syntax" => {
+                expect(&mut tokens, "=")?;
+                packed = tokens.next() != Some("proto2");
+                skip_statement(&mut tokens);
+            }
+            "package" | "import" | "option" => skip_statement(&mut tokens),
+            "message" => {
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| syntax("message without a name"))?;
+                expect(&mut tokens, "{")?;
+                let mut fields = Vec::new();
+                loop {
+                    match tokens.next() {
+                        Some("}") => break,
+                        Some("option") | Some("reserved") => skip_statement(&mut tokens),
+                        Some(token @ ("message" | "enum" | "oneof" | "map")) => {
+                            return Err(syntax(&format!("{token} in {name} isn't supported")))
+                        }
+                        Some(token) => fields.push(field(token, packed, &mut tokens)?),
+                        None => return Err(syntax(&format!("{name} isn't closed"))),
+                    }
+                }
+
+                messages.push(Message {
+                    name: name.to_owned(),
+                    fields,
+                });
+            }
+            ";" => {}
+            token => return Err(syntax(&format!("unexpected `{token}`"))),
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Field declaration starting at `first`, e.g. `repeated double cells = 3;`.
+/// Repeated numbers are `packed` unless its options say otherwise
+fn field<'a, I: Iterator<Item = &'a str>>(
+    first: &'a str,
+    mut packed: bool,
+    tokens: &mut I,
+) -> Result<Field, Error> {
+    let (label, kind) = match first {
+        "optional" | "required" | "repeated" => (first, tokens.next()),
+        _ => ("", Some(first)),
+    };
+
+    let kind = kind.ok_or_else(|| syntax("field without a type"))?;
+    let name = tokens
+        .next()
+        .ok_or_else(|| syntax("field without a name"))?;
+    expect(tokens, "=")?;
+    let number = tokens
+        .next()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| syntax(&format!("field {name} without a number")))?;
+
+    // options like `[packed = false, deprecated = true]`, of which only
+    // packed changes the encoding
+    let mut next = tokens.next();
+    if next == Some("[") {
+        loop {
+            let option = tokens.next();
+            expect(tokens, "=")?;
+            let value = tokens.next();
+            if option == Some("packed") {
+                packed = match value {
+                    Some("true") => true,
+                    Some("false") => false,
+                    _ => return Err(syntax(&format!("field {name} has an invalid packed"))),
+                };
+            }
+
+            match tokens.next() {
+                Some(",") => continue,
+                Some("]") => break,
+                _ => return Err(syntax(&format!("field {name} has invalid options"))),
+            }
+        }
+        next = tokens.next();
+    }
+
+    if next != Some(";") {
+        return Err(syntax(&format!("field {name} isn't terminated")));
+    }
+
+    let kind = Type::parse(kind)
+        .ok_or_else(|| Error::UnsupportedType(name.to_owned(), kind.to_owned()))?;
+
+    Ok(Field {
+        name: name.to_owned(),
+        number,
+        kind,
+        repeated: label == "repeated",
+        optional: label == "optional" || label == "required",
+        packed,
+    })
+}
+
+/// Words, numbers, string literals and punctuation without the comments
+fn tokenize(source: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            '"' | '\'' => {
+                let mut literal = String::new();
+                for next in chars.by_ref() {
+                    if next == c {
+                        break;
+                    }
+                    literal.push(next);
+                }
+                tokens.push(literal);
+            }
+            c if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if !(next.is_alphanumeric() || next == '_' || next == '.') {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                tokens.push(word);
+            }
+            c if c.is_whitespace() => {}
+            c => tokens.push(c.to_string()),
+        }
+    }
+
+    tokens
+}
+
+fn skip_statement<'a, I: Iterator<Item = &'a str>>(tokens: &mut I) {
+    for token in tokens.by_ref() {
+        if token == ";" {
+            break;
+        }
+    }
+}
+
+fn expect<'a, I: Iterator<Item = &'a str>>(tokens: &mut I, expected: &str) -> Result<(), Error> {
+    match tokens.next() {
+        Some(token) if token == expected => Ok(()),
+        token => Err(syntax(&format!("expected `{expected}` but got {token:?}"))),
+    }
+}
+
+fn syntax(reason: &str) -> Error {
+    Error::Syntax(reason.to_owned())
+}
+
+impl Message {
+    /// Batch with every record as a `records` entry. Record keys without a
+    /// field are dropped and values are converted to the type of the field
+    pub fn encode(&self, records: &[Value]) -> Vec<u8> {
+        let mut batch = Vec::new();
+        let mut record = Vec::new();
+        for value in records {
+            record.clear();
+            if let Value::Object(map) = value {
+                self.encode_record(map, &mut record);
+            }
+
+            key(&mut batch, RECORDS, 2);
+            varint(&mut batch, record.len() as u64);
+            batch.extend_from_slice(&record);
+        }
+
+        batch
+    }
+
+    fn encode_record(&self, record: &Map<String, Value>, buf: &mut Vec<u8>) {
+        for field in self.fields.iter() {
+            let value = match record.get(&field.name) {
+                Some(Value::Null) | None => continue,
+                Some(value) => value,
+            };
+
+            match value {
+                Value::Array(values) if field.repeated => {
+                    if field.kind.wire_type() == 2 || !field.packed {
+                        for value in values {
+                            key(buf, field.number, field.kind.wire_type());
+                            scalar(buf, field.kind, value);
+                        }
+                    } else {
+                        let mut packed = Vec::new();
+                        for value in values {
+                            scalar(&mut packed, field.kind, value);
+                        }
+                        key(buf, field.number, 2);
+                        varint(buf, packed.len() as u64);
+                        buf.extend_from_slice(&packed);
+                    }
+                }
+                value => {
+                    if !field.optional && is_default(value) {
+                        continue;
+                    }
+
+                    key(buf, field.number, field.kind.wire_type());
+                    scalar(buf, field.kind, value);
+                }
+            }
+        }
+    }
+}
+
+/// Records in a protobuf batch, `None` if it's malformed
+pub fn records(payload: &[u8]) -> Option<usize> {
+    let entries = entries(payload)?;
+    Some(
+        entries
+            .iter()
+            .filter(|(number, _)| *number == RECORDS)
+            .count(),
+    )
+}
+
+/// Value of a field as it is on the wire
+#[derive(Debug, Clone, Copy)]
+enum Entry<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+/// Field numbers and values of a message, `None` if it's malformed
+fn entries(payload: &[u8]) -> Option<Vec<(u32, Entry<'_>)>> {
+    let mut entries = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let key = read_varint(&mut rest)?;
+        let entry = match key & 0x7 {
+            0 => Entry::Varint(read_varint(&mut rest)?),
+            1 => Entry::Fixed64(read_fixed64(&mut rest)?),
+            2 => {
+                let len = read_varint(&mut rest)? as usize;
+                Entry::Bytes(take(&mut rest, len)?)
+            }
+            5 => Entry::Fixed32(read_fixed32(&mut rest)?),
+            _ => return None,
+        };
+
+        entries.push(((key >> 3) as u32, entry));
+    }
+
+    Some(entries)
+}
+
+fn read_fixed64(buf: &mut &[u8]) -> Option<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(buf, 8)?);
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn read_fixed32(buf: &mut &[u8]) -> Option<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(take(buf, 4)?);
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if len > buf.len() {
+        return None;
+    }
+
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(head)
+}
+
+impl Message {
+    /// Records of a batch, `None` if it's malformed. Fields left out of a
+    /// record get their default value, like in any proto3 decoder
+    pub fn decode(&self, payload: &[u8]) -> Option<Vec<Value>> {
+        let mut records = Vec::new();
+        for (number, entry) in entries(payload)? {
+            match (number, entry) {
+                (RECORDS, Entry::Bytes(record)) => {
+                    records.push(Value::Object(self.decode_record(record)?))
+                }
+                (RECORDS, _) => return None,
+                _ => {}
+            }
+        }
+
+        Some(records)
+    }
+
+    fn decode_record(&self, payload: &[u8]) -> Option<Map<String, Value>> {
+        let mut record = Map::new();
+        for (number, entry) in entries(payload)? {
+            // unknown fields are skipped
+            let field = match self.fields.iter().find(|v| v.number == number) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            if !field.repeated {
+                record.insert(field.name.clone(), decode_scalar(field.kind, entry)?);
+                continue;
+            }
+
+            let values = record
+                .entry(field.name.clone())
+                .or_insert_with(|| Value::Array(Vec::new()));
+            let values = values.as_array_mut()?;
+            match entry {
+                Entry::Bytes(mut packed) if field.kind.wire_type() != 2 => {
+                    while !packed.is_empty() {
+                        let entry = match field.kind.wire_type() {
+                            0 => Entry::Varint(read_varint(&mut packed)?),
+                            1 => Entry::Fixed64(read_fixed64(&mut packed)?),
+                            _ => Entry::Fixed32(read_fixed32(&mut packed)?),
+                        };
+                        values.push(decode_scalar(field.kind, entry)?);
+                    }
+                }
+                entry => values.push(decode_scalar(field.kind, entry)?),
+            }
+        }
+
+        for field in self.fields.iter() {
+            if record.contains_key(&field.name) || (field.optional && !field.repeated) {
+                continue;
+            }
+
+            let default = match (field.repeated, field.kind) {
+                (true, _) => Value::Array(Vec::new()),
+                (_, Type::Bool) => Value::Bool(false),
+                (_, Type::String | Type::Bytes) => Value::String(String::new()),
+                (_, Type::Double | Type::Float) => Value::from(0.0),
+                _ => Value::from(0),
+            };
+            record.insert(field.name.clone(), default);
+        }
+
+        Some(record)
+    }
+}
+
+fn decode_scalar(kind: Type, entry: Entry) -> Option<Value> {
+    let value = match (kind, entry) {
+        (Type::Double, Entry::Fixed64(v)) => float(f64::from_bits(v)),
+        (Type::Float, Entry::Fixed32(v)) => float(f32::from_bits(v) as f64),
+        (Type::Int32 | Type::Int64, Entry::Varint(v)) => Value::from(v as i64),
+        (Type::Uint32 | Type::Uint64, Entry::Varint(v)) => Value::from(v),
+        (Type::Sint32 | Type::Sint64, Entry::Varint(v)) => {
+            Value::from((v >> 1) as i64 ^ -((v & 1) as i64))
+        }
+        (Type::Bool, Entry::Varint(v)) => Value::Bool(v != 0),
+        (Type::Fixed32, Entry::Fixed32(v)) => Value::from(v),
+        (Type::Sfixed32, Entry::Fixed32(v)) => Value::from(v as i32),
+        (Type::Fixed64, Entry::Fixed64(v)) => Value::from(v),
+        (Type::Sfixed64, Entry::Fixed64(v)) => Value::from(v as i64),
+        (Type::String, Entry::Bytes(v)) => Value::String(String::from_utf8(v.to_vec()).ok()?),
+        (Type::Bytes, Entry::Bytes(v)) => Value::String(String::from_utf8_lossy(v).into_owned()),
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+fn float(v: f64) -> Value {
+    Number::from_f64(v).map_or(Value::Null, Value::Number)
+}
+
+/// Zero, false and empty strings are left out of proto3 messages
+fn is_default(value: &Value) -> bool {
+    match value {
+        Value::Bool(v) => !v,
+        Value::Number(v) => v.as_f64() == Some(0.0),
+        Value::String(v) => v.is_empty(),
+        _ => false,
+    }
+}
+
+fn scalar(buf: &mut Vec<u8>, kind: Type, value: &Value) {
+    let float = match value {
+        Value::Number(v) => v.as_f64().unwrap_or_default(),
+        Value::Bool(v) => *v as u8 as f64,
+        _ => 0.0,
+    };
+    let int = match value {
+        Value::Number(v) => v.as_i64().unwrap_or(float.round() as i64),
+        _ => float as i64,
+    };
+
+    match kind {
+        Type::Double => buf.extend_from_slice(&float.to_le_bytes()),
+        Type::Float => buf.extend_from_slice(&(float as f32).to_le_bytes()),
+        Type::Int32 | Type::Int64 => varint(buf, int as u64),
+        Type::Uint32 | Type::Uint64 | Type::Bool => varint(buf, int.max(0) as u64),
+        Type::Sint32 | Type::Sint64 => varint(buf, ((int << 1) ^ (int >> 63)) as u64),
+        Type::Fixed32 => buf.extend_from_slice(&(int as u32).to_le_bytes()),
+        Type::Sfixed32 => buf.extend_from_slice(&(int as i32).to_le_bytes()),
+        Type::Fixed64 => buf.extend_from_slice(&(int as u64).to_le_bytes()),
+        Type::Sfixed64 => buf.extend_from_slice(&int.to_le_bytes()),
+        Type::String | Type::Bytes => {
+            let text = match value {
+                Value::String(v) => v.clone(),
+                v => v.to_string(),
+            };
+            varint(buf, text.len() as u64);
+            buf.extend_from_slice(text.as_bytes());
+        }
+    }
+}
+
+fn key(buf: &mut Vec<u8>, number: u32, wire_type: u32) {
+    varint(buf, ((number << 3) | wire_type) as u64);
+}
+
+fn varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut v = 0;
+    for (i, byte) in buf.iter().enumerate().take(10) {
+        v |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Some(v);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{simulator::publisher, DataType};
+
+    #[test]
+    fn builtin_types_round_trip() {
+        let messages = load(None).unwrap();
+        for data_type in [DataType::Imu, DataType::Bms, DataType::Gps] {
+            let message = find(&messages, &data_type.to_string()).unwrap();
+            // the first record has a default sequence, which is left out
+            let batch: Vec<Value> = (0..3)
+                .map(|i| publisher::generate_data(i, 1_700_000_000_000 + i as u64, data_type))
+                .collect();
+
+            let payload = message.encode(&batch);
+            assert_eq!(records(&payload), Some(3));
+            assert_eq!(message.decode(&payload).unwrap(), batch, "{}", data_type);
+        }
+    }
+
+    #[test]
+    fn repeated_numbers_follow_packed() {
+        let proto = r#"
+            syntax = "proto3";
+            message Pack {
+                repeated double cells = 1;
+                repeated sint32 deltas = 2 [packed = false];
+                repeated uint32 flags = 3 [deprecated = true, packed = true];
+                repeated string tags = 4;
+            }
+        "#;
+        let message = find(&parse(proto).unwrap(), "pack").unwrap();
+        let record = json!({
+            "cells": [1.5, 2.5],
+            "deltas": [-1, 2],
+            "flags": [1, 300],
+            "tags": ["a", "b"],
+        });
+
+        let batch = [record];
+        let payload = message.encode(&batch);
+        let (_, entry) = entries(&payload).unwrap()[0];
+        let fields = match entry {
+            Entry::Bytes(v) => entries(v).unwrap(),
+            v => panic!("expected a record, got {:?}", v),
+        };
+        let numbers: Vec<u32> = fields.iter().map(|(number, _)| *number).collect();
+        assert_eq!(numbers, [1, 2, 2, 3, 4, 4]);
+        assert!(matches!(fields[0].1, Entry::Bytes(v) if v.len() == 16));
+        assert!(matches!(fields[1].1, Entry::Varint(1)));
+        assert!(matches!(fields[2].1, Entry::Varint(4)));
+        assert!(matches!(fields[3].1, Entry::Bytes(v) if v.len() == 3));
+
+        assert_eq!(message.decode(&payload).unwrap(), batch);
+    }
+
+    #[test]
+    fn proto2_numbers_are_unpacked() {
+        let proto = "syntax = 'proto2'; message Pack { repeated int32 a = 1; repeated int32 b = 2 [packed=true]; }";
+        let message = find(&parse(proto).unwrap(), "pack").unwrap();
+        let record = json!({"a": [1, 2], "b": [3, 4]});
+
+        let batch = [record];
+        let payload = message.encode(&batch);
+        let (_, entry) = entries(&payload).unwrap()[0];
+        let fields = match entry {
+            Entry::Bytes(v) => entries(v).unwrap(),
+            v => panic!("expected a record, got {:?}", v),
+        };
+        let numbers: Vec<u32> = fields.iter().map(|(number, _)| *number).collect();
+        assert_eq!(numbers, [1, 1, 2]);
+        assert_eq!(message.decode(&payload).unwrap(), batch);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        let proto = "message Pack { repeated int32 a = 1 [packed = maybe]; }";
+        assert!(matches!(parse(proto), Err(Error::Syntax(_))));
+        let proto = "message Pack { repeated int32 a = 1 [packed = true; }";
+        assert!(matches!(parse(proto), Err(Error::Syntax(_))));
+    }
+}
@@ -1,20 +1,42 @@
 use std::{
-    fs, io,
-    sync::Arc,
+    collections::{BTreeMap, VecDeque},
+    fs, io, mem,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use fake::{Dummy, Fake, Faker};
 use hdrhistogram::Histogram;
-use rumqttc::{AsyncClient, Event, EventLoop, Incoming, MqttOptions, Outgoing, QoS, Transport};
+use rand::Rng;
+use rumqttc::{
+    AsyncClient, ClientError, Event, EventLoop, Incoming, LastWill, MqttOptions, Outgoing, Publish,
+    QoS, Request, Transport,
+};
 use serde::Serialize;
+use serde_json::Value;
 use tokio::{
-    sync::Barrier,
+    sync::{watch, Barrier, OwnedMutexGuard},
     task,
     time::{self, Duration},
 };
+use tokio_util::sync::CancellationToken;
 
-use crate::{bench::ConnectionError, simulator::PubStats, DataType, SimulatorConfig};
+use crate::{
+    bench::ConnectionError,
+    common::{self, Latencies},
+    metrics::METRICS,
+    simulator::{
+        actions::{self, Action, ActionStatus},
+        churn::{Churn, ChurnStats},
+        clock::Clock,
+        signal::{Battery, Track, Walk, Wave},
+        Encoder, Generator, PubStats, Stream, StreamStats,
+    },
+    DataType, SimulatorConfig,
+};
 
 #[derive(Debug, Serialize, Dummy)]
 struct Imu {
@@ -139,6 +161,7 @@ struct Bms {
 pub struct Publisher {
     id: String,
     config: Arc<SimulatorConfig>,
+    streams: Vec<Stream>,
     client: AsyncClient,
     eventloop: EventLoop,
 }
@@ -147,11 +170,9 @@ impl Publisher {
     pub(crate) async fn new(
         id: String,
         config: Arc<SimulatorConfig>,
+        streams: Vec<Stream>,
     ) -> Result<Publisher, ConnectionError> {
-        let (client, mut eventloop) = AsyncClient::new(options(config.clone(), &id)?, 10);
-        eventloop
-            .network_options
-            .set_connection_timeout(config.conn_timeout);
+        let (client, mut eventloop) = connection(&config, &id)?;
 
         loop {
             let event = match eventloop.poll().await {
@@ -169,6 +190,7 @@ impl Publisher {
                 match v {
                     Incoming::ConnAck(_) => {
                         // println!("{id} connected");
+                        METRICS.connected();
                         break;
                     }
                     incoming => return Err(ConnectionError::WrongPacket(incoming)),
@@ -176,31 +198,115 @@ impl Publisher {
             }
         }
 
+        if config.actions {
+            client
+                .subscribe(actions::actions_topic(&config, &id), QoS::AtLeastOnce)
+                .await?;
+
+            // waiting for subscription confirmation
+            loop {
+                if let Event::Incoming(v) = eventloop.poll().await? {
+                    match v {
+                        Incoming::SubAck(_) => break,
+                        incoming => return Err(ConnectionError::WrongPacket(incoming)),
+                    }
+                }
+            }
+        }
+
         Ok(Publisher {
             id,
             config,
+            streams,
             client,
             eventloop,
         })
     }
 
-    pub async fn start(&mut self, barrier_handle: Arc<Barrier>) -> PubStats {
+    /// Replaces the connection with one which isn't connected yet. The
+    /// broker sees the old one closing, after a disconnect packet or not.
+    /// Returns the unacked publishes, which the new connection sends first.
+    /// Moves the state and pending requests of rumqttc's eventloop, which
+    /// is why its version is pinned
+    fn go_offline(&mut self, outbox: &Outbox) -> io::Result<usize> {
+        let (client, mut eventloop) = connection(&self.config, &self.id)?;
+        // packet ids carry on in the new connection
+        mem::swap(&mut eventloop.state, &mut self.eventloop.state);
+        let (replays, publishes) = replays(eventloop.state.clean(), &mut self.eventloop.pending);
+        eventloop.pending = replays.into_iter();
+
+        self.eventloop = eventloop;
+        *outbox.client.lock().unwrap() = client.clone();
+        self.client = client;
+        METRICS.disconnected();
+        Ok(publishes)
+    }
+
+    /// Publishes all the records. Devices which handle actions keep doing so
+    /// after that, until `actions_done` is cancelled. Records dropped from
+    /// full offline buffers are added to `dropped`
+    pub async fn start(
+        &mut self,
+        barrier_handle: Arc<Barrier>,
+        actions_done: CancellationToken,
+        dropped: Arc<AtomicUsize>,
+    ) -> (PubStats, BTreeMap<String, StreamStats>, u64, ChurnStats) {
         let qos = get_qos(self.config.publish_qos);
         let inflight = self.config.max_inflight;
-        let count = self.config.count;
-        let rate = self.config.rate_pub;
+        let count: usize = self.streams.iter().map(|v| v.count).sum();
         let id = self.id.clone();
 
         let start = Instant::now();
+        // records expected to be acked. The synchronization publish of QoS 0
+        // counts as one
         let mut acks_expected = count;
         let mut outgoing_elapsed = Duration::from_secs(0);
         let mut acks_count = 0;
-        let data_type = self.config.data_type;
-        let data_type_str = self.config.data_type.to_string();
 
         let topic = self.config.topic_format.replacen("{pub_id}", &self.id, 1);
-        let topic = topic.replacen("{data_type}", &data_type_str, 1);
-        let client = self.client.clone();
+        // all the streams of a device share its clock
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let clock = Clock::new(&self.config, now);
+        let schedules: Vec<Schedule> = self
+            .streams
+            .iter()
+            .map(|stream| Schedule {
+                topic: topic.replacen("{data_type}", &stream.device.name(), 1),
+                generator: Generator::new(
+                    stream.device.clone(),
+                    self.config.signals,
+                    clock.clone(),
+                ),
+                encoder: stream.encoder.clone(),
+                rate: stream.rate,
+                count: stream.count,
+                batch: Vec::new(),
+                batch_start: None,
+            })
+            .collect();
+        let batch = Batching {
+            size: self.config.batch_size.max(1),
+            timeout: self.config.batch_timeout.map(Duration::from_millis),
+        };
+        let names: Vec<String> = self.streams.iter().map(|v| v.device.name()).collect();
+        let mut stream_stats: Vec<StreamStats> = names.iter().map(|_| Default::default()).collect();
+        // publishes wait while the device is offline
+        let (online, online_rx) = watch::channel(true);
+        let outbox = Outbox {
+            client: Arc::new(Mutex::new(self.client.clone())),
+            pending: Default::default(),
+            order: Default::default(),
+            online: online_rx,
+            backlog: Arc::new(Mutex::new(Backlog::new(
+                self.config.offline_buffer,
+                dropped,
+            ))),
+        };
+        let status_topic = topic.replacen("{data_type}", actions::STATUS_STREAM, 1);
+        let status_sequence = Arc::new(AtomicU32::new(0));
 
         let wait = barrier_handle.wait();
         tokio::pin!(wait);
@@ -219,10 +325,9 @@ impl Publisher {
         // If publish count is 0, don't publish. This is an idle connection
         // which can be used to test pings
         if count != 0 {
-            // delay between messages in milliseconds
-            let delay = if rate == 0 { 0 } else { 1000 / rate };
+            let outbox = outbox.clone();
             task::spawn(async move {
-                requests(topic, count, client, qos, delay, data_type).await;
+                requests(schedules, batch, outbox, qos).await;
             });
         } else {
             // Just keep this connection alive
@@ -235,14 +340,93 @@ impl Publisher {
         }
 
         let mut reconnects: u64 = 0;
-        let mut latencies: Vec<Option<Instant>> = vec![None; inflight as usize + 1];
+        let mut latencies: Vec<Option<(Instant, Pending)>> = vec![None; inflight as usize + 1];
+        // records in the acked publishes
+        let mut records = 0;
         let mut histogram = Histogram::<u64>::new(4).unwrap();
 
+        // done publishing, idle connections only handle actions
+        let mut done = count == 0;
+
+        let churn = Churn::new(&self.config);
+        let mut churn_stats = ChurnStats::default();
+        let mut link = Link::Online;
+        let mut disconnect_at = churn
+            .as_ref()
+            .map(|v| time::Instant::now() + v.online_time());
+        let mut offline_since = time::Instant::now();
+        // holds off publishes between draining and going offline
+        let mut order: Option<OwnedMutexGuard<()>> = None;
+        // unacked publishes of the previous connection, sent again first
+        let mut replays = 0;
+
         loop {
-            let event = match self.eventloop.poll().await {
-                Ok(v) => v,
-                Err(e) => {
+            if let Link::Draining { .. } = link {
+                // queued publishes have to go out on this connection, and
+                // clean disconnects wait for their acks
+                let unacked = latencies[1..].iter().any(Option::is_some);
+                if order.is_none() && outbox.pending.lock().unwrap().is_empty() {
+                    order = outbox.order.clone().try_lock_owned().ok();
+                }
+
+                let client = &self.client;
+                let next = match order {
+                    Some(_) => link.drained(unacked, || client.try_disconnect().is_ok()),
+                    None => None,
+                };
+                match next {
+                    Some(Link::Disconnecting) => churn_stats.clean += 1,
+                    Some(_) => churn_stats.abrupt += 1,
+                    None => {}
+                }
+                link = next.unwrap_or(link);
+            }
+
+            if let (Link::Dropped, Some(churn)) = (link, &churn) {
+                match self.go_offline(&outbox) {
+                    Ok(v) => replays = v,
+                    Err(e) => {
+                        error!("Id = {}, Couldn't reconnect = {:?}", self.id, e);
+                        break;
+                    }
+                }
+                order = None;
+                offline_since = time::Instant::now();
+                link = Link::Offline(offline_since + churn.offline_time());
+            }
+
+            let wake = match link {
+                Link::Online => disconnect_at,
+                Link::Offline(until) => Some(until),
+                _ => None,
+            };
+
+            let event = tokio::select! {
+                _ = actions_done.cancelled(), if done && self.config.actions => break,
+                _ = time::sleep_until(wake.unwrap_or_else(time::Instant::now)), if wake.is_some() => {
+                    if link == Link::Online {
+                        online.send_replace(false);
+                    }
+                    link = link.wake(churn.as_ref().is_some_and(Churn::abrupt));
+                    continue;
+                }
+                event = self.eventloop.poll(), if !matches!(link, Link::Offline(_)) => event,
+            };
+
+            let retry = time::Instant::now() + Duration::from_secs(1);
+            let event = match (event, link.failed(retry)) {
+                (Ok(v), _) => v,
+                (Err(e), Some(next)) => {
+                    if link == Link::Reconnecting {
+                        debug!("Id = {}, Reconnect failed = {:?}", self.id, e);
+                        churn_stats.failed_reconnects += 1;
+                    }
+                    link = next;
+                    continue;
+                }
+                (Err(e), None) => {
                     error!("Id = {}, Connection error = {:?}", self.id, e);
+                    METRICS.reconnected();
                     reconnects += 1;
                     if reconnects >= 1 {
                         break;
@@ -255,17 +439,65 @@ impl Publisher {
             debug!("Id = {}, {:?}, count {}", self.id, event, acks_count);
             match event {
                 Event::Incoming(v) => match v {
+                    Incoming::ConnAck(ack) if link == Link::Reconnecting => {
+                        METRICS.connected();
+                        churn_stats.reconnects += 1;
+                        churn_stats.offline_ms += offline_since.elapsed().as_millis() as u64;
+                        if ack.session_present {
+                            churn_stats.resumed_sessions += 1;
+                        } else if self.config.actions {
+                            let client = self.client.clone();
+                            let topic = actions::actions_topic(&self.config, &self.id);
+                            task::spawn(
+                                async move { client.subscribe(topic, QoS::AtLeastOnce).await },
+                            );
+                        }
+
+                        link = Link::Online;
+                        disconnect_at = churn
+                            .as_ref()
+                            .map(|v| time::Instant::now() + v.online_time());
+                        // records keep going to the backlog until it's sent
+                        if outbox.backlog.lock().unwrap().start_flush() {
+                            let outbox = outbox.clone();
+                            task::spawn(async move { outbox.flush().await });
+                        }
+                        online.send_replace(true);
+                    }
+                    Incoming::SubAck(_) => {}
                     Incoming::PubAck(ack) => {
-                        acks_count += 1;
-                        let elapsed = match latencies[ack.pkid as usize] {
-                            Some(instant) => instant.elapsed(),
+                        METRICS.acked();
+                        let (elapsed, pending) = match latencies[ack.pkid as usize].take() {
+                            Some((instant, pending)) => (instant.elapsed(), pending),
                             None => {
                                 warn!("Id = {}, Unsolicited PubAck", ack.pkid);
                                 continue;
                             }
                         };
+                        METRICS.latency(elapsed.as_millis() as u64);
+                        match pending {
+                            Pending::Records(sent) => {
+                                records += sent.records;
+                                let stats = &mut stream_stats[sent.stream];
+                                stats.acks += 1;
+                                stats.latencies.record(elapsed.as_millis() as u64);
+                            }
+                            Pending::Sync => records += 1,
+                            // action statuses aren't records
+                            Pending::Status => continue,
+                        }
+                        acks_count += 1;
                         histogram.record(elapsed.as_millis() as u64).unwrap();
                     }
+                    Incoming::Publish(publish) if self.config.actions => {
+                        let outbox = outbox.clone();
+                        let topic = status_topic.clone();
+                        let sequence = status_sequence.clone();
+                        let delay = Duration::from_millis(self.config.action_delay);
+                        task::spawn(async move {
+                            reply(outbox, topic, qos, publish, delay, sequence).await;
+                        });
+                    }
                     Incoming::PingResp => {
                         debug!("ping response")
                     }
@@ -274,8 +506,26 @@ impl Publisher {
                         break;
                     }
                 },
+                // replays keep the send time of their first attempt
+                Event::Outgoing(Outgoing::Publish(_)) if replays > 0 => {
+                    METRICS.published();
+                    churn_stats.replayed += 1;
+                    replays -= 1;
+                }
+                Event::Outgoing(Outgoing::Disconnect) if link == Link::Disconnecting => {
+                    link = Link::Dropped;
+                }
                 Event::Outgoing(Outgoing::Publish(pkid)) => {
-                    latencies[pkid as usize] = Some(Instant::now());
+                    METRICS.published();
+                    let pending = outbox.pending.lock().unwrap().pop_front();
+                    let pending = pending.unwrap_or(Pending::Status);
+                    if let Pending::Records(sent) = pending {
+                        let stats = &mut stream_stats[sent.stream];
+                        stats.outgoing_publish += 1;
+                        stats.records += sent.records as u64;
+                        stats.bytes += sent.bytes as u64;
+                    }
+                    latencies[pkid as usize] = Some((Instant::now(), pending));
                 }
                 Event::Outgoing(Outgoing::PingReq) => {
                     debug!("ping request")
@@ -283,13 +533,33 @@ impl Publisher {
                 _ => (),
             }
 
-            if acks_count >= acks_expected {
+            // records dropped while offline are never acked. QoS 0 only
+            // waits for the synchronization publish
+            let dropped = match qos {
+                QoS::AtMostOnce => 0,
+                _ => outbox.backlog.lock().unwrap().stats.dropped as usize,
+            };
+            if records + dropped >= acks_expected && !done {
                 outgoing_elapsed = start.elapsed();
+                done = true;
+            }
+
+            if done && !self.config.actions && count != 0 {
                 break;
             }
         }
 
-        let outgoing_throughput = (count * 1000) as f32 / outgoing_elapsed.as_millis() as f32;
+        // if publish_qos is 0 assume we send all publishes
+        if self.config.publish_qos == 0 {
+            acks_count = stream_stats
+                .iter()
+                .map(|v| v.outgoing_publish as usize)
+                .sum();
+            records = count - outbox.backlog.lock().unwrap().stats.dropped as usize;
+        }
+
+        METRICS.disconnected();
+        let outgoing_throughput = common::throughput(acks_count as u64, outgoing_elapsed);
 
         if self.config.show_pub_stat {
             println!(
@@ -320,44 +590,42 @@ impl Publisher {
             );
         }
 
-        // if publish_qos is 0 assume we send all publishes
-        if self.config.publish_qos == 0 {
-            acks_count = self.config.count;
-        }
-
-        PubStats {
+        let pubstats = PubStats {
             outgoing_publish: acks_count as u64,
             throughput: outgoing_throughput,
             reconnects,
+            latencies: Latencies(histogram),
+        };
+
+        let mut streams = BTreeMap::new();
+        for (name, stats) in names.into_iter().zip(stream_stats) {
+            streams
+                .entry(name)
+                .or_insert_with(StreamStats::default)
+                .merge(&stats);
         }
+
+        churn_stats.merge(&outbox.backlog.lock().unwrap().stats);
+        if churn.is_some() && self.config.show_pub_stat {
+            println!("Id = {}, {:?}", self.id, churn_stats);
+        }
+
+        (pubstats, streams, records as u64, churn_stats)
     }
 }
 
-fn generate_data(sequence: usize, data_type: DataType) -> String {
-    let payload: String = match data_type {
-        DataType::Gps => {
-            let fake_data = vec![dummy_gps(sequence as u32)];
-            serde_json::to_string(&fake_data).unwrap()
-        }
-        DataType::Imu => {
-            let fake_data = vec![dummy_imu(sequence as u32)];
-            serde_json::to_string(&fake_data).unwrap()
-        }
-        DataType::Bms => {
-            let fake_data = vec![dummy_bms(sequence as u32)];
-            serde_json::to_string(&fake_data).unwrap()
-        }
+pub(super) fn generate_data(sequence: usize, timestamp: u64, data_type: DataType) -> Value {
+    let sequence = sequence as u32;
+    let record = match data_type {
+        DataType::Gps => serde_json::to_value(dummy_gps(sequence, timestamp)),
+        DataType::Imu => serde_json::to_value(dummy_imu(sequence, timestamp)),
+        DataType::Bms => serde_json::to_value(dummy_bms(sequence, timestamp)),
     };
 
-    payload
+    record.unwrap()
 }
 
-fn dummy_imu(sequence: u32) -> Imu {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-
+fn dummy_imu(sequence: u32, timestamp: u64) -> Imu {
     Imu {
         sequence,
         timestamp,
@@ -365,11 +633,7 @@ fn dummy_imu(sequence: u32) -> Imu {
     }
 }
 
-fn dummy_bms(sequence: u32) -> Bms {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
+fn dummy_bms(sequence: u32, timestamp: u64) -> Bms {
     Bms {
         sequence,
         timestamp,
@@ -377,12 +641,7 @@ fn dummy_bms(sequence: u32) -> Bms {
     }
 }
 
-fn dummy_gps(sequence: u32) -> Gps {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-
+fn dummy_gps(sequence: u32, timestamp: u64) -> Gps {
     Gps {
         sequence,
         timestamp,
@@ -390,46 +649,583 @@ fn dummy_gps(sequence: u32) -> Gps {
     }
 }
 
-/// make count number of requests at specified QoS.
-async fn requests(
+/// Per device state of the built in types, so that their records follow
+/// each other instead of being independent noise
+pub(super) enum Signals {
+    Imu(Box<ImuSignals>),
+    Bms(Box<BmsSignals>),
+    Gps(Track),
+}
+
+impl Signals {
+    pub(super) fn new(data_type: DataType) -> Signals {
+        let mut rng = rand::thread_rng();
+        match data_type {
+            DataType::Imu => Signals::Imu(Box::new(ImuSignals::new(&mut rng))),
+            DataType::Bms => Signals::Bms(Box::new(BmsSignals::new(&mut rng))),
+            DataType::Gps => Signals::Gps(Track::new(&mut rng)),
+        }
+    }
+
+    /// Record `dt` seconds after the previous one
+    pub(super) fn generate(&mut self, sequence: u32, timestamp: u64, dt: f64) -> Value {
+        let mut rng = rand::thread_rng();
+        match self {
+            Signals::Imu(imu) => serde_json::to_value(imu.next(&mut rng, sequence, timestamp, dt)),
+            Signals::Bms(bms) => serde_json::to_value(bms.next(&mut rng, sequence, timestamp, dt)),
+            Signals::Gps(track) => {
+                track.next(&mut rng, dt);
+                let gps = Gps {
+                    sequence,
+                    timestamp,
+                    latitude: track.latitude,
+                    longitude: track.longitude,
+                };
+                serde_json::to_value(gps)
+            }
+        }
+        .unwrap()
+    }
+}
+
+/// Vibrating vehicle driving over a road with gradual turns
+pub(super) struct ImuSignals {
+    ax: Walk,
+    ay: Walk,
+    az: Wave,
+    pitch: Walk,
+    roll: Walk,
+    yaw: Walk,
+    magx: Walk,
+    magy: Walk,
+    magz: Walk,
+}
+
+impl ImuSignals {
+    fn new<R: Rng>(rng: &mut R) -> ImuSignals {
+        ImuSignals {
+            ax: Walk::new(rng, 1.0, 2.8, 0.2),
+            ay: Walk::new(rng, 1.0, 2.8, 0.2),
+            az: Wave::new(rng, 9.79, 9.82, 0.5, 0.3),
+            pitch: Walk::new(rng, 0.8, 1.0, 0.01),
+            roll: Walk::new(rng, 0.8, 1.0, 0.01),
+            yaw: Walk::new(rng, 0.8, 1.0, 0.01),
+            magx: Walk::new(rng, -45.0, -15.0, 0.5),
+            magy: Walk::new(rng, -45.0, -15.0, 0.5),
+            magz: Walk::new(rng, -45.0, -15.0, 0.5),
+        }
+    }
+
+    fn next<R: Rng>(&mut self, rng: &mut R, sequence: u32, timestamp: u64, dt: f64) -> Imu {
+        Imu {
+            sequence,
+            timestamp,
+            ax: self.ax.next(rng, dt),
+            ay: self.ay.next(rng, dt),
+            az: self.az.next(rng, dt),
+            pitch: self.pitch.next(rng, dt),
+            roll: self.roll.next(rng, dt),
+            yaw: self.yaw.next(rng, dt),
+            magx: self.magx.next(rng, dt),
+            magy: self.magy.next(rng, dt),
+            magz: self.magz.next(rng, dt),
+        }
+    }
+}
+
+/// 16 cell pack draining with the load, warming up with the current and
+/// following the daily ambient temperature
+pub(super) struct BmsSignals {
+    battery: Battery,
+    /// Manufacturing spread of each cell from the pack voltage curve
+    cell_offsets: [f64; 16],
+    cell_temps: [Walk; 8],
+    mosfet_temperature: Walk,
+    ambient_temperature: Wave,
+    soh: f64,
+}
+
+impl BmsSignals {
+    fn new<R: Rng>(rng: &mut R) -> BmsSignals {
+        let mut cell_offsets = [0.0; 16];
+        for offset in cell_offsets.iter_mut() {
+            *offset = rng.gen_range(-0.01..=0.01);
+        }
+
+        BmsSignals {
+            battery: Battery::new(rng, 30.0, 15.0, 20.0),
+            cell_offsets,
+            cell_temps: [(); 8].map(|_| Walk::new(rng, 40.0, 43.0, 0.05)),
+            mosfet_temperature: Walk::new(rng, 40.0, 45.0, 0.05),
+            ambient_temperature: Wave::new(rng, 35.0, 40.0, 24.0 * 3600.0, 0.02),
+            soh: rng.gen_range(9.5..=9.9),
+        }
+    }
+
+    fn next<R: Rng>(&mut self, rng: &mut R, sequence: u32, timestamp: u64, dt: f64) -> Bms {
+        let current = self.battery.next(rng, dt);
+        let voltage = self.battery.cell_voltage();
+        let cells = self.cell_offsets.map(|offset| voltage + offset);
+        let pack_voltage: f64 = cells.iter().sum();
+        let mut cell_temps = [0.0; 8];
+        for (temp, walk) in cell_temps.iter_mut().zip(self.cell_temps.iter_mut()) {
+            *temp = walk.next(rng, dt);
+        }
+
+        Bms {
+            sequence,
+            timestamp,
+            periodicity_ms: 250,
+            mosfet_temperature: self.mosfet_temperature.next(rng, dt),
+            ambient_temperature: self.ambient_temperature.next(rng, dt),
+            mosfet_status: 1,
+            cell_voltage_count: 16,
+            cell_voltage_1: cells[0],
+            cell_voltage_2: cells[1],
+            cell_voltage_3: cells[2],
+            cell_voltage_4: cells[3],
+            cell_voltage_5: cells[4],
+            cell_voltage_6: cells[5],
+            cell_voltage_7: cells[6],
+            cell_voltage_8: cells[7],
+            cell_voltage_9: cells[8],
+            cell_voltage_10: cells[9],
+            cell_voltage_11: cells[10],
+            cell_voltage_12: cells[11],
+            cell_voltage_13: cells[12],
+            cell_voltage_14: cells[13],
+            cell_voltage_15: cells[14],
+            cell_voltage_16: cells[15],
+            cell_thermistor_count: 8,
+            cell_temp_1: cell_temps[0],
+            cell_temp_2: cell_temps[1],
+            cell_temp_3: cell_temps[2],
+            cell_temp_4: cell_temps[3],
+            cell_temp_5: cell_temps[4],
+            cell_temp_6: cell_temps[5],
+            cell_temp_7: cell_temps[6],
+            cell_temp_8: cell_temps[7],
+            cell_balancing_status: 1,
+            pack_voltage,
+            pack_current: current,
+            pack_soc: self.battery.soc * 100.0,
+            pack_soh: self.soh,
+            pack_sop: self.soh,
+            pack_cycle_count: self.battery.cycles as i64,
+            pack_available_energy: (self.battery.soc * self.battery.capacity * pack_voltage) as i64,
+            pack_consumed_energy: (self.battery.consumed * pack_voltage) as i64,
+            pack_fault: 0,
+            pack_status: 1,
+        }
+    }
+}
+
+/// Stream index, record count and payload size of a publish
+#[derive(Debug, Clone, Copy)]
+struct Sent {
+    stream: usize,
+    records: usize,
+    bytes: usize,
+}
+
+/// What a publish carries, to attribute its ack
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    Records(Sent),
+    /// Last publish of QoS 0 runs, acked once all the others are out
+    Sync,
+    /// Progress of an action
+    Status,
+}
+
+impl Pending {
+    fn records(&self) -> usize {
+        match self {
+            Pending::Records(sent) => sent.records,
+            _ => 0,
+        }
+    }
+}
+
+/// Client which queues what every publish carries, in the order the
+/// eventloop sends them
+#[derive(Clone)]
+struct Outbox {
+    /// Client of the current connection
+    client: Arc<Mutex<AsyncClient>>,
+    pending: Arc<Mutex<VecDeque<Pending>>>,
+    /// Keeps the queue in order when action replies and records are
+    /// published concurrently
+    order: Arc<tokio::sync::Mutex<()>>,
+    /// Whether the device is online
+    online: watch::Receiver<bool>,
+    backlog: Arc<Mutex<Backlog>>,
+}
+
+impl Outbox {
+    /// Records go to the backlog while the device is offline and while an
+    /// earlier backlog is being sent, so that they stay in order
+    async fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        payload: Vec<u8>,
+        pending: Pending,
+    ) -> Result<(), ClientError> {
+        if !matches!(pending, Pending::Status) {
+            let mut backlog = self.backlog.lock().unwrap();
+            if backlog.cap > 0 && (backlog.flushing || !*self.online.borrow()) {
+                backlog.push(Buffered {
+                    topic: topic.to_owned(),
+                    qos,
+                    payload,
+                    pending,
+                });
+                return Ok(());
+            }
+        }
+
+        self.send(topic, qos, payload, pending).await
+    }
+
+    /// Sends the backlog as fast as inflight allows, along with the records
+    /// buffered meanwhile
+    async fn flush(self) {
+        let start = Instant::now();
+        let mut burst = 0;
+        loop {
+            let buffered = match self.backlog.lock().unwrap().pop() {
+                Some(v) => v,
+                None => break,
+            };
+
+            burst += buffered.pending.records();
+            let Buffered {
+                topic,
+                qos,
+                payload,
+                pending,
+            } = buffered;
+            if let Err(_e) = self.send(&topic, qos, payload, pending).await {
+                return;
+            }
+        }
+
+        let stats = &mut self.backlog.lock().unwrap().stats;
+        stats.bursts += 1;
+        stats.max_burst = stats.max_burst.max(burst as u64);
+        stats.drain.record(start.elapsed().as_millis() as u64);
+    }
+
+    /// Publishes once the device is online
+    async fn send(
+        &self,
+        topic: &str,
+        qos: QoS,
+        payload: Vec<u8>,
+        pending: Pending,
+    ) -> Result<(), ClientError> {
+        let mut online = self.online.clone();
+        while !*online.borrow_and_update() {
+            if online.changed().await.is_err() {
+                break;
+            }
+        }
+
+        let _order = self.order.lock().await;
+        self.pending.lock().unwrap().push_back(pending);
+        let client = self.client.lock().unwrap().clone();
+        client.publish(topic, qos, false, payload).await
+    }
+}
+
+/// Publish kept while offline
+struct Buffered {
     topic: String,
-    count: usize,
-    client: AsyncClient,
     qos: QoS,
-    delay: u64,
-    data_type: DataType,
+    payload: Vec<u8>,
+    pending: Pending,
+}
+
+/// Publishes a device keeps while offline, sent in a burst when it's back
+struct Backlog {
+    /// Records kept at most, 0 doesn't buffer
+    cap: usize,
+    publishes: VecDeque<Buffered>,
+    /// Records in `publishes`
+    records: usize,
+    /// Backlog is being sent
+    flushing: bool,
+    /// Dropped records of all the devices, which subscribers don't wait for
+    dropped: Arc<AtomicUsize>,
+    stats: ChurnStats,
+}
+
+impl Backlog {
+    fn new(cap: usize, dropped: Arc<AtomicUsize>) -> Backlog {
+        Backlog {
+            cap,
+            publishes: VecDeque::new(),
+            records: 0,
+            flushing: false,
+            dropped,
+            stats: ChurnStats::default(),
+        }
+    }
+
+    /// Drops the oldest records when full
+    fn push(&mut self, buffered: Buffered) {
+        let records = buffered.pending.records();
+        self.records += records;
+        self.stats.buffered += records as u64;
+        self.publishes.push_back(buffered);
+
+        while self.records > self.cap {
+            let dropped = match self.publishes.pop_front() {
+                Some(v) => v.pending.records(),
+                None => break,
+            };
+            self.records -= dropped;
+            self.stats.dropped += dropped as u64;
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether there's a backlog to send and nothing sends it yet
+    fn start_flush(&mut self) -> bool {
+        let start = !self.flushing && !self.publishes.is_empty();
+        self.flushing |= start;
+        start
+    }
+
+    /// Next publish to send, done flushing once there's none
+    fn pop(&mut self) -> Option<Buffered> {
+        let buffered = self.publishes.pop_front();
+        match &buffered {
+            Some(v) => self.records -= v.pending.records(),
+            None => self.flushing = false,
+        }
+
+        buffered
+    }
+}
+
+/// Connection of a device under churn
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Link {
+    Online,
+    /// Publishes are held off until the queued ones are out
+    Draining {
+        abrupt: bool,
+    },
+    /// Disconnect packet is queued
+    Disconnecting,
+    /// Connection is to be replaced
+    Dropped,
+    Offline(time::Instant),
+    /// Waiting for the ConnAck of the new connection
+    Reconnecting,
+}
+
+impl Link {
+    /// Next state once the online or offline time is up
+    fn wake(self, abrupt: bool) -> Link {
+        match self {
+            Link::Online => Link::Draining { abrupt },
+            _ => Link::Reconnecting,
+        }
+    }
+
+    /// Next state of a draining link once the queued publishes are out,
+    /// `None` while a clean disconnect waits for acks. `disconnect` queues
+    /// the disconnect packet and tells whether it could
+    fn drained(self, unacked: bool, disconnect: impl FnOnce() -> bool) -> Option<Link> {
+        match self {
+            Link::Draining { abrupt: false } if unacked => None,
+            Link::Draining { abrupt: false } if disconnect() => Some(Link::Disconnecting),
+            Link::Draining { .. } => Some(Link::Dropped),
+            _ => None,
+        }
+    }
+
+    /// Next state after a connection error, `None` when the error is fatal.
+    /// Failed reconnects try again at `retry`
+    fn failed(self, retry: time::Instant) -> Option<Link> {
+        match self {
+            Link::Reconnecting => Some(Link::Offline(retry)),
+            // broker closing the connection after the disconnect packet
+            Link::Disconnecting => Some(Link::Dropped),
+            _ => None,
+        }
+    }
+}
+
+/// Requests a new connection sends first: the unacked ones in packet id
+/// order, then the ones still queued from an earlier replay. Returns them
+/// with the number of publishes among them
+fn replays(unacked: Vec<Request>, queued: impl Iterator<Item = Request>) -> (Vec<Request>, usize) {
+    let mut replays = unacked;
+    replays.extend(queued);
+    let publishes = replays
+        .iter()
+        .filter(|v| matches!(v, Request::Publish(_)))
+        .count();
+    (replays, publishes)
+}
+
+/// Acts on an action like a device would. Reports it as received right
+/// away, halfway through the delay and as completed after the delay
+async fn reply(
+    outbox: Outbox,
+    topic: String,
+    qos: QoS,
+    publish: Publish,
+    delay: Duration,
+    sequence: Arc<AtomicU32>,
 ) {
-    let mut interval = match delay {
-        0 => None,
-        delay => Some(time::interval(time::Duration::from_millis(delay))),
+    let action: Action = match serde_json::from_slice(&publish.payload) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Invalid action on {} = {:?}", publish.topic, e);
+            return;
+        }
     };
 
-    for i in 0..count {
-        let payload = generate_data(i, data_type);
-        if let Some(interval) = &mut interval {
-            interval.tick().await;
+    let steps = [
+        (Duration::ZERO, "Received", 0),
+        (delay / 2, "Running", 50),
+        (delay, "Completed", 100),
+    ];
+    let start = time::Instant::now();
+    for (at, state, progress) in steps {
+        time::sleep_until(start + at).await;
+        let status = ActionStatus {
+            sequence: sequence.fetch_add(1, Ordering::Relaxed) + 1,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            action_id: action.action_id.clone(),
+            state: state.to_owned(),
+            progress,
+            errors: Vec::new(),
+        };
+
+        let payload = serde_json::to_vec(&[status]).unwrap();
+        if let Err(_e) = outbox.publish(&topic, qos, payload, Pending::Status).await {
+            return;
+        }
+    }
+}
+
+/// Topic, rate and records of one stream of a publisher
+struct Schedule {
+    topic: String,
+    generator: Generator,
+    encoder: Encoder,
+    /// Records per second, 0 is no throttle
+    rate: f64,
+    count: usize,
+    /// Records waiting to be published
+    batch: Vec<Value>,
+    /// When the first record of the batch was generated
+    batch_start: Option<time::Instant>,
+}
+
+/// Records per publish and how long a record can wait for its batch to fill
+#[derive(Clone, Copy)]
+struct Batching {
+    size: usize,
+    timeout: Option<Duration>,
+}
+
+/// make count number of records of every stream and publish them in batches
+/// at specified QoS. Streams are interleaved by when their next record or
+/// batch timeout is due
+async fn requests(mut schedules: Vec<Schedule>, batching: Batching, outbox: Outbox, qos: QoS) {
+    let now = time::Instant::now();
+    let mut due = vec![now; schedules.len()];
+    let mut sent = vec![0; schedules.len()];
+
+    loop {
+        // earliest of the next records and batch timeouts. false is a record
+        let mut next: Option<(time::Instant, bool, usize)> = None;
+        for (i, schedule) in schedules.iter().enumerate() {
+            if sent[i] < schedule.count {
+                next = earliest(next, (due[i], false, i));
+            }
+
+            if let (Some(start), Some(timeout)) = (schedule.batch_start, batching.timeout) {
+                next = earliest(next, (start + timeout, true, i));
+            }
+        }
+
+        let (at, timed_out, i) = match next {
+            Some(v) => v,
+            None => break,
+        };
+
+        time::sleep_until(at).await;
+        let schedule = &mut schedules[i];
+        if !timed_out {
+            schedule.batch.push(schedule.generator.record(sent[i]));
+            schedule.batch_start.get_or_insert_with(time::Instant::now);
+            sent[i] += 1;
+            due[i] = if schedule.rate > 0.0 {
+                due[i] + Duration::from_secs_f64(1.0 / schedule.rate)
+            } else {
+                // unthrottled streams take turns
+                time::Instant::now()
+            };
+
+            if schedule.batch.len() < batching.size && sent[i] < schedule.count {
+                continue;
+            }
         }
 
+        let payload = schedule.encoder.encode(&schedule.batch);
+        let published = Sent {
+            stream: i,
+            records: schedule.batch.len(),
+            bytes: payload.len(),
+        };
+        schedule.batch.clear();
+        schedule.batch_start = None;
+
         // These errors are usually due to eventloop task being dead. We can ignore the
         // error here as the failed eventloop task would have already printed an error
-        if let Err(_e) = client.publish(topic.as_str(), qos, false, payload).await {
+        let published = Pending::Records(published);
+        if let Err(_e) = outbox
+            .publish(&schedule.topic, qos, payload, published)
+            .await
+        {
             break;
         }
 
-        info!("published {}", i);
+        info!("published {} records of {}", sent[i], schedule.topic);
     }
 
     if qos == QoS::AtMostOnce {
-        let payload = generate_data(count, data_type);
-        if let Err(_e) = client
-            .publish(topic.as_str(), QoS::AtLeastOnce, false, payload)
-            .await
-        {
+        let schedule = &mut schedules[0];
+        let record = schedule.generator.record(schedule.count);
+        let payload = schedule.encoder.encode(&[record]);
+        // synchronization publish doesn't belong to any stream's stats
+        let sync = outbox
+            .publish(&schedule.topic, QoS::AtLeastOnce, payload, Pending::Sync)
+            .await;
+        if let Err(_e) = sync {
             // TODO
         }
     }
 }
 
+fn earliest<T: PartialOrd>(current: Option<T>, candidate: T) -> Option<T> {
+    match current {
+        Some(current) if current <= candidate => Some(current),
+        _ => Some(candidate),
+    }
+}
+
 /// get QoS level. Default is AtLeastOnce.
 fn get_qos(qos: i16) -> QoS {
     match qos {
@@ -440,10 +1236,28 @@ fn get_qos(qos: i16) -> QoS {
     }
 }
 
+/// Client and eventloop of a device, not connected until polled
+fn connection(config: &Arc<SimulatorConfig>, id: &str) -> io::Result<(AsyncClient, EventLoop)> {
+    let (client, mut eventloop) = AsyncClient::new(options(config.clone(), id)?, 10);
+    eventloop
+        .network_options
+        .set_connection_timeout(config.conn_timeout);
+
+    Ok((client, eventloop))
+}
+
 fn options(config: Arc<SimulatorConfig>, id: &str) -> io::Result<MqttOptions> {
     let mut options = MqttOptions::new(id, &config.server, config.port);
     options.set_keep_alive(Duration::from_secs(config.keep_alive));
     options.set_inflight(config.max_inflight);
+    options.set_clean_session(!config.persistent);
+
+    if config.churn.is_some() {
+        let topic = config.will_topic.replacen("{pub_id}", id, 1);
+        let payload = serde_json::json!({ "id": id, "state": "offline" });
+        let will = LastWill::new(topic, payload.to_string(), QoS::AtLeastOnce, false);
+        options.set_last_will(will);
+    }
 
     if let Some(ca_file) = &config.ca_file {
         let ca = fs::read(ca_file)?;
@@ -452,3 +1266,214 @@ fn options(config: Arc<SimulatorConfig>, id: &str) -> io::Result<MqttOptions> {
 
     Ok(options)
 }
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::{MqttState, PubRel};
+
+    use super::*;
+
+    fn publish(pkid: u16) -> Request {
+        let mut publish = Publish::new("a", QoS::AtLeastOnce, vec![]);
+        publish.pkid = pkid;
+        Request::Publish(publish)
+    }
+
+    fn pkids(requests: &[Request]) -> Vec<u16> {
+        requests
+            .iter()
+            .map(|v| match v {
+                Request::Publish(v) => v.pkid,
+                Request::PubRel(v) => v.pkid,
+                v => panic!("unexpected request {:?}", v),
+            })
+            .collect()
+    }
+
+    /// Publish of `records` records, told apart by `stream`
+    fn buffered(stream: usize, records: usize) -> Buffered {
+        let sent = Sent {
+            stream,
+            records,
+            bytes: 0,
+        };
+        Buffered {
+            topic: "a".to_owned(),
+            qos: QoS::AtLeastOnce,
+            payload: vec![],
+            pending: Pending::Records(sent),
+        }
+    }
+
+    fn streams(pending: &VecDeque<Pending>) -> Vec<usize> {
+        pending
+            .iter()
+            .map(|v| match v {
+                Pending::Records(sent) => sent.stream,
+                v => panic!("unexpected publish {:?}", v),
+            })
+            .collect()
+    }
+
+    /// Outbox of an offline device, which is set online with the sender.
+    /// The eventloop is never polled, it keeps the requests of the client
+    fn outbox(cap: usize) -> (Outbox, watch::Sender<bool>, EventLoop, Arc<AtomicUsize>) {
+        let options = MqttOptions::new("test", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 100);
+        let (online, online_rx) = watch::channel(false);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let outbox = Outbox {
+            client: Arc::new(Mutex::new(client)),
+            pending: Default::default(),
+            order: Default::default(),
+            online: online_rx,
+            backlog: Arc::new(Mutex::new(Backlog::new(cap, dropped.clone()))),
+        };
+        (outbox, online, eventloop, dropped)
+    }
+
+    async fn publish_records(outbox: &Outbox, stream: usize, records: usize) {
+        let Buffered {
+            topic,
+            qos,
+            payload,
+            pending,
+        } = buffered(stream, records);
+        outbox.publish(&topic, qos, payload, pending).await.unwrap();
+    }
+
+    #[test]
+    fn backlogs_drop_the_oldest_records() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut backlog = Backlog::new(5, dropped.clone());
+        backlog.push(buffered(0, 2));
+        backlog.push(buffered(1, 2));
+        assert_eq!(backlog.records, 4);
+        assert_eq!(backlog.stats.dropped, 0);
+
+        // 6 records don't fit, the oldest publish goes
+        backlog.push(buffered(2, 2));
+        assert_eq!(backlog.records, 4);
+        assert_eq!(backlog.stats.buffered, 6);
+        assert_eq!(backlog.stats.dropped, 2);
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+        // a publish larger than the buffer drops itself too
+        backlog.push(buffered(3, 6));
+        assert_eq!(backlog.records, 0);
+        assert_eq!(backlog.stats.buffered, 12);
+        assert_eq!(backlog.stats.dropped, 12);
+        assert_eq!(dropped.load(Ordering::Relaxed), 12);
+        assert!(backlog.publishes.is_empty());
+    }
+
+    #[test]
+    fn backlogs_flush_once() {
+        let mut backlog = Backlog::new(10, Arc::new(AtomicUsize::new(0)));
+        assert!(!backlog.start_flush());
+
+        backlog.push(buffered(0, 1));
+        backlog.push(buffered(1, 1));
+        assert!(backlog.start_flush());
+        assert!(!backlog.start_flush());
+
+        let order: Vec<usize> = std::iter::from_fn(|| backlog.pop())
+            .map(|v| match v.pending {
+                Pending::Records(sent) => sent.stream,
+                v => panic!("unexpected publish {:?}", v),
+            })
+            .collect();
+        assert_eq!(order, [0, 1]);
+        assert_eq!(backlog.records, 0);
+        assert!(!backlog.flushing);
+    }
+
+    #[tokio::test]
+    async fn offline_records_are_sent_in_a_burst() {
+        let (outbox, online, _eventloop, dropped) = outbox(3);
+        for stream in 0..4 {
+            publish_records(&outbox, stream, 1).await;
+        }
+        assert!(outbox.pending.lock().unwrap().is_empty());
+
+        // back online, records published during the burst queue behind it
+        // and share the buffer
+        online.send_replace(true);
+        assert!(outbox.backlog.lock().unwrap().start_flush());
+        publish_records(&outbox, 4, 1).await;
+        outbox.clone().flush().await;
+
+        assert_eq!(streams(&outbox.pending.lock().unwrap()), [2, 3, 4]);
+        {
+            let backlog = outbox.backlog.lock().unwrap();
+            assert!(!backlog.flushing);
+            assert_eq!(backlog.stats.buffered, 5);
+            assert_eq!(backlog.stats.dropped, 2);
+            assert_eq!(backlog.stats.bursts, 1);
+            assert_eq!(backlog.stats.max_burst, 3);
+            assert_eq!(dropped.load(Ordering::Relaxed), 2);
+        }
+
+        // later records go straight out
+        publish_records(&outbox, 5, 1).await;
+        assert_eq!(streams(&outbox.pending.lock().unwrap()), [2, 3, 4, 5]);
+        assert_eq!(outbox.backlog.lock().unwrap().stats.buffered, 5);
+    }
+
+    #[tokio::test]
+    async fn unbuffered_devices_hold_the_publishes() {
+        let (outbox, online, _eventloop, _) = outbox(0);
+        let held = task::spawn({
+            let outbox = outbox.clone();
+            async move { publish_records(&outbox, 0, 1).await }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!held.is_finished());
+        assert!(outbox.pending.lock().unwrap().is_empty());
+
+        online.send_replace(true);
+        held.await.unwrap();
+        assert_eq!(streams(&outbox.pending.lock().unwrap()), [0]);
+        let backlog = outbox.backlog.lock().unwrap();
+        assert!(backlog.publishes.is_empty());
+        assert_eq!(backlog.stats.buffered, 0);
+        assert_eq!(backlog.stats.dropped, 0);
+    }
+
+    #[test]
+    fn links_go_offline_and_back() {
+        assert_eq!(Link::Online.wake(true), Link::Draining { abrupt: true });
+        let until = time::Instant::now();
+        assert_eq!(Link::Offline(until).wake(true), Link::Reconnecting);
+
+        // clean disconnects wait for acks, abrupt ones don't
+        let clean = Link::Draining { abrupt: false };
+        assert_eq!(clean.drained(true, || true), None);
+        assert_eq!(clean.drained(false, || true), Some(Link::Disconnecting));
+        assert_eq!(clean.drained(false, || false), Some(Link::Dropped));
+        let abrupt = Link::Draining { abrupt: true };
+        assert_eq!(
+            abrupt.drained(true, || panic!("abrupt disconnects send no packet")),
+            Some(Link::Dropped)
+        );
+        assert_eq!(Link::Online.drained(false, || true), None);
+
+        assert_eq!(Link::Reconnecting.failed(until), Some(Link::Offline(until)));
+        assert_eq!(Link::Disconnecting.failed(until), Some(Link::Dropped));
+        assert_eq!(Link::Online.failed(until), None);
+        assert_eq!(Link::Draining { abrupt: false }.failed(until), None);
+    }
+
+    #[test]
+    fn unacked_publishes_are_replayed_first() {
+        let mut state = MqttState::new(10, false);
+        for _ in 0..3 {
+            state.handle_outgoing_packet(publish(0)).unwrap();
+        }
+
+        let queued = vec![publish(7), Request::PubRel(PubRel::new(8))];
+        let (replays, publishes) = replays(state.clean(), queued.into_iter());
+        assert_eq!(pkids(&replays), [1, 2, 3, 7, 8]);
+        assert_eq!(publishes, 4);
+    }
+}
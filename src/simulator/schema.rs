@@ -0,0 +1,291 @@
+use std::{collections::HashSet, fs, io};
+
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+
+use super::signal::{Signal, SignalSpec};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error = {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Invalid schema = {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Schema has no fields")]
+    NoFields,
+    #[error("Field {0} = {1}")]
+    InvalidField(String, &'static str),
+}
+
+/// Payload layout of a user defined device type. Every record also carries
+/// a `sequence` and a `timestamp` like the built in types
+///
+/// ```json
+/// {
+///     "name": "thermostat",
+///     "fields": [
+///         { "name": "temperature", "type": "float", "range": [18, 25], "distribution": "normal" },
+///         { "name": "setpoint", "range": [18, 25], "signal": { "kind": "walk", "step": 0.1 } },
+///         { "name": "humidity", "type": "int", "range": [30, 60] },
+///         { "name": "mode", "type": "string", "values": ["heat", "cool", "off"] },
+///         { "name": "heating", "type": "bool" },
+///         { "name": "firmware", "constant": "1.4.2" }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    /// Device type, used for `{data_type}` in topics
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: Kind,
+    /// Inclusive bounds of generated numbers
+    pub range: Option<[f64; 2]>,
+    #[serde(default)]
+    pub distribution: Distribution,
+    /// Values to pick from at random
+    pub values: Option<Vec<Value>>,
+    /// Same value in every record
+    pub constant: Option<Value>,
+    /// Follow a time series within `range` instead of independent values
+    pub signal: Option<SignalSpec>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    #[default]
+    Float,
+    Int,
+    Bool,
+    String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Distribution {
+    #[default]
+    Uniform,
+    /// Centered in the range with 99.7% of values (3 sigma) inside it
+    Normal,
+}
+
+impl Schema {
+    pub fn load(path: &str) -> Result<Schema, Error> {
+        Schema::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(json: &str) -> Result<Schema, Error> {
+        let schema: Schema = serde_json::from_str(json)?;
+        if schema.fields.is_empty() {
+            return Err(Error::NoFields);
+        }
+
+        let mut names = HashSet::new();
+        for field in schema.fields.iter() {
+            field.validate()?;
+            if !names.insert(field.name.as_str()) {
+                return Err(Error::InvalidField(
+                    field.name.clone(),
+                    "name is used twice",
+                ));
+            }
+        }
+
+        Ok(schema)
+    }
+
+    /// Fresh state of the signals of a device, one per field
+    pub fn signals(&self) -> Vec<Option<Signal>> {
+        let mut rng = rand::thread_rng();
+        self.fields
+            .iter()
+            .map(|field| {
+                let [lo, hi] = field.range.unwrap_or([0.0, 1.0]);
+                field.signal.map(|spec| Signal::new(&mut rng, spec, lo, hi))
+            })
+            .collect()
+    }
+
+    /// One record of a device. Fields with a signal advance by `dt` seconds,
+    /// the others get independent random values
+    pub fn generate(
+        &self,
+        sequence: u32,
+        timestamp: u64,
+        signals: &mut [Option<Signal>],
+        dt: f64,
+    ) -> Map<String, Value> {
+        let mut rng = rand::thread_rng();
+        let mut record = Map::new();
+        record.insert("sequence".to_owned(), sequence.into());
+        record.insert("timestamp".to_owned(), timestamp.into());
+
+        for (field, signal) in self.fields.iter().zip(signals.iter_mut()) {
+            let value = match signal {
+                Some(signal) => field.number(signal.next(&mut rng, dt)),
+                None => field.generate(&mut rng),
+            };
+            record.insert(field.name.clone(), value);
+        }
+
+        record
+    }
+}
+
+impl Field {
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason| Err(Error::InvalidField(self.name.clone(), reason));
+
+        if self.name == "sequence" || self.name == "timestamp" {
+            return invalid("name is reserved");
+        }
+
+        if self.constant.is_some() {
+            return Ok(());
+        }
+
+        if let Some(signal) = self.signal {
+            if self.values.is_some() || !matches!(self.kind, Kind::Float | Kind::Int) {
+                return invalid("signal needs a float or an int");
+            }
+
+            if self.range.is_none() {
+                return invalid("signal needs a range");
+            }
+
+            match signal {
+                // a period of 0 is a flat line
+                SignalSpec::Sine { period, .. } if !(period >= 0.0 && period.is_finite()) => {
+                    return invalid("sine period can't be negative");
+                }
+                SignalSpec::Discharge { hours } if !(hours > 0.0 && hours.is_finite()) => {
+                    return invalid("discharge hours should be positive");
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(values) = &self.values {
+            if values.is_empty() {
+                return invalid("values are empty");
+            }
+
+            return Ok(());
+        }
+
+        match (self.kind, self.range) {
+            (Kind::Float | Kind::Int, Some([lo, hi])) if lo > hi => {
+                invalid("range should be [min, max]")
+            }
+            (Kind::Int, Some([lo, hi])) if lo.fract() != 0.0 || hi.fract() != 0.0 => {
+                invalid("range of an int should be integers")
+            }
+            (Kind::String, _) => invalid("string needs values or a constant"),
+            _ => Ok(()),
+        }
+    }
+
+    fn generate<R: Rng>(&self, rng: &mut R) -> Value {
+        if let Some(constant) = &self.constant {
+            return constant.clone();
+        }
+
+        if let Some(values) = &self.values {
+            return values[rng.gen_range(0..values.len())].clone();
+        }
+
+        let [lo, hi] = self.range.unwrap_or([0.0, 1.0]);
+        match self.kind {
+            Kind::Float | Kind::Int => self.number(self.sample(rng, lo, hi)),
+            Kind::Bool => Value::from(rng.gen_bool(0.5)),
+            Kind::String => Value::Null,
+        }
+    }
+
+    fn number(&self, v: f64) -> Value {
+        match self.kind {
+            Kind::Int => Value::from(v.round() as i64),
+            _ => Number::from_f64(v).map_or(Value::Null, Value::Number),
+        }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R, lo: f64, hi: f64) -> f64 {
+        if lo == hi {
+            return lo;
+        }
+
+        match self.distribution {
+            Distribution::Uniform => rng.gen_range(lo..=hi),
+            Distribution::Normal => {
+                // Box-Muller transform
+                let u1: f64 = 1.0 - rng.gen::<f64>();
+                let u2: f64 = rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                let mean = (lo + hi) / 2.0;
+                let std_dev = (hi - lo) / 6.0;
+                (mean + z * std_dev).clamp(lo, hi)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Error of a schema with these fields
+    fn error(fields: &str) -> String {
+        let json = format!(r#"{{ "name": "device", "fields": [{fields}] }}"#);
+        Schema::parse(&json).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn valid_schemas_load() {
+        let json = r#"{
+            "name": "battery",
+            "fields": [
+                { "name": "charge", "range": [0, 100], "signal": { "kind": "discharge", "hours": 0.5 } },
+                { "name": "load", "range": [0, 10], "signal": { "kind": "sine", "period": 0 } },
+                { "name": "mode", "type": "string", "values": ["eco", "sport"] }
+            ]
+        }"#;
+        let schema = Schema::parse(json).unwrap();
+        assert_eq!(schema.fields.len(), 3);
+    }
+
+    #[test]
+    fn discharges_need_positive_hours() {
+        for hours in ["0", "-2"] {
+            let field = format!(
+                r#"{{ "name": "charge", "range": [0, 100], "signal": {{ "kind": "discharge", "hours": {hours} }} }}"#
+            );
+            assert_eq!(
+                error(&field),
+                "Field charge = discharge hours should be positive"
+            );
+        }
+    }
+
+    #[test]
+    fn sine_periods_cant_be_negative() {
+        let field =
+            r#"{ "name": "load", "range": [0, 10], "signal": { "kind": "sine", "period": -60 } }"#;
+        assert_eq!(error(field), "Field load = sine period can't be negative");
+    }
+
+    #[test]
+    fn field_names_are_unique() {
+        let fields = r#"{ "name": "load", "range": [0, 10] }, { "name": "load", "type": "bool" }"#;
+        assert_eq!(error(fields), "Field load = name is used twice");
+    }
+}
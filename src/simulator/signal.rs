@@ -0,0 +1,295 @@
+//! Stateful generators which make consecutive records of a device look like
+//! one continuous measurement. All of them advance by the seconds elapsed
+//! since the previous record, so they behave the same at any publish rate
+
+use std::f64::consts::PI;
+
+use rand::Rng;
+use serde::Deserialize;
+
+/// Meters per degree of latitude
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Open circuit voltage of a li-ion cell against its state of charge
+const DISCHARGE_CURVE: [(f64, f64); 8] = [
+    (0.0, 3.0),
+    (0.05, 3.3),
+    (0.1, 3.45),
+    (0.2, 3.55),
+    (0.5, 3.7),
+    (0.8, 3.9),
+    (0.9, 4.0),
+    (1.0, 4.2),
+];
+
+/// Random walk which stays in `[min, max]`. `step` is the typical change
+/// over a second
+#[derive(Debug, Clone)]
+pub struct Walk {
+    value: f64,
+    step: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Walk {
+    pub fn new<R: Rng>(rng: &mut R, min: f64, max: f64, step: f64) -> Walk {
+        Walk {
+            value: uniform(rng, min, max),
+            step,
+            min,
+            max,
+        }
+    }
+
+    pub fn next<R: Rng>(&mut self, rng: &mut R, dt: f64) -> f64 {
+        let delta = rng.gen_range(-1.0..=1.0) * self.step * dt.sqrt();
+        self.value += delta;
+
+        // Bounce off the bounds instead of sticking to them
+        if self.value > self.max {
+            self.value = 2.0 * self.max - self.value;
+        }
+        if self.value < self.min {
+            self.value = 2.0 * self.min - self.value;
+        }
+
+        self.value = self.value.clamp(self.min, self.max);
+        self.value
+    }
+}
+
+/// Sine wave between `min` and `max` with a period in seconds and some
+/// noise on top, e.g. daily temperature cycles
+#[derive(Debug, Clone)]
+pub struct Wave {
+    min: f64,
+    max: f64,
+    period: f64,
+    noise: f64,
+    elapsed: f64,
+}
+
+impl Wave {
+    pub fn new<R: Rng>(rng: &mut R, min: f64, max: f64, period: f64, noise: f64) -> Wave {
+        // Devices don't all start at the same point of the cycle
+        let elapsed = rng.gen_range(0.0..=period.max(0.0));
+        Wave {
+            min,
+            max,
+            period,
+            noise,
+            elapsed,
+        }
+    }
+
+    pub fn next<R: Rng>(&mut self, rng: &mut R, dt: f64) -> f64 {
+        self.elapsed += dt;
+
+        let mid = (self.min + self.max) / 2.0;
+        let amplitude = (self.max - self.min) / 2.0;
+        let angle = if self.period > 0.0 {
+            2.0 * PI * self.elapsed / self.period
+        } else {
+            0.0
+        };
+        let noise = rng.gen_range(-1.0..=1.0) * self.noise * amplitude;
+        (mid + amplitude * angle.sin() + noise).clamp(self.min, self.max)
+    }
+}
+
+/// Monotonic counter growing by about `rate` per second, e.g. odometers
+/// and energy meters
+#[derive(Debug, Clone)]
+pub struct Counter {
+    value: f64,
+    rate: f64,
+}
+
+impl Counter {
+    pub fn new(start: f64, rate: f64) -> Counter {
+        Counter { value: start, rate }
+    }
+
+    pub fn next<R: Rng>(&mut self, rng: &mut R, dt: f64) -> f64 {
+        self.value += self.rate * dt * rng.gen_range(0.5..=1.5);
+        self.value
+    }
+}
+
+/// Battery pack discharging with a wandering current. An empty battery is
+/// swapped for a charged one, which counts as a cycle
+#[derive(Debug, Clone)]
+pub struct Battery {
+    /// State of charge between 0 and 1
+    pub soc: f64,
+    /// Capacity in ampere hours
+    pub capacity: f64,
+    /// Discharge current in amperes
+    pub current: Walk,
+    pub cycles: u64,
+    /// Ampere hours drawn over all cycles
+    pub consumed: f64,
+}
+
+impl Battery {
+    pub fn new<R: Rng>(rng: &mut R, capacity: f64, min_current: f64, max_current: f64) -> Battery {
+        Battery {
+            soc: rng.gen_range(0.3..=1.0),
+            capacity,
+            current: Walk::new(
+                rng,
+                min_current,
+                max_current,
+                (max_current - min_current) / 20.0,
+            ),
+            cycles: rng.gen_range(0..500),
+            consumed: 0.0,
+        }
+    }
+
+    /// Draws the current for `dt` seconds and returns it
+    pub fn next<R: Rng>(&mut self, rng: &mut R, dt: f64) -> f64 {
+        let current = self.current.next(rng, dt);
+        let drawn = current * dt / 3600.0;
+        self.consumed += drawn;
+        self.soc -= drawn / self.capacity;
+
+        if self.soc <= 0.05 {
+            self.soc = 1.0;
+            self.cycles += 1;
+        }
+
+        current
+    }
+
+    /// Cell voltage at the current state of charge
+    pub fn cell_voltage(&self) -> f64 {
+        let soc = self.soc.clamp(0.0, 1.0);
+        for pair in DISCHARGE_CURVE.windows(2) {
+            let (lo_soc, lo_v) = pair[0];
+            let (hi_soc, hi_v) = pair[1];
+            if soc <= hi_soc {
+                return lo_v + (soc - lo_soc) * (hi_v - lo_v) / (hi_soc - lo_soc);
+            }
+        }
+
+        DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1].1
+    }
+}
+
+/// Vehicle moving at plausible speeds with gradual turns
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Degrees clockwise from north
+    heading: f64,
+    /// Meters per second
+    speed: Walk,
+}
+
+impl Track {
+    pub fn new<R: Rng>(rng: &mut R) -> Track {
+        Track {
+            latitude: rng.gen_range(-60.0..=60.0),
+            longitude: rng.gen_range(-180.0..=180.0),
+            heading: rng.gen_range(0.0..360.0),
+            speed: Walk::new(rng, 0.0, 30.0, 1.5),
+        }
+    }
+
+    pub fn next<R: Rng>(&mut self, rng: &mut R, dt: f64) {
+        let speed = self.speed.next(rng, dt);
+        self.heading = (self.heading + rng.gen_range(-10.0..=10.0) * dt.sqrt()).rem_euclid(360.0);
+
+        let distance = speed * dt;
+        let heading = self.heading.to_radians();
+        self.latitude += distance * heading.cos() / METERS_PER_DEGREE;
+        self.latitude = self.latitude.clamp(-85.0, 85.0);
+        self.longitude +=
+            distance * heading.sin() / (METERS_PER_DEGREE * self.latitude.to_radians().cos());
+        // Wrap around the antimeridian
+        self.longitude = (self.longitude + 180.0).rem_euclid(360.0) - 180.0;
+    }
+}
+
+/// Signal of a schema field. Bounds come from the `range` of the field
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SignalSpec {
+    /// Random walk changing by about `step` per second
+    Walk { step: f64 },
+    /// Sine wave with a period in seconds
+    Sine {
+        period: f64,
+        #[serde(default)]
+        noise: f64,
+    },
+    /// Counter growing by about `rate` per second from the range start
+    Counter { rate: f64 },
+    /// Drains from the top of the range to the bottom over `hours` and
+    /// starts over, like the charge of a battery
+    Discharge { hours: f64 },
+}
+
+/// State of a schema field of one device
+#[derive(Debug, Clone)]
+pub enum Signal {
+    Walk(Walk),
+    Wave(Wave),
+    Counter(Counter),
+    Discharge {
+        value: f64,
+        min: f64,
+        max: f64,
+        rate: f64,
+    },
+}
+
+impl Signal {
+    pub fn new<R: Rng>(rng: &mut R, spec: SignalSpec, min: f64, max: f64) -> Signal {
+        match spec {
+            SignalSpec::Walk { step } => Signal::Walk(Walk::new(rng, min, max, step)),
+            SignalSpec::Sine { period, noise } => {
+                Signal::Wave(Wave::new(rng, min, max, period, noise))
+            }
+            SignalSpec::Counter { rate } => Signal::Counter(Counter::new(min, rate)),
+            SignalSpec::Discharge { hours } => Signal::Discharge {
+                value: uniform(rng, min, max),
+                min,
+                max,
+                rate: (max - min) / (hours * 3600.0),
+            },
+        }
+    }
+
+    pub fn next<R: Rng>(&mut self, rng: &mut R, dt: f64) -> f64 {
+        match self {
+            Signal::Walk(walk) => walk.next(rng, dt),
+            Signal::Wave(wave) => wave.next(rng, dt),
+            Signal::Counter(counter) => counter.next(rng, dt),
+            Signal::Discharge {
+                value,
+                min,
+                max,
+                rate,
+            } => {
+                *value -= *rate * dt;
+                if *value <= *min {
+                    *value = *max;
+                }
+
+                *value
+            }
+        }
+    }
+}
+
+fn uniform<R: Rng>(rng: &mut R, min: f64, max: f64) -> f64 {
+    if min >= max {
+        return min;
+    }
+
+    rng.gen_range(min..=max)
+}
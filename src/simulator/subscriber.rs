@@ -1,5 +1,8 @@
 use std::{
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -8,13 +11,25 @@ use rumqttc::{AsyncClient, Event, EventLoop, Incoming, Outgoing};
 use tokio::{sync::Barrier, time};
 
 use crate::{
-    simulator::{get_qos, options, ConnectionError, SubStats},
+    common,
+    metrics::METRICS,
+    report::Validation,
+    simulator::{
+        actions::STATUS_STREAM, encoding, get_qos, options, validation::Validator, ConnectionError,
+        SubStats,
+    },
     SimulatorConfig,
 };
 
 pub struct Subscriber {
     id: String,
     config: Arc<SimulatorConfig>,
+    /// Records expected from all the publishers
+    count: usize,
+    /// Checks the records when `--validate` is on
+    validator: Option<Validator>,
+    /// Segment of `{data_type}` in topics
+    data_type_at: Option<usize>,
     #[allow(dead_code)]
     client: AsyncClient,
     eventloop: EventLoop,
@@ -24,6 +39,9 @@ impl Subscriber {
     pub(crate) async fn new(
         id: String,
         config: Arc<SimulatorConfig>,
+        data_type: String,
+        count: usize,
+        validator: Option<Validator>,
     ) -> Result<Subscriber, ConnectionError> {
         let (client, mut eventloop) = AsyncClient::new(options(config.clone(), &id)?, 10);
         eventloop
@@ -35,14 +53,17 @@ impl Subscriber {
             let event = eventloop.poll().await?;
             if let Event::Incoming(v) = event {
                 match v {
-                    Incoming::ConnAck(_) => break,
+                    Incoming::ConnAck(_) => {
+                        METRICS.connected();
+                        break;
+                    }
                     incoming => return Err(ConnectionError::WrongPacket(incoming)),
                 }
             }
         }
 
         let topic = config.topic_format.replacen("{pub_id}", "+", 1);
-        let topic = topic.replacen("{data_type}", &config.data_type.to_string(), 1);
+        let topic = topic.replacen("{data_type}", &data_type, 1);
 
         // subscribing
         client
@@ -60,18 +81,45 @@ impl Subscriber {
             }
         }
 
+        let data_type_at = config
+            .topic_format
+            .split('/')
+            .position(|v| v == "{data_type}");
         Ok(Subscriber {
             id,
             config,
+            count,
+            validator,
+            data_type_at,
             client,
             eventloop,
         })
     }
 
-    pub async fn start(&mut self, barrier_handle: Arc<Barrier>) -> SubStats {
-        let required_publish_count = self.config.count * self.config.publishers;
+    /// Records in a publish. Action statuses match the `+` filter of runs
+    /// with several streams but don't carry records
+    fn records(&self, topic: &str, payload: &[u8]) -> usize {
+        let status = self
+            .data_type_at
+            .is_some_and(|at| topic.split('/').nth(at) == Some(STATUS_STREAM));
+        match status {
+            true => 0,
+            false => encoding::records(self.config.encoding, payload),
+        }
+    }
+
+    /// Stats of the subscriber, the records it received and their checks.
+    /// Doesn't wait for the records publishers `dropped`
+    pub async fn start(
+        &mut self,
+        barrier_handle: Arc<Barrier>,
+        dropped: Arc<AtomicUsize>,
+    ) -> (SubStats, u64, Option<Validation>) {
+        let required_record_count = self.count;
         // total number of publishes received
         let mut publish_count = 0;
+        // total number of records in the received publishes
+        let mut record_count = 0;
         // total number of pubacks sent
         let mut puback_count = 0;
         // when the very first publish arrived
@@ -90,6 +138,7 @@ impl Subscriber {
                 Ok(v) => v,
                 Err(e) => {
                     error!("Id = {}, Connection error = {:?}", self.id, e);
+                    METRICS.reconnected();
 
                     reconnects += 1;
                     if reconnects >= 1 {
@@ -100,8 +149,13 @@ impl Subscriber {
             };
 
             match event {
-                Event::Incoming(Incoming::Publish(_)) => {
+                Event::Incoming(Incoming::Publish(publish)) => {
+                    METRICS.received();
                     publish_count += 1;
+                    record_count += self.records(&publish.topic, &publish.payload);
+                    if let Some(validator) = &mut self.validator {
+                        validator.check(&publish.topic, &publish.payload);
+                    }
                     start = Instant::now();
                     last_publish = start;
                     break;
@@ -124,11 +178,13 @@ impl Subscriber {
 
         let mut seq = 0;
         // for remainging publishes
-        while publish_count < required_publish_count {
+        // records dropped from offline buffers never arrive
+        while record_count + dropped.load(Ordering::Relaxed) < required_record_count {
             let event = match self.eventloop.poll().await {
                 Ok(v) => v,
                 Err(e) => {
                     error!("Id = {}, Connection error = {:?}", self.id, e);
+                    METRICS.reconnected();
                     reconnects += 1;
                     if reconnects >= 2 {
                         break;
@@ -140,9 +196,14 @@ impl Subscriber {
             debug!("Id = {}, {:?}, count = {}", self.id, event, publish_count);
 
             match event {
-                Event::Incoming(Incoming::Publish(_)) => {
+                Event::Incoming(Incoming::Publish(publish)) => {
+                    METRICS.received();
                     seq += 1;
                     publish_count += 1;
+                    record_count += self.records(&publish.topic, &publish.payload);
+                    if let Some(validator) = &mut self.validator {
+                        validator.check(&publish.topic, &publish.payload);
+                    }
                     histogram
                         .record(last_publish.elapsed().as_millis() as u64)
                         .unwrap();
@@ -161,8 +222,8 @@ impl Subscriber {
             }
         }
 
-        let outgoing_throughput =
-            (publish_count * 1000) as f32 / (last_publish - start).as_millis() as f32;
+        METRICS.disconnected();
+        let outgoing_throughput = common::throughput(publish_count as u64, last_publish - start);
 
         if self.config.show_sub_stat {
             println!(
@@ -195,11 +256,14 @@ impl Subscriber {
             );
         }
 
-        SubStats {
+        let substats = SubStats {
             publish_count: publish_count as u64,
             puback_count,
             reconnects,
             throughput: outgoing_throughput,
-        }
+        };
+
+        let validation = self.validator.take().map(Validator::finish);
+        (substats, record_count as u64, validation)
     }
 }
@@ -0,0 +1,279 @@
+//! Subscriber side checks of the records. Brokers can corrupt or truncate
+//! payloads without changing message counts, so records are decoded and
+//! checked against the layout of their device type and the previous record
+//! of their device
+
+use std::{collections::HashMap, sync::Arc};
+
+use serde_json::{Map, Value};
+
+use super::{
+    encoding::Encoder,
+    publisher,
+    schema::{Kind, Schema},
+    signal::SignalSpec,
+    Device, Stream,
+};
+use crate::{report::Validation, DataType};
+
+/// Bounds of the fields of the built in types, over both random and
+/// `--signals` records. `#` stands for the number of numbered fields. Other
+/// fields are only checked for presence and type
+const IMU_RANGES: [(&str, f64, f64); 9] = [
+    ("ax", 1.0, 2.8),
+    ("ay", 1.0, 2.8),
+    ("az", 9.79, 9.82),
+    ("pitch", 0.8, 1.0),
+    ("roll", 0.8, 1.0),
+    ("yaw", 0.8, 1.0),
+    ("magx", -45.0, -15.0),
+    ("magy", -45.0, -15.0),
+    ("magz", -45.0, -15.0),
+];
+const GPS_RANGES: [(&str, f64, f64); 2] = [("latitude", -90.0, 90.0), ("longitude", -180.0, 180.0)];
+const BMS_RANGES: [(&str, f64, f64); 8] = [
+    ("cell_voltage_#", 2.9, 4.3),
+    ("cell_temp_#", 40.0, 43.0),
+    ("mosfet_temperature", 40.0, 45.0),
+    ("ambient_temperature", 35.0, 40.0),
+    ("pack_current", 15.0, 20.0),
+    ("pack_soc", 0.0, 100.0),
+    ("pack_soh", 9.5, 9.9),
+    ("pack_sop", 9.5, 9.9),
+];
+
+/// What a field of a record should hold
+#[derive(Debug)]
+enum Rule {
+    Number(f64, f64),
+    Int(f64, f64),
+    Bool,
+    String,
+    OneOf(Vec<Value>),
+    Equals(Value),
+}
+
+impl Rule {
+    fn check(&self, value: &Value) -> bool {
+        // room for the rounding of 32 bit floats
+        let within = |lo: f64, hi: f64| {
+            let slack = 1e-6 * lo.abs().max(hi.abs()).max(1.0);
+            value
+                .as_f64()
+                .is_some_and(|v| v >= lo - slack && v <= hi + slack)
+        };
+
+        match self {
+            Rule::Number(lo, hi) => within(*lo, *hi),
+            Rule::Int(lo, hi) => (value.is_i64() || value.is_u64()) && within(*lo, *hi),
+            Rule::Bool => value.is_boolean(),
+            Rule::String => value.is_string(),
+            Rule::OneOf(values) => values.contains(value),
+            Rule::Equals(expected) => expected == value,
+        }
+    }
+}
+
+/// Fields of the records of a device type
+#[derive(Debug)]
+struct Layout {
+    fields: Vec<(String, Rule)>,
+}
+
+impl Layout {
+    fn new(device: &Device) -> Layout {
+        match device {
+            Device::Builtin(data_type) => Layout::builtin(*data_type),
+            Device::Schema(schema) => Layout::schema(schema),
+        }
+    }
+
+    fn builtin(data_type: DataType) -> Layout {
+        let ranges: &[(&str, f64, f64)] = match data_type {
+            DataType::Imu => &IMU_RANGES,
+            DataType::Bms => &BMS_RANGES,
+            DataType::Gps => &GPS_RANGES,
+        };
+
+        // every record has the fields of a sample record
+        let sample = match publisher::generate_data(0, 0, data_type) {
+            Value::Object(v) => v,
+            _ => Map::new(),
+        };
+
+        let fields = sample
+            .into_iter()
+            .map(|(name, value)| {
+                let range = ranges
+                    .iter()
+                    .find(|(pattern, ..)| matches(pattern, &name))
+                    .map(|(_, lo, hi)| (*lo, *hi));
+                let rule = match (range, value.is_f64()) {
+                    (Some((lo, hi)), _) => Rule::Number(lo, hi),
+                    (None, true) => Rule::Number(f64::MIN, f64::MAX),
+                    (None, false) => Rule::Int(f64::MIN, f64::MAX),
+                };
+
+                (name, rule)
+            })
+            .collect();
+
+        Layout { fields }
+    }
+
+    fn schema(schema: &Schema) -> Layout {
+        let mut fields = vec![
+            ("sequence".to_owned(), Rule::Int(0.0, f64::MAX)),
+            ("timestamp".to_owned(), Rule::Int(0.0, f64::MAX)),
+        ];
+
+        for field in schema.fields.iter() {
+            let [lo, hi] = field.range.unwrap_or([0.0, 1.0]);
+            // counters grow past the end of their range
+            let hi = match field.signal {
+                Some(SignalSpec::Counter { .. }) => f64::MAX,
+                _ => hi,
+            };
+
+            let rule = match (&field.constant, &field.values, field.kind) {
+                (Some(constant), ..) => Rule::Equals(constant.clone()),
+                (_, Some(values), _) => Rule::OneOf(values.clone()),
+                (_, _, Kind::Float) => Rule::Number(lo, hi),
+                (_, _, Kind::Int) => Rule::Int(lo, hi),
+                (_, _, Kind::Bool) => Rule::Bool,
+                (_, _, Kind::String) => Rule::String,
+            };
+
+            fields.push((field.name.clone(), rule));
+        }
+
+        Layout { fields }
+    }
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('#') {
+        Some(prefix) => name
+            .strip_prefix(prefix)
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+        None => pattern == name,
+    }
+}
+
+/// Encodings and layouts of the device types of a run, shared by all the
+/// subscribers
+pub struct Layouts {
+    types: HashMap<String, (Encoder, Layout)>,
+    /// Segment of `{data_type}` in topics
+    data_type_at: Option<usize>,
+}
+
+impl Layouts {
+    pub fn new(streams: &[Stream], topic_format: &str) -> Layouts {
+        let types = streams
+            .iter()
+            .map(|v| (v.device.name(), (v.encoder.clone(), Layout::new(&v.device))))
+            .collect();
+        let data_type_at = topic_format.split('/').position(|v| v == "{data_type}");
+
+        Layouts {
+            types,
+            data_type_at,
+        }
+    }
+
+    fn find(&self, topic: &str) -> Option<&(Encoder, Layout)> {
+        match self.data_type_at {
+            Some(at) => self.types.get(topic.split('/').nth(at)?),
+            // topics without the type carry a single one
+            None if self.types.len() == 1 => self.types.values().next(),
+            None => None,
+        }
+    }
+}
+
+/// Last record of a device
+#[derive(Debug, Default)]
+struct Track {
+    sequence: Option<u64>,
+    timestamp: Option<u64>,
+}
+
+/// Checks every record a subscriber receives
+pub struct Validator {
+    layouts: Arc<Layouts>,
+    /// Devices by their topic
+    devices: HashMap<String, Track>,
+    validation: Validation,
+}
+
+impl Validator {
+    pub fn new(layouts: Arc<Layouts>) -> Validator {
+        Validator {
+            layouts,
+            devices: HashMap::new(),
+            validation: Validation::default(),
+        }
+    }
+
+    pub fn check(&mut self, topic: &str, payload: &[u8]) {
+        // publishes of other streams, e.g. action statuses
+        let (encoder, layout) = match self.layouts.find(topic) {
+            Some(v) => v,
+            None => return,
+        };
+
+        let records = match encoder.decode(payload) {
+            Some(v) => v,
+            None => {
+                self.validation.undecodable += 1;
+                return;
+            }
+        };
+
+        let track = self.devices.entry(topic.to_owned()).or_default();
+        let validation = &mut self.validation;
+        for record in records {
+            validation.records += 1;
+            let record = match record {
+                Value::Object(v) => v,
+                _ => {
+                    validation.invalid_values += 1;
+                    continue;
+                }
+            };
+
+            for (name, rule) in layout.fields.iter() {
+                match record.get(name) {
+                    Some(value) if rule.check(value) => {}
+                    Some(_) => validation.invalid_values += 1,
+                    None => validation.missing_fields += 1,
+                }
+            }
+
+            if let Some(sequence) = record.get("sequence").and_then(Value::as_u64) {
+                // devices start at 0
+                let expected = track.sequence.map_or(0, |v| v + 1);
+                if sequence < expected {
+                    validation.sequence_regressions += 1;
+                } else {
+                    validation.sequence_gaps += sequence - expected;
+                }
+
+                track.sequence = track.sequence.max(Some(sequence));
+            }
+
+            if let Some(timestamp) = record.get("timestamp").and_then(Value::as_u64) {
+                if track.timestamp.is_some_and(|v| timestamp < v) {
+                    validation.timestamp_regressions += 1;
+                }
+
+                track.timestamp = track.timestamp.max(Some(timestamp));
+            }
+        }
+    }
+
+    pub fn finish(self) -> Validation {
+        self.validation
+    }
+}
@@ -0,0 +1,410 @@
+//! End to end check of a simulator run. Queries the rows a sink stored over
+//! the ClickHouse http interface and compares them, device by device, with
+//! the records the simulator sent. Sinks take a while to flush, so queries
+//! are repeated until every record arrived or the timeout passes
+
+use std::{collections::BTreeMap, io, time::Duration};
+
+use colored::Colorize;
+use tokio::time::{self, Instant};
+
+use crate::{
+    http,
+    simulator::{self, SetupError},
+    VerifyConfig,
+};
+
+/// Devices with problems which are printed in detail
+const SHOWN_DEVICES: usize = 20;
+/// Ranges of missing sequences printed for a device
+const SHOWN_RANGES: usize = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error = {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Sink error, status = {0}, {1}")]
+    Sink(u16, String),
+    #[error("Invalid row `{0}`")]
+    Row(String),
+    #[error("Setup error = {0}")]
+    Setup(#[from] SetupError),
+    #[error("{0}")]
+    Device(String),
+}
+
+/// Rows of a device in the sink
+#[derive(Debug, Default)]
+struct Device {
+    rows: u64,
+    /// Sequences of the run without a row
+    missing: Vec<u64>,
+    /// Extra rows of sequences which were stored more than once
+    duplicates: u64,
+    /// Rows past the sequences of the run, like the synchronization record
+    /// of qos 0 runs. These are reported but don't fail the check
+    unexpected: u64,
+}
+
+#[derive(Debug, Default)]
+struct Check {
+    devices: BTreeMap<String, Device>,
+}
+
+impl Check {
+    fn rows(&self) -> u64 {
+        self.devices.values().map(|v| v.rows).sum()
+    }
+
+    fn missing(&self) -> u64 {
+        self.devices.values().map(|v| v.missing.len() as u64).sum()
+    }
+
+    fn duplicates(&self) -> u64 {
+        self.devices.values().map(|v| v.duplicates).sum()
+    }
+
+    fn unexpected(&self) -> u64 {
+        self.devices.values().map(|v| v.unexpected).sum()
+    }
+}
+
+/// Polls the sink until it has every record of the run and prints what's
+/// missing or duplicated. Returns false if the sink's rows don't match
+pub async fn start(config: VerifyConfig) -> bool {
+    let counts = match counts(&config) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Couldn't plan the run: {}", e);
+            return false;
+        }
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(config.timeout);
+    let expected: u64 = counts.values().sum();
+    let mut check;
+    loop {
+        check = match query(&config, &counts).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Couldn't query {}: {}", config.url, e);
+                return false;
+            }
+        };
+
+        println!(
+            "Rows: expected = {}, stored = {}, missing = {}",
+            expected,
+            check.rows(),
+            check.missing()
+        );
+
+        // duplicates don't go away by waiting
+        if check.missing() == 0 || Instant::now() >= deadline {
+            break;
+        }
+
+        time::sleep_until(deadline.min(Instant::now() + Duration::from_secs(config.interval)))
+            .await;
+    }
+
+    let failed: Vec<_> = check
+        .devices
+        .iter()
+        .filter(|(_, v)| !v.missing.is_empty() || v.duplicates > 0)
+        .collect();
+
+    for (id, device) in failed.iter().take(SHOWN_DEVICES) {
+        println!(
+            "{:<12} rows = {:>8}, missing = {:>8}, duplicates = {:>8}, unexpected = {:>8} {}",
+            id,
+            device.rows,
+            device.missing.len(),
+            device.duplicates,
+            device.unexpected,
+            ranges(&device.missing)
+        );
+    }
+
+    if failed.len() > SHOWN_DEVICES {
+        println!("... and {} more devices", failed.len() - SHOWN_DEVICES);
+    }
+
+    println!(
+        "Devices = {}, failed = {}, missing = {}, duplicates = {}, unexpected = {}",
+        counts.len(),
+        failed.len(),
+        check.missing(),
+        check.duplicates(),
+        check.unexpected()
+    );
+
+    let passed = failed.is_empty();
+    match passed {
+        true => println!("{}", "PASSED".green()),
+        false => println!("{}", "FAILED".red()),
+    }
+
+    passed
+}
+
+/// Records the publishers of the run sent to the table, by id. Publishers
+/// of fleets without the table's device aren't in it
+fn counts(config: &VerifyConfig) -> Result<BTreeMap<String, u64>, Error> {
+    if config.streams.is_empty() && config.fleet.is_none() {
+        let count = config.count as u64;
+        let ids = (0..config.publishers).map(simulator::publisher_id);
+        return Ok(ids.map(|v| (v, count)).collect());
+    }
+
+    let plan = simulator::plan(
+        &config.streams,
+        config.fleet.as_deref(),
+        config.publishers,
+        config.count,
+    )?;
+
+    let device = match &config.device {
+        Some(v) => v.clone(),
+        None => {
+            let mut devices: Vec<&str> = plan.iter().flatten().map(|(v, _)| v.as_str()).collect();
+            devices.sort_unstable();
+            devices.dedup();
+            match devices[..] {
+                [device] => device.to_owned(),
+                _ => {
+                    let devices = devices.join(", ");
+                    let error =
+                        format!("run has devices {devices}, pick the table's with --device");
+                    return Err(Error::Device(error));
+                }
+            }
+        }
+    };
+
+    let mut counts = BTreeMap::new();
+    for (i, streams) in plan.iter().enumerate() {
+        if let Some((_, count)) = streams.iter().find(|(v, _)| *v == device) {
+            counts.insert(simulator::publisher_id(i), *count as u64);
+        }
+    }
+
+    match counts.is_empty() {
+        true => Err(Error::Device(format!(
+            "no publisher of the run has a {device} stream"
+        ))),
+        false => Ok(counts),
+    }
+}
+
+/// Rows of every sequence of the run's devices
+async fn query(config: &VerifyConfig, counts: &BTreeMap<String, u64>) -> Result<Check, Error> {
+    let list: Vec<String> = counts.keys().map(|v| format!("'{v}'")).collect();
+    let sql = format!(
+        "SELECT {id}, {sequence}, count() FROM {table} WHERE {id} IN ({list}) \
+         GROUP BY {id}, {sequence} FORMAT TabSeparated",
+        id = config.id_column,
+        sequence = config.sequence_column,
+        table = config.table,
+        list = list.join(", "),
+    );
+
+    let mut headers = vec![("X-ClickHouse-User", config.user.as_str())];
+    if let Some(password) = &config.password {
+        headers.push(("X-ClickHouse-Key", password));
+    }
+
+    let (status, body) = http::post(&config.url, "text/plain", &headers, &sql).await?;
+    if status != 200 {
+        return Err(Error::Sink(status, body.trim().to_owned()));
+    }
+
+    // rows of every sequence of every device
+    let mut sequences: BTreeMap<&str, BTreeMap<u64, u64>> = counts
+        .keys()
+        .map(|v| (v.as_str(), BTreeMap::new()))
+        .collect();
+    for row in body.lines().filter(|v| !v.is_empty()) {
+        let mut columns = row.split('\t');
+        let parsed: Option<(&str, (u64, u64))> =
+            match (columns.next(), columns.next(), columns.next()) {
+                (Some(id), Some(sequence), Some(rows)) => sequence
+                    .parse()
+                    .ok()
+                    .zip(rows.parse().ok())
+                    .map(|v| (id, v)),
+                _ => None,
+            };
+
+        let (id, (sequence, rows)) = parsed.ok_or_else(|| Error::Row(row.to_owned()))?;
+        if let Some(device) = sequences.get_mut(id) {
+            *device.entry(sequence).or_default() += rows;
+        }
+    }
+
+    let devices = sequences
+        .into_iter()
+        .map(|(id, sequences)| {
+            let count = counts[id];
+            let mut device = Device {
+                rows: sequences.values().sum(),
+                missing: (0..count).filter(|v| !sequences.contains_key(v)).collect(),
+                ..Default::default()
+            };
+
+            for (sequence, rows) in sequences {
+                match sequence < count {
+                    true => device.duplicates += rows.saturating_sub(1),
+                    false => device.unexpected += rows,
+                }
+            }
+
+            (id.to_owned(), device)
+        })
+        .collect();
+
+    Ok(Check { devices })
+}
+
+/// Sorted sequences as ranges like `3-7, 12`
+fn ranges(sequences: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &sequence in sequences {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == sequence => *end = sequence,
+            _ => ranges.push((sequence, sequence)),
+        }
+    }
+
+    let mut shown: Vec<String> = ranges
+        .iter()
+        .take(SHOWN_RANGES)
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{start}-{end}"),
+        })
+        .collect();
+    if ranges.len() > SHOWN_RANGES {
+        shown.push("...".to_owned());
+    }
+
+    shown.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn config(args: &str) -> VerifyConfig {
+        VerifyConfig::parse_from(format!("verify {args}").split_whitespace())
+    }
+
+    /// Reply of ClickHouse with `rows`, in chunks of a few rows
+    fn chunked(rows: &[&str]) -> String {
+        let mut response =
+            "HTTP/1.1 200 OK\r\nContent-Type: text/tab-separated-values\r\nTransfer-Encoding: chunked\r\n\r\n"
+                .to_owned();
+        for chunk in rows.chunks(3) {
+            let chunk: String = chunk.iter().map(|v| format!("{v}\n")).collect();
+            response += &format!("{:x}\r\n{}\r\n", chunk.len(), chunk);
+        }
+
+        response + "0\r\n\r\n"
+    }
+
+    #[tokio::test]
+    async fn rows_are_checked_by_device() {
+        let rows = [
+            "pub-00000\t0\t1",
+            "pub-00000\t1\t1",
+            "pub-00000\t2\t3",
+            "pub-00000\t4\t1",
+            "pub-00000\t5\t1",
+            "pub-00001\t0\t1",
+            "pub-00001\t1\t1",
+            "pub-00001\t2\t1",
+            "pub-00001\t3\t1",
+            "pub-00001\t4\t1",
+            "pub-00099\t0\t1",
+        ];
+        let (url, mut requests) = http::stub(vec![chunked(&rows)]).await;
+        let config = config(&format!("-u {url}/ -p 2 -n 5 --password secret"));
+        let check = query(&config, &counts(&config).unwrap()).await.unwrap();
+
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("X-ClickHouse-Key: secret\r\n"));
+        assert!(request.contains("FROM demo.imu WHERE id IN ('pub-00000', 'pub-00001')"));
+
+        let device = &check.devices["pub-00000"];
+        assert_eq!(device.rows, 7);
+        assert_eq!(device.missing, [3]);
+        assert_eq!(device.duplicates, 2);
+        assert_eq!(device.unexpected, 1);
+
+        let device = &check.devices["pub-00001"];
+        assert_eq!(device.rows, 5);
+        assert!(device.missing.is_empty());
+        assert_eq!(device.duplicates + device.unexpected, 0);
+
+        // rows of other runs are ignored
+        assert_eq!(check.devices.len(), 2);
+        assert_eq!(check.rows(), 12);
+    }
+
+    #[tokio::test]
+    async fn sink_errors_fail() {
+        let response =
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 30\r\n\r\nCode: 60. Table doesn't exist\n";
+        let (url, _requests) = http::stub(vec![response.to_owned()]).await;
+        let config = config(&format!("-u {url} -p 2 -n 5"));
+
+        match query(&config, &counts(&config).unwrap()).await {
+            Err(Error::Sink(404, body)) => assert_eq!(body, "Code: 60. Table doesn't exist"),
+            v => panic!("expected a sink error, got {:?}", v),
+        }
+        assert!(!start(config).await);
+    }
+
+    #[tokio::test]
+    async fn sinks_are_polled_till_complete() {
+        let first = chunked(&["pub-00000\t0\t1"]);
+        let second = chunked(&["pub-00000\t0\t1", "pub-00000\t1\t1"]);
+        let (url, mut requests) = http::stub(vec![first, second]).await;
+
+        assert!(start(config(&format!("-u {url} -n 2 --interval 0"))).await);
+        assert!(requests.recv().await.is_some());
+        assert!(requests.recv().await.is_some());
+    }
+
+    #[test]
+    fn streams_have_their_own_counts() {
+        let planned = counts(&config(
+            "-p 2 -n 100 --stream imu:10 --stream gps:1 --device gps",
+        ))
+        .unwrap();
+        let expected = [("pub-00000".to_owned(), 10), ("pub-00001".to_owned(), 10)];
+        assert_eq!(planned, BTreeMap::from(expected));
+
+        let planned = counts(&config(
+            "-p 2 -n 100 --stream imu:10 --stream gps:0 --device gps",
+        ))
+        .unwrap();
+        assert_eq!(planned["pub-00000"], 100);
+
+        let error = counts(&config("-n 100 --stream imu:10 --stream gps:1")).unwrap_err();
+        assert!(matches!(error, Error::Device(_)));
+    }
+
+    #[test]
+    fn fleets_only_count_devices_of_the_table() {
+        let planned = counts(&config("-p 4 -n 100 --fleet imu=50%,bms=50% --device bms")).unwrap();
+        assert_eq!(planned.len(), 2);
+        assert!(planned.values().all(|v| *v == 100));
+
+        let error = counts(&config("-p 4 --fleet imu=50%,bms=50% --device gps")).unwrap_err();
+        assert!(matches!(error, Error::Device(_)));
+        assert!(counts(&config("-p 4 --fleet imu=50%,bms=50%")).is_err());
+    }
+}